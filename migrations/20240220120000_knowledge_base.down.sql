@@ -0,0 +1,5 @@
+-- Add down migration script here
+drop table if exists chunks;
+drop table if exists documents;
+drop table if exists collections;
+drop extension if exists vector;
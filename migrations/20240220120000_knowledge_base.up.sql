@@ -0,0 +1,52 @@
+-- Add up migration script here
+begin;
+--
+-- vector type (pgvector)
+create extension if not exists vector;
+--
+-- collections table
+create table if not exists collections(
+  id uuid not null default uuid_generate_v4() primary key,
+  name text not null,
+  description text,
+  embedding_model text not null,
+  dimensions integer,
+  chunk_size integer not null,
+  chunk_overlap integer not null,
+  created_at timestamptz not null default current_timestamp,
+  updated_at timestamptz not null default current_timestamp,
+  unique (name)
+);
+create or replace trigger update_collections_timestamp
+  before update on collections for each row
+  execute function update_timestamp();
+--
+-- documents table
+create table if not exists documents(
+  id uuid not null default uuid_generate_v4() primary key,
+  collection_id uuid not null references collections(id) on delete cascade,
+  title text not null,
+  source text,
+  metadata jsonb not null default '{}'::jsonb,
+  created_at timestamptz not null default current_timestamp,
+  updated_at timestamptz not null default current_timestamp
+);
+create index if not exists documents_collection_id_idx on documents(collection_id);
+create or replace trigger update_documents_timestamp
+  before update on documents for each row
+  execute function update_timestamp();
+--
+-- chunks table. embeddings are left without a fixed dimension since each collection may use a
+-- different model, so searches are exact scans over a single collection's chunks
+create table if not exists chunks(
+  id uuid not null default uuid_generate_v4() primary key,
+  collection_id uuid not null references collections(id) on delete cascade,
+  document_id uuid not null references documents(id) on delete cascade,
+  position integer not null,
+  content text not null,
+  embedding vector not null,
+  created_at timestamptz not null default current_timestamp,
+  unique (document_id, position)
+);
+create index if not exists chunks_collection_id_idx on chunks(collection_id);
+commit;
@@ -0,0 +1,3 @@
+-- Add down migration script here
+drop index if exists chunks_search_vector_idx;
+alter table chunks drop column if exists search_vector;
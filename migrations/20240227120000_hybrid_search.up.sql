@@ -0,0 +1,9 @@
+-- Add up migration script here
+begin;
+--
+-- lexical search over chunk content, ranked alongside embedding similarity
+alter table chunks
+  add column if not exists search_vector tsvector
+  generated always as (to_tsvector('english', content)) stored;
+create index if not exists chunks_search_vector_idx on chunks using gin(search_vector);
+commit;
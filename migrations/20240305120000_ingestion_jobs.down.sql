@@ -0,0 +1,4 @@
+-- Add down migration script here
+drop table if exists document_segments;
+drop table if exists ingestion_jobs;
+drop type if exists ingestion_status;
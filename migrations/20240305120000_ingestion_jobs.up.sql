@@ -0,0 +1,49 @@
+-- Add up migration script here
+begin;
+--
+-- ingestion_status type
+create type ingestion_status as enum(
+  'pending',
+  'processing',
+  'completed',
+  'failed'
+);
+--
+-- ingestion_jobs table. uploads are extracted and embedded in the background, the job records
+-- how far that got
+create table if not exists ingestion_jobs(
+  id uuid not null default uuid_generate_v4() primary key,
+  collection_id uuid not null references collections(id) on delete cascade,
+  document_id uuid references documents(id) on delete set null,
+  filename text not null,
+  content_type text,
+  format text not null,
+  size bigint not null,
+  status ingestion_status not null default 'pending',
+  error text,
+  started_at timestamptz,
+  finished_at timestamptz,
+  created_at timestamptz not null default current_timestamp,
+  updated_at timestamptz not null default current_timestamp
+);
+create index if not exists ingestion_jobs_collection_id_idx on ingestion_jobs(collection_id);
+create or replace trigger update_ingestion_jobs_timestamp
+  before update on ingestion_jobs for each row
+  execute function update_timestamp();
+--
+-- document_segments table. the pages of a pdf or the sections of other formats, as extracted
+create table if not exists document_segments(
+  id uuid not null default uuid_generate_v4() primary key,
+  document_id uuid not null references documents(id) on delete cascade,
+  position integer not null,
+  page integer,
+  heading text,
+  content text not null,
+  search_vector tsvector generated always as (
+    to_tsvector('english', coalesce(heading, '') || ' ' || content)
+  ) stored,
+  unique (document_id, position)
+);
+create index if not exists document_segments_search_vector_idx
+  on document_segments using gin(search_vector);
+commit;
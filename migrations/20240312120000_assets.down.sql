@@ -0,0 +1,4 @@
+-- Add down migration script here
+drop table if exists assets;
+drop type if exists asset_visibility;
+drop type if exists asset_backend;
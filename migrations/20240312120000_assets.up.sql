@@ -0,0 +1,38 @@
+-- Add up migration script here
+begin;
+--
+-- asset_backend type
+create type asset_backend as enum(
+  'fs',
+  'aws',
+  'gcp',
+  'azure'
+);
+--
+-- asset_visibility type
+create type asset_visibility as enum(
+  'public',
+  'private'
+);
+--
+-- assets table. owner is the subject of the token that uploaded the asset, and key is where the
+-- backend keeps its contents
+create table if not exists assets(
+  id uuid not null default uuid_generate_v4() primary key,
+  owner text not null,
+  filename text not null,
+  content_type text not null,
+  size bigint not null,
+  checksum text not null,
+  visibility asset_visibility not null default 'private'::asset_visibility,
+  backend asset_backend not null,
+  key text not null,
+  created_at timestamptz not null default current_timestamp,
+  updated_at timestamptz not null default current_timestamp,
+  unique (backend, key)
+);
+create index if not exists assets_owner_idx on assets(owner);
+create or replace trigger update_assets_timestamp
+  before update on assets for each row
+  execute function update_timestamp();
+commit;
@@ -0,0 +1,4 @@
+-- Add down migration script here
+drop table if exists revoked_tokens;
+drop table if exists password_credentials;
+drop index if exists users_lower_email_idx;
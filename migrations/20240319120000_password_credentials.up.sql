@@ -0,0 +1,28 @@
+-- Add up migration script here
+begin;
+--
+-- emails are compared case insensitively, so addresses that only differ by case belong to the
+-- same user
+create unique index if not exists users_lower_email_idx on users(lower(email));
+--
+-- password_credentials table. users registered with the first party (developforgood) provider
+-- sign in with a password, hashed with the recorded algorithm
+create table if not exists password_credentials(
+  user_id uuid not null primary key references users(id) on delete cascade,
+  password_hash text not null,
+  hash_algorithm hash_algorithm not null default 'argon2'::hash_algorithm,
+  created_at timestamptz not null default current_timestamp,
+  updated_at timestamptz not null default current_timestamp
+);
+create or replace trigger update_password_credentials_timestamp
+  before update on password_credentials for each row
+  execute function update_timestamp();
+--
+-- revoked_tokens table. first party tokens that were logged out before they expired, kept until
+-- their expiry
+create table if not exists revoked_tokens(
+  token_id uuid not null primary key,
+  expires_at timestamptz not null
+);
+create index if not exists revoked_tokens_expires_at_idx on revoked_tokens(expires_at);
+commit;
@@ -0,0 +1,6 @@
+-- Add down migration script here
+begin;
+alter table collections drop constraint if exists collections_owner_name_key;
+alter table collections drop column if exists owner;
+alter table collections add constraint collections_name_key unique (name);
+commit;
@@ -0,0 +1,12 @@
+-- Add up migration script here
+begin;
+--
+-- collections belong to the subject of the token that created them, and names only need to be
+-- unique per owner. collections created before owners were recorded get an empty owner, and stay
+-- hidden until they're assigned to someone with
+--   update collections set owner = '<subject>' where owner = '';
+alter table collections add column if not exists owner text not null default '';
+alter table collections alter column owner drop default;
+alter table collections drop constraint if exists collections_name_key;
+alter table collections add constraint collections_owner_name_key unique (owner, name);
+commit;
@@ -0,0 +1,11 @@
+use thiserror::Error;
+
+#[derive(Error, Debug, Eq, PartialEq)]
+pub enum ToolError {
+    #[error("no tool named {0}")]
+    UnknownTool(String),
+    #[error("invalid tool arguments. error: {0}")]
+    InvalidArguments(String),
+    #[error("tool failed. error: {0}")]
+    Failed(String),
+}
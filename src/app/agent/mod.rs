@@ -0,0 +1,4 @@
+pub mod errors;
+pub mod registry;
+pub mod runner;
+pub mod tools;
@@ -0,0 +1,157 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use futures::future::BoxFuture;
+use serde_json::Value;
+
+use crate::app::{
+    auth::principal::Principal,
+    openai::chat::{ChatFunction, ChatTool},
+};
+
+use super::errors::ToolError;
+
+type ToolHandler =
+    Arc<dyn Fn(Principal, Value) -> BoxFuture<'static, Result<Value, ToolError>> + Send + Sync>;
+
+struct RegisteredTool {
+    definition: ChatFunction,
+    handler: ToolHandler,
+}
+
+/// Tools the agent can run on the model's behalf. Each tool is described to the model by a JSON
+/// schema for its arguments and executed by an async handler, which is given the principal the
+/// agent runs for.
+#[derive(Default)]
+pub struct ToolRegistry {
+    tools: HashMap<String, RegisteredTool>,
+}
+
+impl ToolRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a tool, replacing any tool with the same name
+    pub fn register<F, Fut>(&mut self, name: &str, description: &str, parameters: Value, handler: F)
+    where
+        F: Fn(Principal, Value) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Value, ToolError>> + Send + 'static,
+    {
+        let handler: ToolHandler =
+            Arc::new(move |principal, args| Box::pin(handler(principal, args)));
+        self.tools.insert(
+            name.to_owned(),
+            RegisteredTool {
+                definition: ChatFunction {
+                    name: name.to_owned(),
+                    description: Some(description.to_owned()),
+                    parameters,
+                },
+                handler,
+            },
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tools.is_empty()
+    }
+
+    /// Definitions of every registered tool, ordered by name
+    pub fn tools(&self) -> Vec<ChatTool> {
+        let mut tools: Vec<ChatTool> = self
+            .tools
+            .values()
+            .map(|tool| ChatTool::function(tool.definition.clone()))
+            .collect();
+        tools.sort_by(|a, b| a.function.name.cmp(&b.function.name));
+        tools
+    }
+
+    /// Run a tool for `principal` with the JSON encoded arguments supplied by the model. Arguments
+    /// must be an object with every property the schema lists as required.
+    pub async fn call(
+        &self,
+        principal: &Principal,
+        name: &str,
+        arguments: &str,
+    ) -> Result<Value, ToolError> {
+        let tool = self
+            .tools
+            .get(name)
+            .ok_or_else(|| ToolError::UnknownTool(name.to_owned()))?;
+
+        let arguments = match arguments.trim() {
+            "" => Value::Object(Default::default()),
+            raw => {
+                serde_json::from_str(raw).map_err(|e| ToolError::InvalidArguments(e.to_string()))?
+            }
+        };
+        let object = arguments
+            .as_object()
+            .ok_or_else(|| ToolError::InvalidArguments("arguments must be an object".into()))?;
+
+        let required = tool
+            .definition
+            .parameters
+            .get("required")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(Value::as_str);
+        for property in required {
+            if !object.contains_key(property) {
+                return Err(ToolError::InvalidArguments(format!(
+                    "missing required property `{property}`"
+                )));
+            }
+        }
+
+        (tool.handler)(principal.clone(), arguments).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_call_tool() {
+        let mut registry = ToolRegistry::new();
+        registry.register(
+            "add",
+            "add two numbers",
+            json!({
+                "type": "object",
+                "properties": {"a": {"type": "number"}, "b": {"type": "number"}},
+                "required": ["a", "b"]
+            }),
+            |_, args| async move {
+                let operand = |name: &str| args[name].as_f64().unwrap_or_default();
+                Ok(json!(operand("a") + operand("b")))
+            },
+        );
+
+        let principal = Principal::anonymous();
+        assert_eq!(registry.tools()[0].function.name, "add");
+        assert_eq!(
+            registry
+                .call(&principal, "add", r#"{"a": 1, "b": 2}"#)
+                .await,
+            Ok(json!(3.0))
+        );
+        assert!(matches!(
+            registry.call(&principal, "add", r#"{"a": 1}"#).await,
+            Err(ToolError::InvalidArguments(_))
+        ));
+        assert!(matches!(
+            registry.call(&principal, "add", "[1, 2]").await,
+            Err(ToolError::InvalidArguments(_))
+        ));
+        assert_eq!(
+            registry.call(&principal, "subtract", "{}").await,
+            Err(ToolError::UnknownTool("subtract".into()))
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use std::time::Instant;
+
+use futures::future;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::app::{
+    auth::principal::Principal,
+    llm::provider::ChatProvider,
+    openai::{
+        chat::{ChatMessage, ChatOptions, ToolCall},
+        errors::OpenAIError,
+        usage::Usage,
+    },
+};
+
+use super::registry::ToolRegistry;
+
+pub const DEFAULT_MAX_ITERATIONS: usize = 8;
+/// Upper bound on the iterations a caller may ask for
+pub const MAX_ITERATIONS: usize = 32;
+
+/// A single step of an agent run
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AgentStep {
+    Model {
+        iteration: usize,
+        content: Option<String>,
+        tool_calls: Option<Vec<ToolCall>>,
+        finish_reason: String,
+        usage: Usage,
+    },
+    Tool {
+        iteration: usize,
+        id: String,
+        name: String,
+        arguments: String,
+        output: Option<Value>,
+        error: Option<String>,
+        elapsed_ms: u128,
+    },
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AgentFinish {
+    /// The model answered without calling any more tools
+    Answer,
+    /// The iteration cap was reached while the model was still calling tools
+    MaxIterations,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentRun {
+    pub answer: Option<String>,
+    pub finish: AgentFinish,
+    pub iterations: usize,
+    pub usage: Usage,
+    pub trace: Vec<AgentStep>,
+}
+
+/// Runs the tool calling loop: call the model, execute the tools it asks for, append the results
+/// to the conversation and repeat until it answers or `max_iterations` model calls have been made.
+/// Tools run on behalf of `principal`.
+pub struct Agent<'a> {
+    provider: &'a dyn ChatProvider,
+    registry: &'a ToolRegistry,
+    principal: &'a Principal,
+    max_iterations: usize,
+}
+
+impl<'a> Agent<'a> {
+    pub fn new(
+        provider: &'a dyn ChatProvider,
+        registry: &'a ToolRegistry,
+        principal: &'a Principal,
+    ) -> Self {
+        Self {
+            provider,
+            registry,
+            principal,
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+        }
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations.clamp(1, MAX_ITERATIONS);
+        self
+    }
+
+    /// Execute one tool call. Failures are reported to the model as the tool's output so that it
+    /// can correct its arguments or carry on without the tool.
+    async fn call_tool(&self, iteration: usize, call: &ToolCall) -> (ChatMessage, AgentStep) {
+        let started = Instant::now();
+        let result = self
+            .registry
+            .call(
+                self.principal,
+                &call.function.name,
+                &call.function.arguments,
+            )
+            .await;
+        let elapsed_ms = started.elapsed().as_millis();
+
+        let (content, output, error) = match result {
+            Ok(output) => (output.to_string(), Some(output), None),
+            Err(e) => {
+                log::warn!("{}", e.to_string());
+                (
+                    json!({"error": e.to_string()}).to_string(),
+                    None,
+                    Some(e.to_string()),
+                )
+            }
+        };
+
+        let step = AgentStep::Tool {
+            iteration,
+            id: call.id.clone(),
+            name: call.function.name.clone(),
+            arguments: call.function.arguments.clone(),
+            output,
+            error,
+            elapsed_ms,
+        };
+        (ChatMessage::tool_result(&call.id, &content), step)
+    }
+
+    pub async fn run(&self, mut opts: ChatOptions) -> Result<AgentRun, OpenAIError> {
+        opts.stream = None;
+        opts.n = None;
+        if !self.registry.is_empty() {
+            opts.tools = Some(self.registry.tools());
+        }
+
+        let mut usage = Usage::default();
+        let mut trace = vec![];
+
+        for iteration in 1..=self.max_iterations {
+            let completion = self.provider.get_chat_completion(&opts).await?;
+            usage += &completion.usage;
+            let choice = completion.choices.into_iter().next().ok_or_else(|| {
+                OpenAIError::Serialize("chat completion contained no choices".into())
+            })?;
+
+            let tool_calls = choice.message.tool_calls.clone().unwrap_or_default();
+            trace.push(AgentStep::Model {
+                iteration,
+                content: choice.message.content.clone(),
+                tool_calls: choice.message.tool_calls.clone(),
+                finish_reason: choice.finish_reason,
+                usage: completion.usage,
+            });
+
+            if tool_calls.is_empty() {
+                return Ok(AgentRun {
+                    answer: choice.message.content,
+                    finish: AgentFinish::Answer,
+                    iterations: iteration,
+                    usage,
+                    trace,
+                });
+            }
+
+            opts.messages.push(choice.message.into());
+            let results = future::join_all(
+                tool_calls
+                    .iter()
+                    .map(|call| self.call_tool(iteration, call)),
+            )
+            .await;
+            for (message, step) in results {
+                opts.messages.push(message);
+                trace.push(step);
+            }
+        }
+
+        Ok(AgentRun {
+            answer: None,
+            finish: AgentFinish::MaxIterations,
+            iterations: self.max_iterations,
+            usage,
+            trace,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use crate::app::openai::{
+        chat::{ChatCompletion, ChatResponseChoice, ChatResponseMessage, ChatRole},
+        stream::ChatCompletionStream,
+    };
+
+    use super::*;
+
+    /// A provider that replays canned replies and records the requests it receives
+    struct ScriptedProvider {
+        replies: Mutex<Vec<ChatResponseMessage>>,
+        requests: Mutex<Vec<ChatOptions>>,
+    }
+
+    impl ScriptedProvider {
+        fn new(mut replies: Vec<ChatResponseMessage>) -> Self {
+            replies.reverse();
+            Self {
+                replies: Mutex::new(replies),
+                requests: Mutex::new(vec![]),
+            }
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl ChatProvider for ScriptedProvider {
+        async fn get_chat_completion(
+            &self,
+            opts: &ChatOptions,
+        ) -> Result<ChatCompletion, OpenAIError> {
+            self.requests.lock().unwrap().push(opts.clone());
+            let message = self.replies.lock().unwrap().pop().unwrap();
+            let finish_reason = match message.tool_calls {
+                Some(_) => "tool_calls",
+                None => "stop",
+            };
+            Ok(ChatCompletion {
+                id: "chatcmpl-1".into(),
+                object: "chat.completion".into(),
+                created: 0,
+                model: opts.model.clone(),
+                choices: vec![ChatResponseChoice {
+                    index: 0,
+                    message,
+                    finish_reason: finish_reason.into(),
+                    content_filter_results: None,
+                }],
+                usage: Usage {
+                    prompt_tokens: 10,
+                    completion_tokens: Some(5),
+                    total_tokens: 15,
+                },
+                prompt_filter_results: None,
+            })
+        }
+
+        async fn stream_chat_completion(
+            &self,
+            _opts: &ChatOptions,
+        ) -> Result<ChatCompletionStream, OpenAIError> {
+            unimplemented!()
+        }
+    }
+
+    fn reply(content: Option<&str>, tool_calls: Option<Vec<ToolCall>>) -> ChatResponseMessage {
+        ChatResponseMessage {
+            role: "assistant".into(),
+            content: content.map(str::to_owned),
+            tool_calls,
+        }
+    }
+
+    fn registry() -> ToolRegistry {
+        let mut registry = ToolRegistry::new();
+        registry.register(
+            "get_weather",
+            "current weather for a city",
+            json!({"type": "object", "required": ["city"]}),
+            |_, args| async move { Ok(json!({"city": args["city"], "forecast": "sunny"})) },
+        );
+        registry
+    }
+
+    fn options() -> ChatOptions {
+        ChatOptions::default(
+            "gpt-4o",
+            vec![ChatMessage::new(ChatRole::User, "weather in paris?")],
+            64,
+        )
+    }
+
+    #[tokio::test]
+    pub async fn test_agent_loop() {
+        let provider = ScriptedProvider::new(vec![
+            reply(
+                None,
+                Some(vec![
+                    ToolCall::function("call_1", "get_weather", r#"{"city":"Paris"}"#),
+                    ToolCall::function("call_2", "get_weather", "{}"),
+                ]),
+            ),
+            reply(Some("it is sunny in paris"), None),
+        ]);
+        let registry = registry();
+
+        let run = Agent::new(&provider, &registry, &Principal::anonymous())
+            .run(options())
+            .await
+            .unwrap();
+        assert_eq!(run.finish, AgentFinish::Answer);
+        assert_eq!(run.answer.as_deref(), Some("it is sunny in paris"));
+        assert_eq!(run.iterations, 2);
+        assert_eq!(run.usage.total_tokens, 30);
+        assert_eq!(run.trace.len(), 4);
+        assert!(matches!(
+            &run.trace[2],
+            AgentStep::Tool { error: Some(_), .. }
+        ));
+
+        let requests = provider.requests.lock().unwrap();
+        assert_eq!(requests[0].tools.as_ref().map(Vec::len), Some(1));
+        let followup = &requests[1].messages;
+        assert_eq!(followup.len(), 4);
+        assert_eq!(followup[2].tool_call_id.as_deref(), Some("call_1"));
+        assert_eq!(
+            followup[2].content.as_deref(),
+            Some(r#"{"city":"Paris","forecast":"sunny"}"#)
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_agent_iteration_cap() {
+        let call = || {
+            reply(
+                None,
+                Some(vec![ToolCall::function(
+                    "call_1",
+                    "get_weather",
+                    r#"{"city":"Paris"}"#,
+                )]),
+            )
+        };
+        let provider = ScriptedProvider::new(vec![call(), call(), call()]);
+        let registry = registry();
+
+        let run = Agent::new(&provider, &registry, &Principal::anonymous())
+            .with_max_iterations(2)
+            .run(options())
+            .await
+            .unwrap();
+        assert_eq!(run.finish, AgentFinish::MaxIterations);
+        assert!(run.answer.is_none());
+        assert_eq!(provider.requests.lock().unwrap().len(), 2);
+    }
+}
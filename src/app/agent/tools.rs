@@ -0,0 +1,131 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::app::{
+    auth::principal::Principal,
+    openai::OpenAIClient,
+    rag::{
+        errors::RagError,
+        knowledge_base::{KnowledgeBase, DEFAULT_TOP_K},
+        store,
+    },
+};
+
+use super::{errors::ToolError, registry::ToolRegistry};
+
+#[derive(Debug, Serialize, sqlx::FromRow)]
+struct UserSummary {
+    id: Uuid,
+    first_name: String,
+    last_name: String,
+    email: String,
+    username: String,
+}
+
+/// The account of the user the agent runs for, matched by the token's subject or email. Other
+/// users' accounts are never returned.
+async fn lookup_user(sql: PgPool, principal: Principal) -> Result<Value, ToolError> {
+    let user: Option<UserSummary> = sqlx::query_as(
+        "select id, first_name, last_name, email, username from users \
+         where id::text = $1 or lower(email) = lower($2) limit 1",
+    )
+    .bind(&principal.subject)
+    .bind(&principal.email)
+    .fetch_optional(&sql)
+    .await
+    .map_err(|e| {
+        log::error!("{}", e.to_string());
+        ToolError::Failed("unable to query users".into())
+    })?;
+
+    Ok(match user {
+        Some(user) => json!({"found": true, "user": user}),
+        None => json!({"found": false}),
+    })
+}
+
+/// Search one of the caller's knowledge base collections, by name, for passages relevant to a
+/// query
+async fn search_knowledge_base(
+    sql: PgPool,
+    embeddings: OpenAIClient,
+    principal: Principal,
+    args: Value,
+) -> Result<Value, ToolError> {
+    let (Some(name), Some(query)) = (args["collection"].as_str(), args["query"].as_str()) else {
+        return Err(ToolError::InvalidArguments(
+            "`collection` and `query` must be strings".into(),
+        ));
+    };
+    let top_k = args["top_k"]
+        .as_u64()
+        .map(|top_k| top_k as usize)
+        .unwrap_or(DEFAULT_TOP_K);
+
+    let search = async {
+        let collection = store::get_collection_by_name(&sql, name, &principal.subject).await?;
+        KnowledgeBase::new(&sql, &embeddings)
+            .query(&collection, query, top_k)
+            .await
+    };
+    match search.await {
+        Ok(found) => Ok(json!({"results": found.results})),
+        Err(RagError::NotFound(e) | RagError::InvalidInput(e)) => {
+            Err(ToolError::InvalidArguments(e))
+        }
+        Err(e) => {
+            log::error!("{}", e.to_string());
+            Err(ToolError::Failed(
+                "unable to search the knowledge base".into(),
+            ))
+        }
+    }
+}
+
+/// The tools available to the agent endpoint. Knowledge base search needs an embeddings provider
+/// and is left out without one.
+pub fn registry(sql: PgPool, embeddings: Option<OpenAIClient>) -> ToolRegistry {
+    let mut registry = ToolRegistry::new();
+
+    registry.register(
+        "lookup_user",
+        "Look up the melody account of the user you are talking to",
+        json!({"type": "object", "properties": {}}),
+        {
+            let sql = sql.clone();
+            move |principal, _| lookup_user(sql.clone(), principal)
+        },
+    );
+
+    if let Some(embeddings) = embeddings {
+        registry.register(
+            "search_knowledge_base",
+            "Search one of the user's knowledge base collections for passages relevant to a query",
+            json!({
+                "type": "object",
+                "properties": {
+                    "collection": {
+                        "type": "string",
+                        "description": "The name of the collection to search"
+                    },
+                    "query": {
+                        "type": "string",
+                        "description": "What to search for"
+                    },
+                    "top_k": {
+                        "type": "integer",
+                        "description": "How many passages to return"
+                    }
+                },
+                "required": ["collection", "query"]
+            }),
+            move |principal, args| {
+                search_knowledge_base(sql.clone(), embeddings.clone(), principal, args)
+            },
+        );
+    }
+
+    registry
+}
@@ -1 +1,312 @@
-pub enum ApiError {}
+use axum::{
+    extract::{
+        multipart::{MultipartError, MultipartRejection},
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Request,
+    },
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::app::{
+    assets::errors::AssetError,
+    auth::errors::{AccountError, AuthError},
+    llm::semantic_cache::SemanticCacheError,
+    openai::errors::OpenAIError,
+    rag::errors::{ExtractError, RagError},
+    storage::{cache::errors::CacheError, errors::DbError},
+};
+
+const PROBLEM_JSON: &str = "application/problem+json";
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Errors returned by api handlers. Every variant renders as an RFC 7807 problem document.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error(transparent)]
+    Auth(#[from] AuthError),
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+    #[error(transparent)]
+    Ai(#[from] OpenAIError),
+    #[error(transparent)]
+    Json(#[from] JsonRejection),
+    #[error(transparent)]
+    Path(#[from] PathRejection),
+    #[error(transparent)]
+    Query(#[from] QueryRejection),
+    #[error(transparent)]
+    Multipart(#[from] MultipartRejection),
+    #[error(transparent)]
+    MultipartField(#[from] MultipartError),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    #[error("{0}")]
+    TooManyRequests(String),
+    /// A storage backend other than the database failed
+    #[error("{0}")]
+    Storage(String),
+    /// A failure that clients can do nothing about
+    #[error("{0}")]
+    Internal(String),
+}
+
+/// An RFC 7807 problem document. `code` and `request_id` are extension members.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Serialize)]
+pub struct ProblemDetails {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: Option<String>,
+    pub code: String,
+    pub request_id: Option<String>,
+}
+
+impl ApiError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Auth(
+                AuthError::Init(..) | AuthError::FetchJwks(..) | AuthError::RevocationCheck(..),
+            ) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Auth(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Db(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            ApiError::Cache(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Ai(e) => e.status_code(),
+            ApiError::Json(rejection) => rejection.status(),
+            ApiError::Path(rejection) => rejection.status(),
+            ApiError::Query(rejection) => rejection.status(),
+            ApiError::Multipart(rejection) => rejection.status(),
+            ApiError::MultipartField(e) => e.status(),
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Storage(_) => StatusCode::BAD_GATEWAY,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A stable, machine readable code for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Auth(
+                AuthError::Init(..) | AuthError::FetchJwks(..) | AuthError::RevocationCheck(..),
+            ) => "auth_provider_unavailable",
+            ApiError::Auth(_) => "unauthorized",
+            ApiError::Db(_) => "database_error",
+            ApiError::Cache(_) => "cache_unavailable",
+            ApiError::Ai(e) => e.code(),
+            ApiError::Json(_) => "invalid_body",
+            ApiError::Path(_) => "invalid_path",
+            ApiError::Query(_) => "invalid_query",
+            ApiError::Multipart(_) | ApiError::MultipartField(_) => "invalid_upload",
+            ApiError::BadRequest(_) => "bad_request",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::TooManyRequests(_) => "too_many_requests",
+            ApiError::Storage(_) => "storage_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// A description that is safe to show to clients. Internal errors are not described.
+    fn detail(&self) -> Option<String> {
+        match self {
+            ApiError::Auth(
+                AuthError::Init(..) | AuthError::FetchJwks(..) | AuthError::RevocationCheck(..),
+            ) => Some("unable to reach the authentication provider".into()),
+            ApiError::Auth(_) => Some("missing or invalid credentials".into()),
+            ApiError::Db(_) | ApiError::Internal(_) => None,
+            ApiError::Cache(_) => Some("unable to reach the cache".into()),
+            ApiError::Storage(_) => Some("unable to reach asset storage".into()),
+            ApiError::Ai(e) => Some(e.public_message()),
+            ApiError::Json(rejection) => Some(rejection.body_text()),
+            ApiError::Path(rejection) => Some(rejection.body_text()),
+            ApiError::Query(rejection) => Some(rejection.body_text()),
+            ApiError::Multipart(rejection) => Some(rejection.body_text()),
+            ApiError::MultipartField(e) => Some(e.body_text()),
+            ApiError::BadRequest(detail)
+            | ApiError::Forbidden(detail)
+            | ApiError::NotFound(detail)
+            | ApiError::Conflict(detail)
+            | ApiError::UnsupportedMediaType(detail)
+            | ApiError::TooManyRequests(detail) => Some(detail.to_owned()),
+        }
+    }
+
+    pub fn problem(&self) -> ProblemDetails {
+        let status = self.status_code();
+        let code = self.code();
+        ProblemDetails {
+            kind: format!("urn:melody:error:{code}"),
+            title: status.canonical_reason().unwrap_or("Error").to_owned(),
+            status: status.as_u16(),
+            detail: self.detail(),
+            code: code.to_owned(),
+            request_id: None,
+        }
+    }
+}
+
+impl From<AccountError> for ApiError {
+    fn from(e: AccountError) -> Self {
+        match e {
+            AccountError::Db(e) => ApiError::Db(e),
+            AccountError::InvalidInput(detail) => ApiError::BadRequest(detail),
+            AccountError::Conflict(detail) => ApiError::Conflict(detail),
+            AccountError::InvalidCredentials => ApiError::Auth(AuthError::InvalidCredentials(
+                "DevelopForGood".into(),
+                e.to_string(),
+            )),
+            AccountError::Hash(_) | AccountError::Token(_) => ApiError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<RagError> for ApiError {
+    fn from(e: RagError) -> Self {
+        match e {
+            RagError::Db(e) => ApiError::Db(e),
+            RagError::Ai(e) => ApiError::Ai(e),
+            RagError::NotFound(detail) => ApiError::NotFound(detail),
+            RagError::Conflict(detail) => ApiError::Conflict(detail),
+            RagError::InvalidInput(detail) => ApiError::BadRequest(detail),
+            RagError::Extract(ExtractError::Unsupported(detail)) => {
+                ApiError::UnsupportedMediaType(detail)
+            }
+            RagError::Extract(ExtractError::Malformed(detail)) => ApiError::BadRequest(detail),
+        }
+    }
+}
+
+impl From<AssetError> for ApiError {
+    fn from(e: AssetError) -> Self {
+        match e {
+            AssetError::Db(e) => ApiError::Db(e),
+            AssetError::NotFound(detail) => ApiError::NotFound(detail),
+            AssetError::Forbidden(detail) => ApiError::Forbidden(detail),
+            AssetError::InvalidInput(detail) => ApiError::BadRequest(detail),
+            AssetError::Backend(detail) => ApiError::Storage(detail),
+        }
+    }
+}
+
+impl From<SemanticCacheError> for ApiError {
+    fn from(e: SemanticCacheError) -> Self {
+        match e {
+            SemanticCacheError::Cache(e) => ApiError::Cache(e),
+            SemanticCacheError::Embeddings(e) => ApiError::Ai(e),
+        }
+    }
+}
+
+impl ProblemDetails {
+    fn into_response_with_status(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut res = (
+            status,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON))],
+            Json(&self),
+        )
+            .into_response();
+        res.extensions_mut().insert(self);
+        res
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        match self.status_code() {
+            status if status.is_server_error() => log::error!("{}", self.to_string()),
+            _ => log::debug!("{}", self.to_string()),
+        }
+        self.problem().into_response_with_status()
+    }
+}
+
+/// Stamp problem responses with the id of the request that produced them. Must run inside the
+/// layer that assigns request ids.
+pub async fn attach_request_id(req: Request, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(|id| id.to_owned());
+
+    let mut res = next.run(req).await;
+    match (res.extensions_mut().remove::<ProblemDetails>(), request_id) {
+        (Some(mut problem), Some(request_id)) => {
+            problem.request_id = Some(request_id);
+            let headers = res.headers().clone();
+            let mut res = problem.into_response_with_status();
+            res.headers_mut().extend(headers);
+            res
+        }
+        _ => res,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing, Router};
+    use tower::ServiceExt;
+    use tower_http::request_id::{MakeRequestUuid, SetRequestIdLayer};
+
+    use super::*;
+
+    #[tokio::test]
+    pub async fn test_problem_response() {
+        let app = Router::new()
+            .route(
+                "/",
+                routing::get(|| async { ApiError::Ai(OpenAIError::RateLimit("slow down".into())) }),
+            )
+            .layer(middleware::from_fn(attach_request_id))
+            .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+
+        let res = app
+            .oneshot(Request::builder().uri("/").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], PROBLEM_JSON);
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["type"], "urn:melody:error:rate_limited");
+        assert_eq!(problem["title"], "Too Many Requests");
+        assert_eq!(problem["status"], 429);
+        assert_eq!(problem["detail"], "rate limit exceeded, try again later");
+        assert!(problem["request_id"].is_string());
+    }
+
+    #[test]
+    pub fn test_too_many_requests_problem() {
+        let problem = ApiError::TooManyRequests("busy".into()).problem();
+        assert_eq!(problem.status, 429);
+        assert_eq!(problem.kind, "urn:melody:error:too_many_requests");
+        assert_eq!(problem.detail.as_deref(), Some("busy"));
+    }
+}
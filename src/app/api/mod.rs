@@ -1,3 +1,4 @@
+pub mod errors;
 pub mod v1;
 
 use std::sync::Arc;
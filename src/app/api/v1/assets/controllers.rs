@@ -0,0 +1,393 @@
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{
+        multipart::MultipartRejection,
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Multipart, Path, Query, State,
+    },
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::{Duration, Utc};
+use uuid::Uuid;
+
+use crate::{
+    app::{
+        api::errors::ApiError,
+        assets::{
+            self,
+            db::Asset,
+            range::{self, RangeRequest},
+            MAX_LINK_TTL_SECS,
+        },
+        auth::principal::AuthUser,
+        types::AssetVisibility,
+    },
+    state::AppState,
+};
+
+use super::requests::{AssetLink, CreateLink, SignedLink};
+
+/// Largest file accepted by the upload endpoint
+pub const MAX_ASSET_BYTES: usize = 25 * 1024 * 1024;
+
+/// Where these routes are mounted, used to build download links
+const ASSETS_PATH: &str = "/api/v1/assets";
+
+/// Lifetime of a signed download link when the request doesn't ask for one
+const DEFAULT_LINK_TTL_SECS: i64 = 60 * 60;
+
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Types a browser can display without running anything the uploader wrote. Every other type,
+/// notably html and svg, is served as an attachment.
+const INLINE_CONTENT_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "image/avif",
+    "application/pdf",
+];
+
+/// Public assets never change under the same id, so their stable urls can be cached forever
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// Upload an asset from the `file` field of a multipart form. The optional `visibility` field is
+/// `public` or `private`, and defaults to the configured asset visibility.
+pub async fn upload_asset(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<(StatusCode, Json<Asset>), ApiError> {
+    let mut multipart = multipart?;
+
+    let (mut file, mut visibility) = (None, state.config.asset_visibility);
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("file") => {
+                let filename = field.file_name().unwrap_or("upload").to_owned();
+                let content_type = field
+                    .content_type()
+                    .unwrap_or(DEFAULT_CONTENT_TYPE)
+                    .to_owned();
+                file = Some((filename, content_type, field.bytes().await?));
+            }
+            Some("visibility") => {
+                visibility = match field.text().await?.to_lowercase().as_str() {
+                    "public" => AssetVisibility::Public,
+                    "private" => AssetVisibility::Private,
+                    _ => {
+                        return Err(ApiError::BadRequest(
+                            "visibility must be `public` or `private`".into(),
+                        ))
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let (filename, content_type, bytes) =
+        file.ok_or_else(|| ApiError::BadRequest("missing `file` field".into()))?;
+    let asset = assets::upload(
+        &state.storage_layer.sql,
+        state.services.assets.as_ref(),
+        &user.subject,
+        &filename,
+        &content_type,
+        visibility,
+        bytes.to_vec(),
+    )
+    .await?;
+    Ok((StatusCode::CREATED, Json(asset)))
+}
+
+/// The assets uploaded by the caller
+pub async fn list_assets(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<Asset>>, ApiError> {
+    Ok(Json(
+        assets::db::list_assets(&state.storage_layer.sql, &user.subject).await?,
+    ))
+}
+
+pub async fn get_asset(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<Asset>, ApiError> {
+    let Path(id) = id?;
+    Ok(Json(
+        assets::find_visible(&state.storage_layer.sql, id, &user.subject).await?,
+    ))
+}
+
+/// Whether an asset of a content type, which the uploader chose, may be displayed by the browser
+fn is_inline(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    INLINE_CONTENT_TYPES
+        .iter()
+        .any(|inline| essence.eq_ignore_ascii_case(inline))
+}
+
+/// `Content-Disposition` for an asset, with its filename quoted and stripped of characters that
+/// can't appear in a header
+fn disposition(filename: &str, content_type: &str) -> HeaderValue {
+    let kind = match is_inline(content_type) {
+        true => "inline",
+        false => "attachment",
+    };
+    let filename: String = filename
+        .chars()
+        .filter(|c| c.is_ascii() && !c.is_ascii_control() && !matches!(c, '"' | '\\'))
+        .collect();
+    HeaderValue::from_str(&format!("{kind}; filename=\"{filename}\""))
+        .unwrap_or(HeaderValue::from_static(kind))
+}
+
+/// A header value built from text the server formats itself
+fn ascii(value: String) -> HeaderValue {
+    HeaderValue::from_str(&value).expect("formatted header values are ascii")
+}
+
+/// Whether an `If-None-Match` header matches an entity tag
+fn matches_etag(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .any(|tag| tag == "*" || tag == etag)
+}
+
+/// Respond with the contents of an asset. The checksum is the entity tag, so conditional requests
+/// are answered with `304 Not Modified` without touching the store, and a single byte range is
+/// served with `206 Partial Content`.
+async fn serve(
+    state: &AppState,
+    asset: &Asset,
+    headers: &HeaderMap,
+    cache_control: HeaderValue,
+) -> Result<Response, ApiError> {
+    let header = |name| {
+        headers
+            .get(name)
+            .and_then(|v: &HeaderValue| v.to_str().ok())
+    };
+    let etag = format!("\"{}\"", asset.checksum);
+    let size = asset.size as u64;
+
+    let mut res = HeaderMap::new();
+    res.insert(header::CACHE_CONTROL, cache_control);
+    res.insert(header::ETAG, ascii(etag.clone()));
+    if header(header::IF_NONE_MATCH).is_some_and(|tags| matches_etag(tags, &etag)) {
+        return Ok((StatusCode::NOT_MODIFIED, res).into_response());
+    }
+
+    res.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    res.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    // a range is only honoured if the client's copy is still current
+    let range = match header(header::IF_RANGE) {
+        Some(if_range) if if_range != etag => RangeRequest::Full,
+        _ => range::parse_range(header(header::RANGE), size),
+    };
+    let (status, range) = match range {
+        RangeRequest::Full => (StatusCode::OK, None),
+        RangeRequest::Partial(range) => {
+            res.insert(header::CONTENT_RANGE, ascii(range.content_range(size)));
+            (StatusCode::PARTIAL_CONTENT, Some(range))
+        }
+        RangeRequest::Unsatisfiable => {
+            res.insert(header::CONTENT_RANGE, ascii(format!("bytes */{size}")));
+            return Ok((StatusCode::RANGE_NOT_SATISFIABLE, res).into_response());
+        }
+    };
+
+    let stream = assets::open(state.services.assets.as_ref(), asset, range).await?;
+    res.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&asset.content_type)
+            .unwrap_or(HeaderValue::from_static(DEFAULT_CONTENT_TYPE)),
+    );
+    res.insert(
+        header::CONTENT_DISPOSITION,
+        disposition(&asset.filename, &asset.content_type),
+    );
+    // uploads are untrusted, so nothing served inline may run script on this origin
+    res.insert(
+        header::CONTENT_SECURITY_POLICY,
+        HeaderValue::from_static("sandbox"),
+    );
+    res.insert(
+        header::CONTENT_LENGTH,
+        HeaderValue::from(range.map_or(size, |range| range.length())),
+    );
+    Ok((status, res, Body::from_stream(stream)).into_response())
+}
+
+pub async fn download_asset(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    headers: HeaderMap,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path(id) = id?;
+    let asset = assets::find_visible(&state.storage_layer.sql, id, &user.subject).await?;
+    let cache_control = match asset.visibility {
+        AssetVisibility::Public => "public, max-age=3600",
+        AssetVisibility::Private => "private, no-store",
+    };
+    serve(
+        &state,
+        &asset,
+        &headers,
+        HeaderValue::from_static(cache_control),
+    )
+    .await
+}
+
+/// A link that downloads an asset without credentials. Public assets get their stable url, and
+/// private assets a signed url that expires after `expires_in` seconds.
+pub async fn create_link(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+    data: Result<Json<CreateLink>, JsonRejection>,
+) -> Result<(StatusCode, Json<AssetLink>), ApiError> {
+    let Path(id) = id?;
+    let Json(data) = data?;
+    let asset = assets::find_visible(&state.storage_layer.sql, id, &user.subject).await?;
+
+    let link = match asset.visibility {
+        AssetVisibility::Public => AssetLink {
+            url: format!("{ASSETS_PATH}/public/{id}"),
+            expires_at: None,
+        },
+        AssetVisibility::Private => {
+            let ttl = link_ttl(data.expires_in)?;
+            let (expires_at, signature) = assets::sign_link(&state.config.secret, &asset, ttl)?;
+            AssetLink {
+                url: format!(
+                    "{ASSETS_PATH}/shared/{id}?expires={}&signature={signature}",
+                    expires_at.timestamp()
+                ),
+                expires_at: Some(expires_at),
+            }
+        }
+    };
+    Ok((StatusCode::CREATED, Json(link)))
+}
+
+/// The lifetime a link was asked for, checked before it is made a `Duration`, which can't hold
+/// every number of seconds
+fn link_ttl(expires_in: Option<i64>) -> Result<Duration, ApiError> {
+    match expires_in.unwrap_or(DEFAULT_LINK_TTL_SECS) {
+        secs @ 1..=MAX_LINK_TTL_SECS => Ok(Duration::seconds(secs)),
+        _ => Err(ApiError::BadRequest(format!(
+            "expires_in must be between 1 and {MAX_LINK_TTL_SECS} seconds"
+        ))),
+    }
+}
+
+/// Download through a signed link. Browsers may keep the contents until the link expires.
+pub async fn download_signed(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    id: Result<Path<Uuid>, PathRejection>,
+    link: Result<Query<SignedLink>, QueryRejection>,
+) -> Result<Response, ApiError> {
+    let Path(id) = id?;
+    let Query(link) = link?;
+    let asset = assets::find_signed(
+        &state.storage_layer.sql,
+        &state.config.secret,
+        id,
+        link.expires,
+        &link.signature,
+    )
+    .await?;
+
+    let remaining = (link.expires - Utc::now().timestamp()).max(0);
+    let cache_control = ascii(format!("private, max-age={remaining}"));
+    serve(&state, &asset, &headers, cache_control).await
+}
+
+/// Download a public asset from its stable url
+pub async fn download_public(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> Result<Response, ApiError> {
+    let Path(id) = id?;
+    let asset = assets::find_public(&state.storage_layer.sql, id).await?;
+    serve(
+        &state,
+        &asset,
+        &headers,
+        HeaderValue::from_static(IMMUTABLE),
+    )
+    .await
+}
+
+pub async fn delete_asset(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = id?;
+    assets::delete(
+        &state.storage_layer.sql,
+        state.services.assets.as_ref(),
+        id,
+        &user.subject,
+    )
+    .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_link_ttl() {
+        assert_eq!(
+            link_ttl(None).unwrap(),
+            Duration::seconds(DEFAULT_LINK_TTL_SECS)
+        );
+        assert_eq!(
+            link_ttl(Some(MAX_LINK_TTL_SECS)).unwrap(),
+            Duration::seconds(MAX_LINK_TTL_SECS)
+        );
+        for expires_in in [0, -1, MAX_LINK_TTL_SECS + 1, i64::MAX, i64::MIN] {
+            assert!(matches!(
+                link_ttl(Some(expires_in)),
+                Err(ApiError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    pub fn test_disposition() {
+        assert_eq!(
+            disposition("cat.png", "image/png"),
+            "inline; filename=\"cat.png\""
+        );
+        assert_eq!(
+            disposition("report.pdf", "Application/PDF; charset=binary"),
+            "inline; filename=\"report.pdf\""
+        );
+        for content_type in ["text/html", "image/svg+xml", "application/xhtml+xml", ""] {
+            assert_eq!(
+                disposition("page \"1\".html", content_type),
+                "attachment; filename=\"page 1.html\""
+            );
+        }
+    }
+}
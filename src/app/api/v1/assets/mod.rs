@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use axum::{extract::DefaultBodyLimit, middleware, routing, Router};
+
+use crate::{app::auth, state::AppState};
+
+use self::controllers::{
+    create_link, delete_asset, download_asset, download_public, download_signed, get_asset,
+    list_assets, upload_asset, MAX_ASSET_BYTES,
+};
+
+mod controllers;
+mod requests;
+
+pub fn routes(state: Arc<AppState>) -> Router<()> {
+    // these carry their own authorization, a signature or the asset being public
+    let links = Router::new()
+        .route("/shared/:id", routing::get(download_signed))
+        .route("/public/:id", routing::get(download_public))
+        .with_state(state.clone());
+
+    Router::new()
+        .route(
+            "/",
+            routing::get(list_assets)
+                .post(upload_asset)
+                .layer(DefaultBodyLimit::max(MAX_ASSET_BYTES)),
+        )
+        .route("/:id", routing::get(get_asset).delete(delete_asset))
+        .route("/:id/content", routing::get(download_asset))
+        .route("/:id/links", routing::post(create_link))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::simple_route_guard,
+        ))
+        .with_state(state)
+        .merge(links)
+}
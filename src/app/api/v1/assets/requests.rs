@@ -0,0 +1,23 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A request for a download link. `expires_in` is in seconds and only applies to private assets.
+#[derive(Debug, Deserialize)]
+pub struct CreateLink {
+    pub expires_in: Option<i64>,
+}
+
+/// A link that downloads an asset without credentials. Links to public assets don't expire.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+pub struct AssetLink {
+    pub url: String,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// The query string of a signed download link
+#[derive(Debug, Deserialize)]
+pub struct SignedLink {
+    pub expires: i64,
+    pub signature: String,
+}
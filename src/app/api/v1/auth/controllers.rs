@@ -1,3 +1,68 @@
-pub async fn register() {}
-pub async fn login() {}
-pub async fn logout() {}
+use std::sync::Arc;
+
+use axum::{
+    extract::{rejection::JsonRejection, State},
+    http::StatusCode,
+    Json,
+};
+
+use crate::{
+    app::{
+        api::errors::ApiError,
+        auth::{
+            db::{NewUser, User},
+            first_party::{self, AccessToken, FirstPartyAuth},
+            principal::AuthUser,
+        },
+    },
+    state::AppState,
+};
+
+use super::requests::{LoginUser, RegisterUser};
+
+fn first_party(state: &AppState) -> Result<&FirstPartyAuth, ApiError> {
+    state
+        .services
+        .first_party
+        .as_ref()
+        .ok_or_else(|| ApiError::NotFound("first party authentication is not enabled".into()))
+}
+
+pub async fn register(
+    State(state): State<Arc<AppState>>,
+    data: Result<Json<RegisterUser>, JsonRejection>,
+) -> Result<(StatusCode, Json<User>), ApiError> {
+    let first_party = first_party(&state)?;
+    let Json(data) = data?;
+    if data.username.trim().is_empty() {
+        return Err(ApiError::BadRequest("username must not be empty".into()));
+    }
+
+    let new = NewUser {
+        first_name: data.first_name.trim().to_owned(),
+        last_name: data.last_name.trim().to_owned(),
+        email: first_party::normalize_email(&data.email)?,
+        username: data.username.trim().to_owned(),
+        image_uri: data.image_uri.unwrap_or_default(),
+    };
+    let user = first_party.register(new, data.password).await?;
+    Ok((StatusCode::CREATED, Json(user)))
+}
+
+pub async fn login(
+    State(state): State<Arc<AppState>>,
+    data: Result<Json<LoginUser>, JsonRejection>,
+) -> Result<Json<AccessToken>, ApiError> {
+    let first_party = first_party(&state)?;
+    let Json(data) = data?;
+    Ok(Json(first_party.login(&data.email, data.password).await?))
+}
+
+/// Revoke the token the request was made with
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+) -> Result<StatusCode, ApiError> {
+    first_party(&state)?.logout(&user).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
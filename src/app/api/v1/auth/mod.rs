@@ -1,2 +1,26 @@
+use std::sync::Arc;
+
+use axum::{middleware, routing, Router};
+
+use crate::{app::auth, state::AppState};
+
+use self::controllers::{login, logout, register};
+
 mod controllers;
 mod requests;
+
+pub fn routes(state: Arc<AppState>) -> Router<()> {
+    let public = Router::new()
+        .route("/register", routing::post(register))
+        .route("/login", routing::post(login))
+        .with_state(state.clone());
+
+    Router::new()
+        .route("/logout", routing::post(logout))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::simple_route_guard,
+        ))
+        .with_state(state)
+        .merge(public)
+}
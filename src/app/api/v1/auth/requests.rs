@@ -1,4 +1,17 @@
 use serde::Deserialize;
 
 #[derive(Debug, Deserialize)]
-pub struct RegisterUser {}
+pub struct RegisterUser {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub username: String,
+    pub password: String,
+    pub image_uri: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginUser {
+    pub email: String,
+    pub password: String,
+}
@@ -0,0 +1,347 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        multipart::MultipartRejection,
+        rejection::{JsonRejection, PathRejection},
+        Multipart, Path, State,
+    },
+    http::StatusCode,
+    Json,
+};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    app::{
+        api::errors::ApiError,
+        auth::principal::AuthUser,
+        openai::{chat::ChatCompletion, errors::OpenAIError, usage::Usage},
+        rag::{
+            chunker::ChunkOptions,
+            extract::DocumentFormat,
+            jobs::{self, IngestionJob, Upload},
+            knowledge_base::{
+                self, Citation, IngestedDocument, KnowledgeBase, QueryResult, SearchOptions,
+                SearchResult, DEFAULT_TOP_K,
+            },
+            search::SearchMode,
+            store::{self, Collection, Document, DocumentSegment, NewCollection, NewDocument},
+        },
+    },
+    state::AppState,
+};
+
+use super::requests::{
+    CollectionChat, CreateCollection, CreateDocument, QueryCollection, SearchCollection,
+};
+
+pub const DEFAULT_EMBEDDING_MODEL: &str = "text-embedding-3-small";
+/// Largest file accepted by the upload endpoint
+pub const MAX_UPLOAD_BYTES: usize = 25 * 1024 * 1024;
+
+fn knowledge_base(state: &AppState) -> Result<KnowledgeBase<'_>, ApiError> {
+    let embeddings =
+        state.services.embeddings.as_ref().ok_or_else(|| {
+            OpenAIError::Unavailable("no embeddings provider is configured".into())
+        })?;
+    Ok(KnowledgeBase::new(&state.storage_layer.sql, embeddings))
+}
+
+pub async fn create_collection(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    data: Result<Json<CreateCollection>, JsonRejection>,
+) -> Result<(StatusCode, Json<Collection>), ApiError> {
+    let Json(data) = data?;
+    if data.name.trim().is_empty() {
+        return Err(ApiError::BadRequest("name must not be empty".into()));
+    }
+
+    let defaults = ChunkOptions::default();
+    let chunking = ChunkOptions::new(
+        data.chunk_size.unwrap_or(defaults.size),
+        data.chunk_overlap.unwrap_or(defaults.overlap),
+    )
+    .map_err(ApiError::BadRequest)?;
+
+    let new = NewCollection {
+        owner: user.subject,
+        name: data.name.trim().to_owned(),
+        description: data.description,
+        embedding_model: data
+            .embedding_model
+            .unwrap_or(DEFAULT_EMBEDDING_MODEL.into()),
+        dimensions: data.dimensions,
+        chunking,
+    };
+    let collection = store::create_collection(&state.storage_layer.sql, &new).await?;
+    Ok((StatusCode::CREATED, Json(collection)))
+}
+
+pub async fn list_collections(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+) -> Result<Json<Vec<Collection>>, ApiError> {
+    Ok(Json(
+        store::list_collections(&state.storage_layer.sql, &user.subject).await?,
+    ))
+}
+
+pub async fn get_collection(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<Collection>, ApiError> {
+    let Path(id) = id?;
+    Ok(Json(
+        store::get_collection(&state.storage_layer.sql, id, &user.subject).await?,
+    ))
+}
+
+pub async fn delete_collection(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path(id) = id?;
+    store::delete_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn create_document(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+    data: Result<Json<CreateDocument>, JsonRejection>,
+) -> Result<(StatusCode, Json<IngestedDocument>), ApiError> {
+    let Path(id) = id?;
+    let Json(data) = data?;
+
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    let document = NewDocument {
+        title: data.title,
+        source: data.source,
+        metadata: data.metadata.unwrap_or(serde_json::json!({})),
+        content: data.content,
+        segments: vec![],
+    };
+    let ingested = knowledge_base(&state)?
+        .add_document(&collection, document)
+        .await?;
+    Ok((StatusCode::CREATED, Json(ingested)))
+}
+
+pub async fn list_documents(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<Vec<Document>>, ApiError> {
+    let Path(id) = id?;
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    Ok(Json(
+        store::list_documents(&state.storage_layer.sql, collection.id).await?,
+    ))
+}
+
+pub async fn delete_document(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    ids: Result<Path<(Uuid, Uuid)>, PathRejection>,
+) -> Result<StatusCode, ApiError> {
+    let Path((id, document_id)) = ids?;
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    store::delete_document(&state.storage_layer.sql, collection.id, document_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn list_segments(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    ids: Result<Path<(Uuid, Uuid)>, PathRejection>,
+) -> Result<Json<Vec<DocumentSegment>>, ApiError> {
+    let Path((id, document_id)) = ids?;
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    Ok(Json(
+        store::list_segments(&state.storage_layer.sql, collection.id, document_id).await?,
+    ))
+}
+
+/// Accept a file as a multipart upload and ingest it in the background. The `file` field holds
+/// the document, and the optional `title` and `source` fields describe it.
+pub async fn upload_document(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+    multipart: Result<Multipart, MultipartRejection>,
+) -> Result<(StatusCode, Json<IngestionJob>), ApiError> {
+    let Path(id) = id?;
+    let mut multipart = multipart?;
+
+    let embeddings =
+        state.services.embeddings.clone().ok_or_else(|| {
+            OpenAIError::Unavailable("no embeddings provider is configured".into())
+        })?;
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    // taken before the body is read, so a busy server doesn't buffer uploads it can't ingest yet
+    let permit = state
+        .services
+        .ingestion
+        .clone()
+        .try_acquire_owned()
+        .map_err(|_| {
+            ApiError::TooManyRequests("too many uploads are being ingested, try again later".into())
+        })?;
+
+    let (mut file, mut title, mut source) = (None, None, None);
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("file") => {
+                let filename = field.file_name().unwrap_or("upload").to_owned();
+                let content_type = field.content_type().map(str::to_owned);
+                file = Some((filename, content_type, field.bytes().await?));
+            }
+            Some("title") => title = Some(field.text().await?),
+            Some("source") => source = Some(field.text().await?),
+            _ => {}
+        }
+    }
+
+    let (filename, content_type, bytes) =
+        file.ok_or_else(|| ApiError::BadRequest("missing `file` field".into()))?;
+    let format = DocumentFormat::detect(&filename, content_type.as_deref()).ok_or_else(|| {
+        ApiError::UnsupportedMediaType(
+            "files must be pdf, docx, html, markdown or plain text".into(),
+        )
+    })?;
+    if bytes.is_empty() {
+        return Err(ApiError::BadRequest("file is empty".into()));
+    }
+
+    let upload = Upload {
+        filename,
+        content_type,
+        format,
+        title: title.filter(|title| !title.trim().is_empty()),
+        source: source.filter(|source| !source.trim().is_empty()),
+        bytes: bytes.to_vec(),
+    };
+    let job = jobs::create_job(&state.storage_layer.sql, collection.id, &upload).await?;
+    jobs::spawn(
+        state.storage_layer.sql.clone(),
+        embeddings,
+        collection,
+        &job,
+        upload,
+        permit,
+    );
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+pub async fn list_uploads(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+) -> Result<Json<Vec<IngestionJob>>, ApiError> {
+    let Path(id) = id?;
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    Ok(Json(
+        jobs::list_jobs(&state.storage_layer.sql, collection.id).await?,
+    ))
+}
+
+pub async fn get_upload(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    ids: Result<Path<(Uuid, Uuid)>, PathRejection>,
+) -> Result<Json<IngestionJob>, ApiError> {
+    let Path((id, job_id)) = ids?;
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    Ok(Json(
+        jobs::get_job(&state.storage_layer.sql, collection.id, job_id).await?,
+    ))
+}
+
+pub async fn query_collection(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+    data: Result<Json<QueryCollection>, JsonRejection>,
+) -> Result<Json<QueryResult>, ApiError> {
+    let Path(id) = id?;
+    let Json(data) = data?;
+
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    let result = knowledge_base(&state)?
+        .query(
+            &collection,
+            &data.query,
+            data.top_k.unwrap_or(DEFAULT_TOP_K),
+        )
+        .await?;
+    Ok(Json(result))
+}
+
+pub async fn search_collection(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+    data: Result<Json<SearchCollection>, JsonRejection>,
+) -> Result<Json<SearchResult>, ApiError> {
+    let Path(id) = id?;
+    let Json(data) = data?;
+
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    let opts = SearchOptions {
+        mode: data.mode,
+        top_k: data.top_k.unwrap_or(DEFAULT_TOP_K),
+        rerank_model: data.rerank,
+    };
+    // searching by words needs no embeddings
+    let knowledge_base = match opts.mode {
+        SearchMode::Lexical => KnowledgeBase::lexical(&state.storage_layer.sql),
+        SearchMode::Vector | SearchMode::Hybrid => knowledge_base(&state)?,
+    };
+    let result = knowledge_base
+        .with_reranker(state.services.ai.as_ref())
+        .search(&collection, &data.query, &opts)
+        .await?;
+    Ok(Json(result))
+}
+
+/// A chat completion along with the passages it was given and the embedding usage of retrieval
+#[derive(Debug, Serialize)]
+pub struct CollectionChatResponse {
+    #[serde(flatten)]
+    pub completion: ChatCompletion,
+    pub citations: Vec<Citation>,
+    pub retrieval_usage: Usage,
+}
+
+pub async fn chat_with_collection(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    id: Result<Path<Uuid>, PathRejection>,
+    data: Result<Json<CollectionChat>, JsonRejection>,
+) -> Result<Json<CollectionChatResponse>, ApiError> {
+    let Path(id) = id?;
+    let Json(CollectionChat { mut options, top_k }) = data?;
+
+    let query = knowledge_base::last_user_message(&options)
+        .ok_or_else(|| ApiError::BadRequest("chat must include a user message".into()))?
+        .to_owned();
+
+    let collection = store::get_collection(&state.storage_layer.sql, id, &user.subject).await?;
+    let retrieved = knowledge_base(&state)?
+        .query(&collection, &query, top_k.unwrap_or(DEFAULT_TOP_K))
+        .await?;
+    let citations = knowledge_base::augment(&mut options, &retrieved.results);
+
+    options.stream = None;
+    let completion = state.services.ai.get_chat_completion(&options).await?;
+    Ok(Json(CollectionChatResponse {
+        completion,
+        citations,
+        retrieval_usage: retrieved.usage,
+    }))
+}
@@ -0,0 +1,50 @@
+use std::sync::Arc;
+
+use axum::{extract::DefaultBodyLimit, middleware, routing, Router};
+
+use crate::{app::auth, state::AppState};
+
+use self::controllers::{
+    chat_with_collection, create_collection, create_document, delete_collection, delete_document,
+    get_collection, get_upload, list_collections, list_documents, list_segments, list_uploads,
+    query_collection, search_collection, upload_document, MAX_UPLOAD_BYTES,
+};
+
+mod controllers;
+mod requests;
+
+pub fn routes(state: Arc<AppState>) -> Router<()> {
+    Router::new()
+        .route("/", routing::get(list_collections).post(create_collection))
+        .route(
+            "/:id",
+            routing::get(get_collection).delete(delete_collection),
+        )
+        .route(
+            "/:id/documents",
+            routing::get(list_documents).post(create_document),
+        )
+        .route(
+            "/:id/documents/:document_id",
+            routing::delete(delete_document),
+        )
+        .route(
+            "/:id/documents/:document_id/segments",
+            routing::get(list_segments),
+        )
+        .route(
+            "/:id/uploads",
+            routing::get(list_uploads)
+                .post(upload_document)
+                .layer(DefaultBodyLimit::max(MAX_UPLOAD_BYTES)),
+        )
+        .route("/:id/uploads/:job_id", routing::get(get_upload))
+        .route("/:id/query", routing::post(query_collection))
+        .route("/:id/search", routing::post(search_collection))
+        .route("/:id/chat", routing::post(chat_with_collection))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::simple_route_guard,
+        ))
+        .with_state(state)
+}
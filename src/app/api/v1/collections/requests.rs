@@ -0,0 +1,46 @@
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::app::{openai::chat::ChatOptions, rag::search::SearchMode};
+
+#[derive(Debug, Deserialize)]
+pub struct CreateCollection {
+    pub name: String,
+    pub description: Option<String>,
+    pub embedding_model: Option<String>,
+    pub dimensions: Option<u32>,
+    pub chunk_size: Option<usize>,
+    pub chunk_overlap: Option<usize>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateDocument {
+    pub title: String,
+    pub content: String,
+    pub source: Option<String>,
+    pub metadata: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryCollection {
+    pub query: String,
+    pub top_k: Option<usize>,
+}
+
+/// A search of a collection. `rerank` names the chat model used to rerank the results.
+#[derive(Debug, Deserialize)]
+pub struct SearchCollection {
+    pub query: String,
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub mode: SearchMode,
+    pub rerank: Option<String>,
+}
+
+/// A chat answered from a collection. Context is retrieved for the last user message.
+#[derive(Debug, Deserialize)]
+pub struct CollectionChat {
+    #[serde(flatten)]
+    pub options: ChatOptions,
+    pub top_k: Option<usize>,
+}
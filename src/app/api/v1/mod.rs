@@ -1,24 +1,38 @@
 use std::sync::Arc;
 
-use axum::{http::StatusCode, routing, Json, Router};
+use axum::{extract::State, http::StatusCode, routing, Json, Router};
 
 use crate::state::AppState;
 
+mod assets;
 mod auth;
+mod collections;
 mod openai;
 
 pub fn routes(state: Arc<AppState>) -> Router<()> {
     let openai_routes = openai::routes(state.clone());
+    let collections_routes = collections::routes(state.clone());
+    let assets_routes = assets::routes(state.clone());
+    let auth_routes = auth::routes(state.clone());
     Router::new()
-        .route(
-            "/health",
-            routing::get(|| async {
-                (
-                    StatusCode::OK,
-                    Json(serde_json::json!({"status": "healthy"})),
-                )
-            }),
-        )
+        .route("/health", routing::get(health))
         .with_state(state)
         .nest("/ai", openai_routes)
+        .nest("/collections", collections_routes)
+        .nest("/assets", assets_routes)
+        .nest("/auth", auth_routes)
+}
+
+/// Always 200 while the service can answer. A redis outage is reported as `degraded` since
+/// requests are still served from memory.
+async fn health(State(state): State<Arc<AppState>>) -> (StatusCode, Json<serde_json::Value>) {
+    let cache = state.storage_layer.cache.health().await;
+    let status = match cache.is_degraded() {
+        true => "degraded",
+        false => "healthy",
+    };
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({"status": status, "cache": cache})),
+    )
 }
@@ -1,27 +1,247 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::{IntoResponse, Response},
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Query, State,
+    },
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
     Json,
 };
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
 
-use crate::{app::openai, state::AppState};
+use crate::{
+    app::{
+        agent::runner::{Agent, AgentRun, DEFAULT_MAX_ITERATIONS},
+        api::errors::ApiError,
+        auth::principal::{AuthUser, Principal},
+        llm::{
+            cache::{self, CachePolicy},
+            semantic_cache::{self, SemanticCache},
+        },
+        openai::{
+            self,
+            embeddings::{EmbeddingOptions, EmbeddingResponse},
+            errors::OpenAIError,
+            stream::ChatCompletionStream,
+            usage::Usage,
+        },
+    },
+    state::AppState,
+};
+
+/// Seconds to cache the completion for. Requests opt into caching with it when the server has no
+/// default ttl.
+const CACHE_TTL_HEADER: &str = "x-cache-ttl";
+/// Skip the cache entirely
+const CACHE_BYPASS_HEADER: &str = "x-cache-bypass";
+/// Cache the completion even if the request is not deterministic
+const CACHE_FORCE_HEADER: &str = "x-cache-force";
+/// `HIT`, `MISS` or `BYPASS`
+const CACHE_STATUS_HEADER: &str = "x-cache";
+
+/// How a chat request wants the completion cache used, from its headers
+fn cache_policy(
+    headers: &HeaderMap,
+    default_ttl: Option<Duration>,
+) -> Result<CachePolicy, ApiError> {
+    let header = |name| {
+        headers
+            .get(name)
+            .map(|v: &HeaderValue| v.to_str().unwrap_or_default())
+    };
+    let flag = |name| match header(name).map(|v| v.trim().to_lowercase()).as_deref() {
+        None | Some("false" | "0") => Ok(false),
+        Some("true" | "1") => Ok(true),
+        Some(_) => Err(ApiError::BadRequest(format!(
+            "{name} must be `true` or `false`"
+        ))),
+    };
+
+    let ttl = match header(CACHE_TTL_HEADER) {
+        Some(secs) => match secs.trim().parse() {
+            Ok(secs) => Some(Duration::from_secs(secs)).filter(|ttl| !ttl.is_zero()),
+            Err(_) => {
+                return Err(ApiError::BadRequest(format!(
+                    "{CACHE_TTL_HEADER} must be a number of seconds"
+                )))
+            }
+        },
+        None => default_ttl,
+    };
+    Ok(CachePolicy {
+        ttl: ttl.unwrap_or_default(),
+        bypass: ttl.is_none() || flag(CACHE_BYPASS_HEADER)?,
+        force: flag(CACHE_FORCE_HEADER)?,
+    })
+}
+
+/// Semantic cache entries are shared within an organization, or kept to the user when the token
+/// has no organization
+fn cache_namespace(user: &Principal) -> String {
+    match &user.organization {
+        Some(organization) => format!("org:{organization}"),
+        None => format!("user:{}", user.subject),
+    }
+}
 
 pub async fn post_chat_message(
     State(state): State<Arc<AppState>>,
-    Json(data): Json<openai::chat::ChatOptions>,
-) -> (StatusCode, Response) {
-    match state.services.ai.get_chat_completion(&data).await {
-        Ok(chat) => (StatusCode::OK, Json(chat).into_response()),
-        Err(e) => {
-            log::error!("{}", e.to_string());
-            (
-                StatusCode::BAD_REQUEST,
-                Json(serde_json::json!({"msg": "unable to retrieve chat completion"}))
-                    .into_response(),
-            )
-        }
+    AuthUser(user): AuthUser,
+    headers: HeaderMap,
+    data: Result<Json<openai::chat::ChatOptions>, JsonRejection>,
+) -> Result<(StatusCode, Response), ApiError> {
+    let Json(data) = data?;
+    if data.stream.unwrap_or(false) {
+        return stream_chat_message(state, data).await;
     }
+
+    let policy = cache_policy(&headers, state.config.chat_cache_ttl)?;
+    let namespace = cache_namespace(&user);
+    let semantic = match (&state.config.semantic_cache, &state.services.embeddings) {
+        (Some(config), Some(embeddings)) => Some(SemanticCache::new(
+            &state.storage_layer.cache,
+            embeddings,
+            config,
+            &namespace,
+        )),
+        _ => None,
+    };
+    let (chat, status) = cache::get_chat_completion(
+        state.services.ai.as_ref(),
+        &state.storage_layer.cache,
+        semantic.as_ref(),
+        &data,
+        policy,
+    )
+    .await?;
+    let mut res = Json(chat).into_response();
+    res.headers_mut().insert(
+        CACHE_STATUS_HEADER,
+        HeaderValue::from_static(status.as_str()),
+    );
+    Ok((StatusCode::OK, res))
+}
+
+/// Relay a streamed completion to the client as server-sent events. Each chunk is sent as it
+/// arrives, followed by a `usage` event with the accumulated usage and a final `[DONE]` marker.
+async fn stream_chat_message(
+    state: Arc<AppState>,
+    data: openai::chat::ChatOptions,
+) -> Result<(StatusCode, Response), ApiError> {
+    let chunks = state.services.ai.stream_chat_completion(&data).await?;
+
+    let events = stream::unfold(
+        Some((chunks, Usage::default())),
+        |state: Option<(ChatCompletionStream, Usage)>| async move {
+            let (mut chunks, mut usage) = state?;
+            match chunks.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(chunk_usage) = &chunk.usage {
+                        usage += chunk_usage;
+                    }
+                    Some((Event::default().json_data(&chunk), Some((chunks, usage))))
+                }
+                Some(Err(e)) => {
+                    log::error!("{}", e.to_string());
+                    let event = Event::default()
+                        .event("error")
+                        .json_data(ApiError::from(e).problem());
+                    Some((event, None))
+                }
+                None => {
+                    log::info!("chat completion stream finished. usage: {usage:?}");
+                    Some((Event::default().event("usage").json_data(&usage), None))
+                }
+            }
+        },
+    )
+    .chain(stream::once(async { Ok(Event::default().data("[DONE]")) }));
+
+    Ok((
+        StatusCode::OK,
+        Sse::new(events)
+            .keep_alive(KeepAlive::default())
+            .into_response(),
+    ))
+}
+
+/// A chat request for the agent. The registered tools replace any tools in the request.
+#[derive(Debug, Deserialize)]
+pub struct AgentRequest {
+    #[serde(flatten)]
+    pub options: openai::chat::ChatOptions,
+    pub max_iterations: Option<usize>,
+}
+
+/// Let the model answer using the server's tools, returning the answer with a trace of every
+/// model call and tool execution
+pub async fn post_agent_message(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    data: Result<Json<AgentRequest>, JsonRejection>,
+) -> Result<Json<AgentRun>, ApiError> {
+    let Json(data) = data?;
+    let run = Agent::new(state.services.ai.as_ref(), &state.services.tools, &user)
+        .with_max_iterations(data.max_iterations.unwrap_or(DEFAULT_MAX_ITERATIONS))
+        .run(data.options)
+        .await?;
+    Ok(Json(run))
+}
+
+pub async fn post_embeddings(
+    State(state): State<Arc<AppState>>,
+    data: Result<Json<EmbeddingOptions>, JsonRejection>,
+) -> Result<Json<EmbeddingResponse>, ApiError> {
+    let Json(data) = data?;
+    let client =
+        state.services.embeddings.as_ref().ok_or_else(|| {
+            OpenAIError::Unavailable("no embeddings provider is configured".into())
+        })?;
+
+    if data.input.clone().into_vec().is_empty() {
+        return Err(ApiError::BadRequest("input must not be empty".into()));
+    }
+
+    Ok(Json(client.create_embeddings(&data).await?))
+}
+
+/// The namespace to purge, either `org:<organization>` or `user:<subject>`. Every namespace is
+/// purged when it's left out.
+#[derive(Debug, Deserialize)]
+pub struct PurgeSemanticCache {
+    pub namespace: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PurgedSemanticCache {
+    pub purged: usize,
+}
+
+/// Drop semantic cache entries. Only admins may purge the cache.
+pub async fn purge_semantic_cache(
+    State(state): State<Arc<AppState>>,
+    AuthUser(user): AuthUser,
+    query: Result<Query<PurgeSemanticCache>, QueryRejection>,
+) -> Result<Json<PurgedSemanticCache>, ApiError> {
+    let Query(query) = query?;
+    if !state.config.is_admin(&user.subject) {
+        return Err(ApiError::Forbidden(
+            "only admins can purge the semantic cache".into(),
+        ));
+    }
+
+    let purged =
+        semantic_cache::purge(&state.storage_layer.cache, query.namespace.as_deref()).await?;
+    log::info!(
+        "{} purged {purged} semantic cache keys from {}",
+        user.subject,
+        query.namespace.as_deref().unwrap_or("every namespace")
+    );
+    Ok(Json(PurgedSemanticCache { purged }))
 }
@@ -4,13 +4,21 @@ use axum::{middleware, routing, Json, Router};
 
 use crate::{app::auth, state::AppState};
 
-use self::controllers::post_chat_message;
+use self::{
+    controllers::{post_agent_message, post_chat_message, post_embeddings, purge_semantic_cache},
+    socket::chat_socket,
+};
 
 mod controllers;
+mod socket;
 
 pub fn routes(state: Arc<AppState>) -> Router<()> {
     Router::new()
         .route("/completions", routing::post(post_chat_message))
+        .route("/chat", routing::get(chat_socket))
+        .route("/agent", routing::post(post_agent_message))
+        .route("/embeddings", routing::post(post_embeddings))
+        .route("/semantic-cache", routing::delete(purge_semantic_cache))
         // .route(
         //     "/completions",
         //     routing::get(|| async move { Json(serde_json::json!({"msg": "here"})) }),
@@ -0,0 +1,266 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::openai::{
+        chat::{ChatCompletionChunk, ChatMessage, ChatOptions, ChatRole, ToolCall},
+        errors::OpenAIError,
+        stream::ChatCompletionStream,
+        usage::Usage,
+    },
+    state::AppState,
+};
+
+/// Frames sent by the client. A `chat` frame is a `ChatOptions` object with an extra
+/// `"type": "chat"` field. Its messages are appended to the conversation held by the socket.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientFrame {
+    Chat(Box<ChatOptions>),
+    Cancel,
+}
+
+/// Frames sent by the server
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerFrame {
+    Delta { chunk: ChatCompletionChunk },
+    Done { usage: Usage },
+    Cancelled,
+    Error { msg: String, code: &'static str },
+}
+
+impl From<&OpenAIError> for ServerFrame {
+    fn from(e: &OpenAIError) -> Self {
+        ServerFrame::Error {
+            msg: e.public_message(),
+            code: e.code(),
+        }
+    }
+}
+
+/// Most messages of the conversation kept by a socket. Older turns are dropped first.
+const MAX_HISTORY_MESSAGES: usize = 64;
+
+/// A generation in progress on the socket
+struct Generation {
+    chunks: ChatCompletionStream,
+    /// Length of the history before the turn that started the generation, which is rolled back
+    /// to if the generation doesn't finish
+    turn_start: usize,
+    reply: String,
+    tool_calls: Vec<ToolCall>,
+    usage: Usage,
+}
+
+pub async fn chat_socket(State(state): State<Arc<AppState>>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| handle_chat_socket(socket, state))
+}
+
+async fn next_chunk(
+    generation: &mut Option<Generation>,
+) -> Option<Result<ChatCompletionChunk, OpenAIError>> {
+    match generation {
+        Some(generation) => generation.chunks.next().await,
+        None => futures::future::pending().await,
+    }
+}
+
+async fn send(socket: &mut WebSocket, frame: &ServerFrame) -> Result<(), axum::Error> {
+    let text = serde_json::to_string(frame).map_err(axum::Error::new)?;
+    socket.send(Message::Text(text)).await
+}
+
+impl Generation {
+    /// Accumulate the reply, tool calls and usage of a chunk
+    fn add(&mut self, chunk: &ChatCompletionChunk) -> Result<(), OpenAIError> {
+        if let Some(usage) = &chunk.usage {
+            self.usage += usage;
+        }
+        for choice in &chunk.choices {
+            if let Some(content) = &choice.delta.content {
+                self.reply.push_str(content);
+            }
+            for delta in choice.delta.tool_calls.iter().flatten() {
+                delta.apply(&mut self.tool_calls)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stop the generation in progress, if any, and remove its turn from the history. Returns
+/// whether there was a generation to stop.
+fn abandon(generation: &mut Option<Generation>, history: &mut Vec<ChatMessage>) -> bool {
+    match generation.take() {
+        Some(abandoned) => {
+            history.truncate(abandoned.turn_start);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Drop the oldest turns until the history fits, keeping any leading system messages. The
+/// history is cut before a user message, so that it never opens with a reply or a tool result.
+fn trim_history(history: &mut Vec<ChatMessage>) {
+    if history.len() <= MAX_HISTORY_MESSAGES {
+        return;
+    }
+    let system = history
+        .iter()
+        .take_while(|message| matches!(message.role, ChatRole::System))
+        .count();
+    let excess = history.len() - MAX_HISTORY_MESSAGES;
+    let cut = history[system..]
+        .iter()
+        .enumerate()
+        .skip(excess)
+        .find(|(_, message)| matches!(message.role, ChatRole::User))
+        .map_or(history.len() - system, |(at, _)| at);
+    history.drain(system..system + cut);
+}
+
+/// Drive a multi-turn chat over a websocket. The socket keeps the conversation history, streams
+/// deltas for one generation at a time, and aborts the upstream request when the client cancels
+/// (dropping the stream closes the connection to the provider). A turn that is cancelled or fails
+/// is removed from the history.
+async fn handle_chat_socket(mut socket: WebSocket, state: Arc<AppState>) {
+    let mut history: Vec<ChatMessage> = vec![];
+    let mut generation: Option<Generation> = None;
+
+    loop {
+        let frame = tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<ClientFrame>(&text) {
+                    Ok(ClientFrame::Chat(_)) if generation.is_some() => Some(ServerFrame::Error {
+                        msg: "a chat completion is already in progress".into(),
+                        code: "generation_in_progress",
+                    }),
+                    Ok(ClientFrame::Chat(mut opts)) => {
+                        let turn_start = history.len();
+                        history.append(&mut opts.messages);
+                        opts.messages = history.clone();
+                        match state.services.ai.stream_chat_completion(&opts).await {
+                            Ok(chunks) => {
+                                generation = Some(Generation {
+                                    chunks,
+                                    turn_start,
+                                    reply: String::new(),
+                                    tool_calls: vec![],
+                                    usage: Usage::default(),
+                                });
+                                None
+                            }
+                            Err(e) => {
+                                log::error!("{}", e.to_string());
+                                history.truncate(turn_start);
+                                Some(ServerFrame::from(&e))
+                            }
+                        }
+                    }
+                    Ok(ClientFrame::Cancel) => abandon(&mut generation, &mut history)
+                        .then_some(ServerFrame::Cancelled),
+                    Err(e) => Some(ServerFrame::Error {
+                        msg: format!("invalid frame. error: {e}"),
+                        code: "invalid_frame",
+                    }),
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // pings are answered by axum, binary frames are not part of the protocol
+                Some(Ok(_)) => None,
+            },
+            chunk = next_chunk(&mut generation) => match chunk {
+                Some(Ok(chunk)) => {
+                    let added = generation.as_mut().map_or(Ok(()), |current| current.add(&chunk));
+                    match added {
+                        Ok(()) => Some(ServerFrame::Delta { chunk }),
+                        Err(e) => {
+                            log::error!("{}", e.to_string());
+                            abandon(&mut generation, &mut history);
+                            Some(ServerFrame::from(&e))
+                        }
+                    }
+                }
+                Some(Err(e)) => {
+                    log::error!("{}", e.to_string());
+                    abandon(&mut generation, &mut history);
+                    Some(ServerFrame::from(&e))
+                }
+                None => generation.take().map(|finished| {
+                    // tool results sent by the client are answered against these calls
+                    history.push(ChatMessage {
+                        tool_calls: (!finished.tool_calls.is_empty())
+                            .then_some(finished.tool_calls),
+                        ..ChatMessage::new(ChatRole::Assistant, &finished.reply)
+                    });
+                    trim_history(&mut history);
+                    ServerFrame::Done {
+                        usage: finished.usage,
+                    }
+                }),
+            },
+        };
+
+        if let Some(frame) = frame {
+            if let Err(e) = send(&mut socket, &frame).await {
+                log::error!("{}", e.to_string());
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_client_frames() {
+        let chat = r#"{"type":"chat","model":"gpt-3.5-turbo","max_tokens":20,"messages":[{"role":"user","content":"hi"}]}"#;
+        match serde_json::from_str::<ClientFrame>(chat).unwrap() {
+            ClientFrame::Chat(opts) => assert_eq!(opts.messages.len(), 1),
+            frame => panic!("unexpected frame {frame:?}"),
+        }
+
+        assert!(matches!(
+            serde_json::from_str::<ClientFrame>(r#"{"type":"cancel"}"#).unwrap(),
+            ClientFrame::Cancel
+        ));
+    }
+
+    #[test]
+    pub fn test_trim_history() {
+        let turn = |i: usize| {
+            vec![
+                ChatMessage::new(ChatRole::User, &format!("question {i}")),
+                ChatMessage::new(ChatRole::Assistant, &format!("answer {i}")),
+            ]
+        };
+        let mut history = vec![ChatMessage::new(ChatRole::System, "be brief")];
+        history.extend((0..MAX_HISTORY_MESSAGES / 2).flat_map(turn));
+        trim_history(&mut history);
+        // the whole oldest turn goes, not just its question
+        assert_eq!(history.len(), MAX_HISTORY_MESSAGES - 1);
+        assert_eq!(history[1].content.as_deref(), Some("question 1"));
+
+        history.extend(turn(100));
+        trim_history(&mut history);
+        assert_eq!(history.len(), MAX_HISTORY_MESSAGES - 1);
+        assert!(matches!(history[0].role, ChatRole::System));
+        assert_eq!(history[1].content.as_deref(), Some("question 2"));
+        assert_eq!(
+            history.last().and_then(|m| m.content.as_deref()),
+            Some("answer 100")
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::app::types::{AssetBackend, AssetVisibility};
+
+use super::errors::AssetError;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Asset {
+    pub id: Uuid,
+    pub owner: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    /// Hex encoded sha-256 of the contents
+    pub checksum: String,
+    pub visibility: AssetVisibility,
+    #[serde(skip)]
+    pub backend: AssetBackend,
+    #[serde(skip)]
+    pub key: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Asset {
+    /// Private assets are only visible to their owner. Others are told they don't exist.
+    pub fn visible_to(&self, subject: &str) -> bool {
+        matches!(self.visibility, AssetVisibility::Public) || self.owner == subject
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NewAsset {
+    pub id: Uuid,
+    pub owner: String,
+    pub filename: String,
+    pub content_type: String,
+    pub size: i64,
+    pub checksum: String,
+    pub visibility: AssetVisibility,
+    pub backend: AssetBackend,
+    pub key: String,
+}
+
+const ASSET_COLUMNS: &str = "id, owner, filename, content_type, size, checksum, visibility, \
+     backend, key, created_at, updated_at";
+
+pub async fn insert_asset(sql: &PgPool, new: &NewAsset) -> Result<Asset, AssetError> {
+    let query = format!(
+        "insert into assets (id, owner, filename, content_type, size, checksum, visibility, \
+         backend, key) values ($1, $2, $3, $4, $5, $6, $7, $8, $9) returning {ASSET_COLUMNS}"
+    );
+    Ok(sqlx::query_as(&query)
+        .bind(new.id)
+        .bind(&new.owner)
+        .bind(&new.filename)
+        .bind(&new.content_type)
+        .bind(new.size)
+        .bind(&new.checksum)
+        .bind(new.visibility)
+        .bind(new.backend)
+        .bind(&new.key)
+        .fetch_one(sql)
+        .await?)
+}
+
+pub async fn get_asset(sql: &PgPool, id: Uuid) -> Result<Asset, AssetError> {
+    let query = format!("select {ASSET_COLUMNS} from assets where id = $1");
+    sqlx::query_as(&query)
+        .bind(id)
+        .fetch_optional(sql)
+        .await?
+        .ok_or_else(|| AssetError::NotFound(format!("no asset with id {id}")))
+}
+
+pub async fn list_assets(sql: &PgPool, owner: &str) -> Result<Vec<Asset>, AssetError> {
+    let query =
+        format!("select {ASSET_COLUMNS} from assets where owner = $1 order by created_at desc");
+    Ok(sqlx::query_as(&query).bind(owner).fetch_all(sql).await?)
+}
+
+pub async fn delete_asset(sql: &PgPool, id: Uuid) -> Result<(), AssetError> {
+    let deleted = sqlx::query("delete from assets where id = $1")
+        .bind(id)
+        .execute(sql)
+        .await?;
+    match deleted.rows_affected() {
+        0 => Err(AssetError::NotFound(format!("no asset with id {id}"))),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,25 @@
+use thiserror::Error;
+
+use crate::app::storage::errors::DbError;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum AssetError {
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    /// The blob store could not be reached or refused the request
+    #[error("asset backend error: {0}")]
+    Backend(String),
+}
+
+impl From<sqlx::Error> for AssetError {
+    fn from(e: sqlx::Error) -> Self {
+        log::error!("{}", e.to_string());
+        AssetError::Db(DbError::Query(e.to_string()))
+    }
+}
@@ -0,0 +1,134 @@
+use std::{
+    io::{ErrorKind, SeekFrom},
+    path::PathBuf,
+};
+
+use futures::{StreamExt, TryStreamExt};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+use crate::app::types::AssetBackend;
+
+use super::{
+    errors::AssetError,
+    range::ByteRange,
+    store::{validate_key, AssetStore, AssetStream},
+};
+
+/// Keeps assets as files under a root directory
+#[derive(Debug, Clone)]
+pub struct FsStore {
+    root: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf, AssetError> {
+        validate_key(key)?;
+        Ok(self.root.join(key))
+    }
+}
+
+fn backend_error(e: std::io::Error) -> AssetError {
+    log::error!("{}", e.to_string());
+    AssetError::Backend(e.to_string())
+}
+
+#[async_trait::async_trait]
+impl AssetStore for FsStore {
+    fn backend(&self) -> AssetBackend {
+        AssetBackend::Fs
+    }
+
+    /// Files are written next to their final path and renamed into place, so readers never see a
+    /// partially written asset
+    async fn put(&self, key: &str, _content_type: &str, bytes: Vec<u8>) -> Result<(), AssetError> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(backend_error)?;
+        }
+
+        let partial = path.with_extension(format!("partial-{}", Uuid::new_v4()));
+        tokio::fs::write(&partial, bytes)
+            .await
+            .map_err(backend_error)?;
+        if let Err(e) = tokio::fs::rename(&partial, &path).await {
+            let _ = tokio::fs::remove_file(&partial).await;
+            return Err(backend_error(e));
+        }
+        Ok(())
+    }
+
+    async fn stream(&self, key: &str, range: Option<ByteRange>) -> Result<AssetStream, AssetError> {
+        let mut file = match tokio::fs::File::open(self.path(key)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Err(AssetError::NotFound(format!("no asset stored at {key}")))
+            }
+            Err(e) => return Err(backend_error(e)),
+        };
+
+        let stream = match range {
+            Some(range) => {
+                file.seek(SeekFrom::Start(range.start))
+                    .await
+                    .map_err(backend_error)?;
+                ReaderStream::new(file.take(range.length())).boxed()
+            }
+            None => ReaderStream::new(file).boxed(),
+        };
+        Ok(Box::pin(stream.map_err(backend_error)))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AssetError> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(backend_error(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::assets::store::test_util::read;
+
+    #[tokio::test]
+    pub async fn test_fs_store() {
+        let root = std::env::temp_dir().join(format!("melody-assets-{}", Uuid::new_v4()));
+        let store = FsStore::new(&root);
+
+        store
+            .put("avatars/one.png", "image/png", b"png bytes".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(
+            read(&store, "avatars/one.png", None).await.unwrap(),
+            b"png bytes"
+        );
+        let range = ByteRange { start: 4, end: 7 };
+        assert_eq!(
+            read(&store, "avatars/one.png", Some(range)).await.unwrap(),
+            b"byte"
+        );
+
+        store.delete("avatars/one.png").await.unwrap();
+        store.delete("avatars/one.png").await.unwrap();
+        assert!(matches!(
+            read(&store, "avatars/one.png", None).await,
+            Err(AssetError::NotFound(_))
+        ));
+        assert!(matches!(
+            read(&store, "../etc/passwd", None).await,
+            Err(AssetError::InvalidInput(_))
+        ));
+
+        tokio::fs::remove_dir_all(root).await.unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+pub mod db;
+pub mod errors;
+pub mod fs;
+pub mod range;
+pub mod s3;
+pub mod signing;
+pub mod sigv4;
+pub mod store;
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::app::types::AssetVisibility;
+
+use self::{
+    db::{Asset, NewAsset},
+    errors::AssetError,
+    range::ByteRange,
+    store::{AssetStore, AssetStream},
+};
+
+/// Longest lifetime of a signed download link
+pub const MAX_LINK_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+
+/// An asset that may be read by `subject`. Private assets of other owners are reported as missing
+/// so their ids can't be probed.
+pub async fn find_visible(sql: &PgPool, id: Uuid, subject: &str) -> Result<Asset, AssetError> {
+    let asset = db::get_asset(sql, id).await?;
+    match asset.visible_to(subject) {
+        true => Ok(asset),
+        false => Err(AssetError::NotFound(format!("no asset with id {id}"))),
+    }
+}
+
+/// A public asset. Private assets are reported as missing.
+pub async fn find_public(sql: &PgPool, id: Uuid) -> Result<Asset, AssetError> {
+    let asset = db::get_asset(sql, id).await?;
+    match asset.visibility {
+        AssetVisibility::Public => Ok(asset),
+        AssetVisibility::Private => Err(AssetError::NotFound(format!("no asset with id {id}"))),
+    }
+}
+
+/// The asset a signed download link points at, once its signature and expiry are checked
+pub async fn find_signed(
+    sql: &PgPool,
+    secret: &[u8],
+    id: Uuid,
+    expires: i64,
+    signature: &str,
+) -> Result<Asset, AssetError> {
+    signing::verify(secret, id, expires, signature, Utc::now().timestamp())?;
+    db::get_asset(sql, id).await
+}
+
+/// Sign a download link for `asset` that works for `ttl` without credentials. Returns the link's
+/// expiry and signature.
+pub fn sign_link(
+    secret: &[u8],
+    asset: &Asset,
+    ttl: Duration,
+) -> Result<(DateTime<Utc>, String), AssetError> {
+    if ttl <= Duration::zero() || ttl > Duration::seconds(MAX_LINK_TTL_SECS) {
+        return Err(AssetError::InvalidInput(format!(
+            "links must expire within {MAX_LINK_TTL_SECS} seconds"
+        )));
+    }
+    // links carry whole seconds, so the reported expiry is truncated to match
+    let expires = (Utc::now() + ttl).timestamp();
+    let expires_at = DateTime::from_timestamp(expires, 0).expect("expiry is a valid timestamp");
+    Ok((expires_at, signing::sign(secret, asset.id, expires)))
+}
+
+/// Store the contents of an asset, then record it. The contents are removed again if the record
+/// can't be written.
+pub async fn upload(
+    sql: &PgPool,
+    store: &dyn AssetStore,
+    owner: &str,
+    filename: &str,
+    content_type: &str,
+    visibility: AssetVisibility,
+    bytes: Vec<u8>,
+) -> Result<Asset, AssetError> {
+    let id = Uuid::new_v4();
+    let new = NewAsset {
+        id,
+        owner: owner.to_owned(),
+        filename: filename.to_owned(),
+        content_type: content_type.to_owned(),
+        size: bytes.len() as i64,
+        checksum: sigv4::sha256_hex(&bytes),
+        visibility,
+        backend: store.backend(),
+        key: id.to_string(),
+    };
+
+    store.put(&new.key, content_type, bytes).await?;
+    match db::insert_asset(sql, &new).await {
+        Ok(asset) => Ok(asset),
+        Err(e) => {
+            if let Err(e) = store.delete(&new.key).await {
+                log::error!("unable to remove contents of unrecorded asset {id}: {e}");
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Stream the contents of an asset, or `range` of them. Assets recorded while another backend was
+/// configured can't be read from this one.
+pub async fn open(
+    store: &dyn AssetStore,
+    asset: &Asset,
+    range: Option<ByteRange>,
+) -> Result<AssetStream, AssetError> {
+    if asset.backend != store.backend() {
+        return Err(AssetError::Backend(format!(
+            "asset {} is stored in the {:?} backend",
+            asset.id, asset.backend
+        )));
+    }
+    store.stream(&asset.key, range).await
+}
+
+/// Delete an asset owned by `subject`. The record goes first, so a failure to remove the contents
+/// leaves an orphaned blob rather than a record pointing at nothing.
+pub async fn delete(
+    sql: &PgPool,
+    store: &dyn AssetStore,
+    id: Uuid,
+    subject: &str,
+) -> Result<(), AssetError> {
+    let asset = find_visible(sql, id, subject).await?;
+    if asset.owner != subject {
+        return Err(AssetError::Forbidden(
+            "only the owner of an asset can delete it".into(),
+        ));
+    }
+
+    db::delete_asset(sql, id).await?;
+    if let Err(e) = store.delete(&asset.key).await {
+        log::error!("unable to remove contents of deleted asset {id}: {e}");
+    }
+    Ok(())
+}
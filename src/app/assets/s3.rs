@@ -0,0 +1,322 @@
+use chrono::Utc;
+use futures::TryStreamExt;
+use reqwest::{Client, Method, StatusCode, Url};
+
+use crate::app::types::AssetBackend;
+
+use super::{
+    errors::AssetError,
+    range::ByteRange,
+    sigv4::{self, Signer},
+    store::{validate_key, AssetStore, AssetStream},
+};
+
+#[derive(Debug, Clone)]
+pub struct S3Options {
+    /// e.g. `https://s3.us-east-1.amazonaws.com`, or `http://localhost:9000` for minio
+    pub endpoint: String,
+    pub bucket: String,
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// Address the bucket in the path rather than the host name. minio and most other s3
+    /// compatible servers need this.
+    pub path_style: bool,
+}
+
+/// Keeps assets in an s3 compatible bucket
+#[derive(Debug, Clone)]
+pub struct S3Store {
+    http: Client,
+    signer: Signer,
+    endpoint: Url,
+    bucket: String,
+    path_style: bool,
+}
+
+impl S3Store {
+    pub fn new(options: S3Options) -> Result<Self, AssetError> {
+        let endpoint = Url::parse(&options.endpoint)
+            .map_err(|e| AssetError::InvalidInput(format!("invalid s3 endpoint: {e}")))?;
+        if endpoint.host_str().is_none() {
+            return Err(AssetError::InvalidInput(
+                "s3 endpoint must have a host".into(),
+            ));
+        }
+
+        Ok(Self {
+            http: Client::new(),
+            signer: Signer {
+                access_key_id: options.access_key_id,
+                secret_access_key: options.secret_access_key,
+                region: options.region,
+                service: "s3".into(),
+            },
+            endpoint,
+            bucket: options.bucket,
+            path_style: options.path_style,
+        })
+    }
+
+    /// The url of an object along with the values of its `host` header and canonical path
+    fn locate(&self, key: &str) -> Result<(Url, String, String), AssetError> {
+        validate_key(key)?;
+        let host = self.endpoint.host_str().unwrap_or_default();
+        let (host, path) = match self.path_style {
+            true => (host.to_owned(), format!("/{}/{key}", self.bucket)),
+            false => (format!("{}.{host}", self.bucket), format!("/{key}")),
+        };
+        let host = match self.endpoint.port() {
+            Some(port) => format!("{host}:{port}"),
+            None => host,
+        };
+        let path = sigv4::encode_path(&path);
+
+        let url = format!("{}://{host}{path}", self.endpoint.scheme());
+        let url = Url::parse(&url)
+            .map_err(|e| AssetError::InvalidInput(format!("invalid asset url: {e}")))?;
+        Ok((url, host, path))
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        key: &str,
+        content_type: Option<&str>,
+        range: Option<ByteRange>,
+        body: Option<Vec<u8>>,
+    ) -> Result<reqwest::Response, AssetError> {
+        let (url, host, path) = self.locate(key)?;
+        let payload_hash = match &body {
+            Some(body) => sigv4::sha256_hex(body),
+            None => sigv4::EMPTY_PAYLOAD_SHA256.to_owned(),
+        };
+        let now = Utc::now();
+        let amz_date = sigv4::amz_date(now);
+        let range = range.map(|range| format!("bytes={}-{}", range.start, range.end));
+
+        let mut headers = vec![
+            ("host", host.as_str()),
+            ("x-amz-content-sha256", payload_hash.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        if let Some(content_type) = content_type {
+            headers.push(("content-type", content_type));
+        }
+        if let Some(range) = &range {
+            headers.push(("range", range));
+        }
+        let authorization =
+            self.signer
+                .authorization(method.as_str(), &path, "", &headers, &payload_hash, now);
+
+        // reqwest sets the host header from the url, which matches the signed value
+        let mut req = self
+            .http
+            .request(method, url)
+            .header("authorization", authorization);
+        for (name, value) in headers.iter().filter(|(name, _)| *name != "host") {
+            req = req.header(*name, *value);
+        }
+        if let Some(body) = body {
+            req = req.body(body);
+        }
+
+        let res = req.send().await.map_err(|e| {
+            log::error!("{}", e.to_string());
+            AssetError::Backend(e.to_string())
+        })?;
+        match res.status() {
+            status if status.is_success() => Ok(res),
+            StatusCode::NOT_FOUND => Err(AssetError::NotFound(format!("no asset stored at {key}"))),
+            status => {
+                let body = res.text().await.unwrap_or_default();
+                log::error!("s3 responded with {status}: {body}");
+                Err(AssetError::Backend(format!("s3 responded with {status}")))
+            }
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl AssetStore for S3Store {
+    fn backend(&self) -> AssetBackend {
+        AssetBackend::Aws
+    }
+
+    async fn put(&self, key: &str, content_type: &str, bytes: Vec<u8>) -> Result<(), AssetError> {
+        self.send(Method::PUT, key, Some(content_type), None, Some(bytes))
+            .await?;
+        Ok(())
+    }
+
+    async fn stream(&self, key: &str, range: Option<ByteRange>) -> Result<AssetStream, AssetError> {
+        let res = self.send(Method::GET, key, None, range, None).await?;
+        Ok(Box::pin(res.bytes_stream().map_err(|e| {
+            log::error!("{}", e.to_string());
+            AssetError::Backend(e.to_string())
+        })))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), AssetError> {
+        self.send(Method::DELETE, key, None, None, None).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        env,
+        sync::{Arc, Mutex},
+    };
+
+    use axum::{
+        body::Bytes,
+        extract::{Path, State},
+        http::{HeaderMap, StatusCode},
+        routing, Router,
+    };
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::app::{assets::store::test_util::read, util};
+
+    type Objects = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+    /// Serve a path style bucket from memory, rejecting requests that are not signed
+    async fn s3_server() -> String {
+        async fn object(
+            State(objects): State<Objects>,
+            Path((bucket, key)): Path<(String, String)>,
+            method: axum::http::Method,
+            headers: HeaderMap,
+            body: Bytes,
+        ) -> (StatusCode, Vec<u8>) {
+            let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+            let signed = header("authorization").is_some_and(|authorization| {
+                authorization.starts_with("AWS4-HMAC-SHA256 Credential=minio/")
+            });
+            if bucket != "assets"
+                || !signed
+                || header("x-amz-content-sha256") != Some(&sigv4::sha256_hex(&body))
+            {
+                return (StatusCode::FORBIDDEN, vec![]);
+            }
+
+            let mut objects = objects.lock().unwrap();
+            match method.as_str() {
+                "PUT" => {
+                    objects.insert(key, body.to_vec());
+                    (StatusCode::OK, vec![])
+                }
+                "GET" => match (objects.get(&key), header("range")) {
+                    (Some(bytes), Some(range)) => {
+                        let (start, end) =
+                            range.trim_start_matches("bytes=").split_once('-').unwrap();
+                        let (start, end) = (start.parse().unwrap(), end.parse::<usize>().unwrap());
+                        (StatusCode::PARTIAL_CONTENT, bytes[start..=end].to_vec())
+                    }
+                    (Some(bytes), None) => (StatusCode::OK, bytes.clone()),
+                    (None, _) => (StatusCode::NOT_FOUND, vec![]),
+                },
+                _ => {
+                    objects.remove(&key);
+                    (StatusCode::NO_CONTENT, vec![])
+                }
+            }
+        }
+
+        let app = Router::new()
+            .route(
+                "/:bucket/*key",
+                routing::put(object).get(object).delete(object),
+            )
+            .with_state(Objects::default());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        uri
+    }
+
+    async fn round_trip(store: &S3Store) {
+        let key = format!("tests/{}.txt", Uuid::new_v4());
+        store
+            .put(&key, "text/plain", b"hello from melody".to_vec())
+            .await
+            .unwrap();
+        assert_eq!(read(store, &key, None).await.unwrap(), b"hello from melody");
+        let range = ByteRange { start: 6, end: 9 };
+        assert_eq!(read(store, &key, Some(range)).await.unwrap(), b"from");
+        store.delete(&key).await.unwrap();
+        assert!(matches!(
+            read(store, &key, None).await,
+            Err(AssetError::NotFound(_))
+        ));
+    }
+
+    #[tokio::test]
+    pub async fn test_s3_store() {
+        let store = S3Store::new(S3Options {
+            endpoint: s3_server().await,
+            bucket: "assets".into(),
+            region: "us-east-1".into(),
+            access_key_id: "minio".into(),
+            secret_access_key: "minio-secret".into(),
+            path_style: true,
+        })
+        .unwrap();
+        round_trip(&store).await;
+    }
+
+    #[test]
+    pub fn test_locate() {
+        let options = S3Options {
+            endpoint: "https://s3.us-east-1.amazonaws.com".into(),
+            bucket: "assets".into(),
+            region: "us-east-1".into(),
+            access_key_id: "key".into(),
+            secret_access_key: "secret".into(),
+            path_style: false,
+        };
+        let (url, host, path) = S3Store::new(options.clone())
+            .unwrap()
+            .locate("avatars/one.png")
+            .unwrap();
+        assert_eq!(
+            url.as_str(),
+            "https://assets.s3.us-east-1.amazonaws.com/avatars/one.png"
+        );
+        assert_eq!(host, "assets.s3.us-east-1.amazonaws.com");
+        assert_eq!(path, "/avatars/one.png");
+
+        let path_style = S3Store::new(S3Options {
+            endpoint: "http://localhost:9000".into(),
+            path_style: true,
+            ..options
+        })
+        .unwrap();
+        let (url, host, _) = path_style.locate("avatars/one.png").unwrap();
+        assert_eq!(url.as_str(), "http://localhost:9000/assets/avatars/one.png");
+        assert_eq!(host, "localhost:9000");
+        assert!(path_style.locate("../one.png").is_err());
+    }
+
+    #[tokio::test]
+    pub async fn test_s3_store_minio() {
+        util::test_util::init();
+
+        let store = S3Store::new(S3Options {
+            endpoint: env::var("S3_ENDPOINT").expect("missing s3 endpoint"),
+            bucket: env::var("S3_BUCKET").expect("missing s3 bucket"),
+            region: env::var("S3_REGION").expect("missing s3 region"),
+            access_key_id: env::var("S3_ACCESS_KEY_ID").expect("missing s3 access key id"),
+            secret_access_key: env::var("S3_SECRET_ACCESS_KEY")
+                .expect("missing s3 secret access key"),
+            path_style: true,
+        })
+        .unwrap();
+        round_trip(&store).await;
+    }
+}
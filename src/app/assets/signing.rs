@@ -0,0 +1,63 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::Uuid;
+
+use super::errors::AssetError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn mac(secret: &[u8], id: Uuid, expires: i64) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("hmac accepts keys of any length");
+    mac.update(format!("asset:{id}:{expires}").as_bytes());
+    mac
+}
+
+/// The signature of a download link for asset `id` that stops working at the unix timestamp
+/// `expires`
+pub fn sign(secret: &[u8], id: Uuid, expires: i64) -> String {
+    URL_SAFE_NO_PAD.encode(mac(secret, id, expires).finalize().into_bytes())
+}
+
+/// Check the signature of a download link. Signatures are compared in constant time, and a link
+/// past its expiry is rejected even when its signature is valid.
+pub fn verify(
+    secret: &[u8],
+    id: Uuid,
+    expires: i64,
+    signature: &str,
+    now: i64,
+) -> Result<(), AssetError> {
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| AssetError::Forbidden("invalid download signature".into()))?;
+    mac(secret, id, expires)
+        .verify_slice(&signature)
+        .map_err(|_| AssetError::Forbidden("invalid download signature".into()))?;
+    match now < expires {
+        true => Ok(()),
+        false => Err(AssetError::Forbidden("download link has expired".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_sign_and_verify() {
+        let (secret, id) = (b"app secret".as_slice(), Uuid::new_v4());
+        let signature = sign(secret, id, 1_000);
+
+        assert_eq!(verify(secret, id, 1_000, &signature, 999), Ok(()));
+        assert!(matches!(
+            verify(secret, id, 1_000, &signature, 1_000),
+            Err(AssetError::Forbidden(detail)) if detail.contains("expired")
+        ));
+        // a later expiry, another asset or another secret all invalidate the signature
+        assert!(verify(secret, id, 2_000, &signature, 999).is_err());
+        assert!(verify(secret, Uuid::new_v4(), 1_000, &signature, 999).is_err());
+        assert!(verify(b"other secret", id, 1_000, &signature, 999).is_err());
+        assert!(verify(secret, id, 1_000, "not base64!", 999).is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use std::pin::Pin;
+
+use bytes::Bytes;
+use futures::Stream;
+
+use crate::app::types::AssetBackend;
+
+use super::{errors::AssetError, range::ByteRange};
+
+/// Asset contents read incrementally from a store
+pub type AssetStream = Pin<Box<dyn Stream<Item = Result<Bytes, AssetError>> + Send>>;
+
+/// Where asset contents are kept. Keys are generated by the server and only contain ascii
+/// letters, digits, `-`, `_`, `.` and `/`.
+#[async_trait::async_trait]
+pub trait AssetStore: Send + Sync {
+    fn backend(&self) -> AssetBackend;
+
+    async fn put(&self, key: &str, content_type: &str, bytes: Vec<u8>) -> Result<(), AssetError>;
+
+    /// Stream the contents of a key, or only `range` of them. The range must lie within the
+    /// stored contents.
+    async fn stream(&self, key: &str, range: Option<ByteRange>) -> Result<AssetStream, AssetError>;
+
+    /// Deleting a key that does not exist succeeds
+    async fn delete(&self, key: &str) -> Result<(), AssetError>;
+}
+
+/// Reject keys that could escape the store's root or bucket prefix
+pub fn validate_key(key: &str) -> Result<(), AssetError> {
+    let allowed = key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '/'));
+    let traverses = key
+        .split('/')
+        .any(|part| part.is_empty() || part == "." || part == "..");
+    match allowed && !traverses {
+        true => Ok(()),
+        false => Err(AssetError::InvalidInput(format!(
+            "invalid asset key {key:?}"
+        ))),
+    }
+}
+
+#[cfg(test)]
+pub mod test_util {
+    use futures::TryStreamExt;
+
+    use super::*;
+
+    /// Collect the contents of a key, or `range` of them
+    pub async fn read(
+        store: &dyn AssetStore,
+        key: &str,
+        range: Option<ByteRange>,
+    ) -> Result<Vec<u8>, AssetError> {
+        let chunks: Vec<Bytes> = store.stream(key, range).await?.try_collect().await?;
+        Ok(chunks.concat())
+    }
+}
@@ -1,10 +1,13 @@
-use jsonwebtoken::{jwk::JwkSet, DecodingKey, Validation};
+use jsonwebtoken::{Algorithm, Validation};
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
 
 use super::{
     authenticator::{Authenticator, UserData},
     errors::AuthError,
+    jwks::JwksCache,
+    oidc,
+    principal::Principal,
+    verifier::TokenVerifier,
 };
 
 const AUTHENTICATOR_ID: &'static str = "Auth0";
@@ -41,105 +44,50 @@ pub struct Auth0 {
     pub tenant_base_uri: String,
     pub audiences: Vec<String>,
     pub configuration: Auth0Configuration,
+    /// Checks tokens against the tenant's signing keys, fetched from `configuration.jwks_uri`
+    pub verifier: TokenVerifier,
 }
 
 impl Auth0 {
     pub async fn new(tenant_base_uri: &str, audiences: Vec<String>) -> Result<Self, AuthError> {
-        let discovery_endpoint = tenant_base_uri.to_owned() + "/.well-known/openid-configuration";
-        let res = reqwest::get(&discovery_endpoint)
-            .await
-            .map_err(|e| AuthError::Init(AUTHENTICATOR_ID.into(), e.to_string()))?;
-        let configuration: Auth0Configuration = res
-            .json()
-            .await
-            .map_err(|e| AuthError::Init(AUTHENTICATOR_ID.into(), e.to_string()))?;
+        let configuration: Auth0Configuration =
+            oidc::discover(AUTHENTICATOR_ID, tenant_base_uri).await?;
+        let jwks = JwksCache::new(AUTHENTICATOR_ID, &configuration.jwks_uri);
         Ok(Auth0 {
             tenant_base_uri: tenant_base_uri.into(),
             configuration,
             audiences,
+            verifier: TokenVerifier::new(AUTHENTICATOR_ID, jwks),
         })
     }
+
+    /// Accept tokens signed with these algorithms only. RS256 is the default.
+    pub fn with_algorithms(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.verifier = self.verifier.with_algorithms(algorithms);
+        self
+    }
+
+    /// Verify HS256 tokens with the application's client secret
+    pub fn with_client_secret(mut self, secret: &str) -> Self {
+        self.verifier = self.verifier.with_shared_secret(secret.as_bytes());
+        self
+    }
 }
 
-pub struct Auth0UserData {}
+pub struct Auth0UserData {
+    pub principal: Principal,
+}
 
 #[async_trait::async_trait]
 impl Authenticator for Auth0 {
     async fn authenticate(&self, token: &str) -> Result<UserData, AuthError> {
-        let jwks_uri: &str = &self.configuration.jwks_uri;
-        let res = reqwest::get(jwks_uri)
-            .await
-            .map_err(|e| AuthError::FetchJwks(AUTHENTICATOR_ID.into(), e.to_string()))?;
-        let jwks: JwkSet = res
-            .json()
-            .await
-            .map_err(|e| AuthError::FetchJwks(AUTHENTICATOR_ID.into(), e.to_string()))?;
-        let header = jsonwebtoken::decode_header(token)
-            .map_err(|e| AuthError::MalformedToken(AUTHENTICATOR_ID.into(), e.to_string()))?;
-
-        // Get the jwk with the matching kid if it exists
-        //
-        match header.kid {
-            Some(kid) => {
-                use jsonwebtoken::jwk::AlgorithmParameters;
-                match jwks
-                    .keys
-                    .into_iter()
-                    .find(|jwk| match jwk.common.key_id.clone() {
-                        Some(jwk_id) => jwk_id == kid,
-                        None => false,
-                    }) {
-                    Some(jwk) => match jwk.algorithm {
-                        AlgorithmParameters::EllipticCurve(_) => {
-                            Err(AuthError::UnimplementedAlgorithm(
-                                AUTHENTICATOR_ID.into(),
-                                "elliptic curve".into(),
-                            ))?
-                        }
-                        AlgorithmParameters::RSA(rsa) => {
-                            let (n, e) = (rsa.n, rsa.e);
-
-                            let mut validator = Validation::new(header.alg);
-                            validator.set_audience(&self.audiences);
-
-                            let decoded = jsonwebtoken::decode::<Value>(
-                                token,
-                                &DecodingKey::from_rsa_components(&n, &e).map_err(|e| {
-                                    AuthError::InvalidToken(AUTHENTICATOR_ID.into(), e.to_string())
-                                })?,
-                                &validator,
-                            )
-                            .map_err(|e| {
-                                AuthError::InvalidToken(AUTHENTICATOR_ID.into(), e.to_string())
-                            })?;
-
-                            println!("{decoded:#?}");
+        let mut validation = Validation::default();
+        validation.set_issuer(&[&self.configuration.issuer]);
+        validation.set_audience(&self.audiences);
+        let claims = self.verifier.verify(token, &validation).await?;
 
-                            Ok(UserData::Auth0(Auth0UserData {}))
-                        }
-                        AlgorithmParameters::OctetKey(_) => {
-                            Err(AuthError::UnimplementedAlgorithm(
-                                AUTHENTICATOR_ID.into(),
-                                "octet key".into(),
-                            ))?
-                        }
-                        AlgorithmParameters::OctetKeyPair(_) => {
-                            Err(AuthError::UnimplementedAlgorithm(
-                                AUTHENTICATOR_ID.into(),
-                                "octet key pair".into(),
-                            ))?
-                        }
-                    },
-                    None => Err(AuthError::NoMatchingKey(
-                        AUTHENTICATOR_ID.into(),
-                        format!("no jwk with key id {kid} found").into(),
-                    )),
-                }
-            }
-            None => Err(AuthError::InvalidToken(
-                AUTHENTICATOR_ID.into(),
-                "Missing key id (kid) in token header".into(),
-            )),
-        }
+        Ok(UserData::Auth0(Auth0UserData {
+            principal: Principal::from_claims(&claims),
+        }))
     }
 }
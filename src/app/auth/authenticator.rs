@@ -1,12 +1,32 @@
-use super::{auth0::Auth0UserData, errors::AuthError, noop::NoOpUserData};
+use super::{
+    auth0::Auth0UserData, errors::AuthError, first_party::FirstPartyUserData, noop::NoOpUserData,
+    oidc::OidcUserData, principal::Principal,
+};
 
 pub enum UserData {
     Auth0(Auth0UserData),
-    Keycloak,
-    Okta,
+    Keycloak(OidcUserData),
+    Okta(OidcUserData),
+    Oidc(OidcUserData),
+    FirstParty(FirstPartyUserData),
     NoOp(NoOpUserData),
 }
 
+impl UserData {
+    /// Who the token identifies. Providers that don't identify users authenticate everyone as
+    /// the anonymous principal.
+    pub fn principal(self) -> Principal {
+        match self {
+            UserData::Auth0(data) => data.principal,
+            UserData::Keycloak(data) | UserData::Okta(data) | UserData::Oidc(data) => {
+                data.principal
+            }
+            UserData::FirstParty(data) => data.principal,
+            UserData::NoOp(_) => Principal::anonymous(),
+        }
+    }
+}
+
 #[async_trait::async_trait]
 pub trait Authenticator: Send + Sync {
     async fn authenticate(&self, token: &str) -> Result<UserData, AuthError>;
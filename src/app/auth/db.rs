@@ -0,0 +1,143 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::app::types::{AuthProvider, HashAlgorithm};
+
+use super::errors::AccountError;
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct User {
+    pub id: Uuid,
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub username: String,
+    pub image_uri: String,
+    pub auth_provider: AuthProvider,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewUser {
+    pub first_name: String,
+    pub last_name: String,
+    pub email: String,
+    pub username: String,
+    pub image_uri: String,
+}
+
+/// A first party user with their password hash
+#[derive(Debug, Clone, sqlx::FromRow)]
+pub struct Credentials {
+    #[sqlx(flatten)]
+    pub user: User,
+    pub password_hash: String,
+    pub hash_algorithm: HashAlgorithm,
+}
+
+const USER_COLUMNS: &str = "users.id, users.first_name, users.last_name, users.email, \
+     users.username, users.image_uri, users.auth_provider, users.created_at, users.updated_at";
+
+/// Create a first party user along with their password
+pub async fn insert_user(
+    sql: &PgPool,
+    new: &NewUser,
+    password_hash: &str,
+    hash_algorithm: HashAlgorithm,
+) -> Result<User, AccountError> {
+    let mut tx = sql.begin().await?;
+    let query = format!(
+        "insert into users (first_name, last_name, email, username, image_uri, auth_provider) \
+         values ($1, $2, $3, $4, $5, $6) returning {USER_COLUMNS}"
+    );
+    let user: User = sqlx::query_as(&query)
+        .bind(&new.first_name)
+        .bind(&new.last_name)
+        .bind(&new.email)
+        .bind(&new.username)
+        .bind(&new.image_uri)
+        .bind(AuthProvider::DevelopForGood)
+        .fetch_one(&mut *tx)
+        .await?;
+    sqlx::query(
+        "insert into password_credentials (user_id, password_hash, hash_algorithm) \
+         values ($1, $2, $3)",
+    )
+    .bind(user.id)
+    .bind(password_hash)
+    .bind(hash_algorithm)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(user)
+}
+
+/// The credentials of the first party user with an email, matched case insensitively so that
+/// users imported with mixed case emails can log in
+pub async fn find_credentials(
+    sql: &PgPool,
+    email: &str,
+) -> Result<Option<Credentials>, AccountError> {
+    let query = format!(
+        "select {USER_COLUMNS}, password_credentials.password_hash, \
+         password_credentials.hash_algorithm from users \
+         join password_credentials on password_credentials.user_id = users.id \
+         where lower(users.email) = lower($1) and users.auth_provider = $2"
+    );
+    Ok(sqlx::query_as(&query)
+        .bind(email)
+        .bind(AuthProvider::DevelopForGood)
+        .fetch_optional(sql)
+        .await?)
+}
+
+pub async fn update_password_hash(
+    sql: &PgPool,
+    user_id: Uuid,
+    password_hash: &str,
+    hash_algorithm: HashAlgorithm,
+) -> Result<(), AccountError> {
+    sqlx::query(
+        "update password_credentials set password_hash = $2, hash_algorithm = $3 \
+         where user_id = $1",
+    )
+    .bind(user_id)
+    .bind(password_hash)
+    .bind(hash_algorithm)
+    .execute(sql)
+    .await?;
+    Ok(())
+}
+
+/// Revoke a token until it expires. Tokens that have expired since they were revoked are
+/// cleared out along the way.
+pub async fn revoke_token(
+    sql: &PgPool,
+    token_id: Uuid,
+    expires_at: DateTime<Utc>,
+) -> Result<(), AccountError> {
+    sqlx::query("delete from revoked_tokens where expires_at < current_timestamp")
+        .execute(sql)
+        .await?;
+    sqlx::query(
+        "insert into revoked_tokens (token_id, expires_at) values ($1, $2) \
+         on conflict (token_id) do nothing",
+    )
+    .bind(token_id)
+    .bind(expires_at)
+    .execute(sql)
+    .await?;
+    Ok(())
+}
+
+pub async fn is_token_revoked(sql: &PgPool, token_id: Uuid) -> Result<bool, AccountError> {
+    Ok(
+        sqlx::query_scalar("select exists(select 1 from revoked_tokens where token_id = $1)")
+            .bind(token_id)
+            .fetch_one(sql)
+            .await?,
+    )
+}
@@ -1,5 +1,7 @@
 use thiserror::Error;
 
+use crate::app::storage::errors::DbError;
+
 #[derive(Debug, Error)]
 pub enum AuthError {
     #[error("unable to initialize {0} client. error: {1}")]
@@ -12,6 +14,44 @@ pub enum AuthError {
     InvalidToken(String, String),
     #[error("no matching jwk found ({0} client). error: {1}")]
     NoMatchingKey(String, String),
-    #[error("{0} client does not implement {1} algorithm")]
-    UnimplementedAlgorithm(String, String),
+    #[error("{0}")]
+    Unauthenticated(String),
+    #[error("{0} client rejected the credentials. error: {1}")]
+    InvalidCredentials(String, String),
+    #[error("unable to check whether a {0} token was revoked. error: {1}")]
+    RevocationCheck(String, String),
+}
+
+/// Errors of first party accounts
+#[derive(Debug, Error)]
+pub enum AccountError {
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error("{0}")]
+    Conflict(String),
+    /// The email is unknown or the password is wrong. Callers are not told which.
+    #[error("invalid email or password")]
+    InvalidCredentials,
+    #[error("unable to hash password. error: {0}")]
+    Hash(String),
+    #[error("unable to issue token. error: {0}")]
+    Token(String),
+}
+
+impl From<sqlx::Error> for AccountError {
+    fn from(e: sqlx::Error) -> Self {
+        let unique_violation = e
+            .as_database_error()
+            .and_then(|e| e.code())
+            .is_some_and(|code| code == "23505");
+        match unique_violation {
+            true => AccountError::Conflict("an account with this email already exists".into()),
+            false => {
+                log::error!("{}", e.to_string());
+                AccountError::Db(DbError::Query(e.to_string()))
+            }
+        }
+    }
 }
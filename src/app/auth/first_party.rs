@@ -0,0 +1,218 @@
+use std::{fmt, time::Duration};
+
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{
+    authenticator::{Authenticator, UserData},
+    db::{self, NewUser, User},
+    errors::{AccountError, AuthError},
+    password::{self, MAX_PASSWORD_LENGTH, MIN_PASSWORD_LENGTH},
+    principal::Principal,
+};
+
+const AUTHENTICATOR_ID: &str = "DevelopForGood";
+
+pub const DEFAULT_TOKEN_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessToken {
+    pub access_token: String,
+    pub token_type: &'static str,
+    /// Seconds until the token expires
+    pub expires_in: u64,
+}
+
+pub struct FirstPartyUserData {
+    pub principal: Principal,
+}
+
+/// Issues and verifies the tokens of users who registered with a password on this service.
+/// Tokens are HS256 signed with the app secret, name this service as their issuer and audience,
+/// and carry an id so that logging out can revoke them before they expire.
+#[derive(Clone)]
+pub struct FirstPartyAuth {
+    sql: PgPool,
+    issuer: String,
+    ttl: Duration,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+}
+
+impl fmt::Debug for FirstPartyAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FirstPartyAuth")
+            .field("issuer", &self.issuer)
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FirstPartyAuth {
+    pub fn new(sql: PgPool, issuer: &str, secret: &[u8]) -> Self {
+        Self {
+            sql,
+            issuer: issuer.into(),
+            ttl: DEFAULT_TOKEN_TTL,
+            encoding: EncodingKey::from_secret(secret),
+            decoding: DecodingKey::from_secret(secret),
+        }
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    pub fn issue(&self, user: &User) -> Result<AccessToken, AccountError> {
+        let now = Utc::now().timestamp();
+        let claims = json!({
+            "sub": user.id,
+            "email": user.email,
+            "iss": self.issuer,
+            "aud": self.issuer,
+            "iat": now,
+            "exp": now + self.ttl.as_secs() as i64,
+            "jti": Uuid::new_v4(),
+        });
+        let access_token =
+            jsonwebtoken::encode(&Header::new(Algorithm::HS256), &claims, &self.encoding)
+                .map_err(|e| AccountError::Token(e.to_string()))?;
+        Ok(AccessToken {
+            access_token,
+            token_type: "Bearer",
+            expires_in: self.ttl.as_secs(),
+        })
+    }
+
+    /// The claims of a token issued by `issue`, whether or not it has been revoked
+    pub fn verify(&self, token: &str) -> Result<Value, AuthError> {
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.issuer]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        jsonwebtoken::decode::<Value>(token, &self.decoding, &validation)
+            .map(|decoded| decoded.claims)
+            .map_err(|e| AuthError::InvalidToken(AUTHENTICATOR_ID.into(), e.to_string()))
+    }
+}
+
+/// Hashing is slow on purpose, so it is kept off the async workers
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, AccountError> + Send + 'static,
+) -> Result<T, AccountError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| AccountError::Hash(e.to_string()))?
+}
+
+/// Emails are compared case insensitively
+pub fn normalize_email(email: &str) -> Result<String, AccountError> {
+    let email = email.trim().to_lowercase();
+    match email.split_once('@') {
+        Some((local, domain)) if !local.is_empty() && domain.contains('.') => Ok(email),
+        _ => Err(AccountError::InvalidInput(format!(
+            "{email} is not a valid email address"
+        ))),
+    }
+}
+
+pub fn check_password(password: &str) -> Result<(), AccountError> {
+    let length = password.chars().count();
+    if !(MIN_PASSWORD_LENGTH..=MAX_PASSWORD_LENGTH).contains(&length) {
+        return Err(AccountError::InvalidInput(format!(
+            "passwords must be between {MIN_PASSWORD_LENGTH} and {MAX_PASSWORD_LENGTH} \
+             characters long"
+        )));
+    }
+    Ok(())
+}
+
+impl FirstPartyAuth {
+    /// Create a user who logs in with `password`
+    pub async fn register(&self, new: NewUser, password: String) -> Result<User, AccountError> {
+        check_password(&password)?;
+        let (hash, algorithm) = blocking(move || password::hash(&password)).await?;
+        db::insert_user(&self.sql, &new, &hash, algorithm).await
+    }
+
+    /// A token for the user with `email`, if `password` is theirs. Passwords hashed with an
+    /// older algorithm are rehashed with the current one.
+    pub async fn login(&self, email: &str, password: String) -> Result<AccessToken, AccountError> {
+        let Some(credentials) = db::find_credentials(&self.sql, &normalize_email(email)?).await?
+        else {
+            blocking(move || {
+                password::verify_dummy(&password);
+                Ok(())
+            })
+            .await?;
+            return Err(AccountError::InvalidCredentials);
+        };
+
+        let (hash, algorithm) = (credentials.password_hash, credentials.hash_algorithm);
+        let (valid, rehashed) = blocking(move || {
+            if !password::verify(&password, &hash, algorithm)? {
+                return Ok((false, None));
+            }
+            match password::needs_rehash(algorithm) {
+                true => Ok((true, password::hash(&password).ok())),
+                false => Ok((true, None)),
+            }
+        })
+        .await?;
+        if !valid {
+            return Err(AccountError::InvalidCredentials);
+        }
+
+        if let Some((hash, algorithm)) = rehashed {
+            let user_id = credentials.user.id;
+            match db::update_password_hash(&self.sql, user_id, &hash, algorithm).await {
+                Ok(()) => log::info!("rehashed the password of user {user_id} with {algorithm:?}"),
+                Err(e) => log::error!("unable to rehash the password of user {user_id}: {e}"),
+            }
+        }
+        self.issue(&credentials.user)
+    }
+
+    /// Revoke the token that authenticated `principal`
+    pub async fn logout(&self, principal: &Principal) -> Result<(), AccountError> {
+        let (Some(token_id), Some(expires_at)) = (token_id(principal), principal.expires_at) else {
+            return Err(AccountError::InvalidInput(
+                "only tokens issued by this service can be logged out".into(),
+            ));
+        };
+        db::revoke_token(&self.sql, token_id, expires_at).await
+    }
+}
+
+/// The id of a first party token, from its `jti` claim
+pub fn token_id(principal: &Principal) -> Option<Uuid> {
+    principal.token_id.as_deref()?.parse().ok()
+}
+
+#[async_trait::async_trait]
+impl Authenticator for FirstPartyAuth {
+    async fn authenticate(&self, token: &str) -> Result<UserData, AuthError> {
+        let principal = Principal::from_claims(&self.verify(token)?);
+        let Some(token_id) = token_id(&principal) else {
+            return Err(AuthError::InvalidToken(
+                AUTHENTICATOR_ID.into(),
+                "missing or malformed token id (jti)".into(),
+            ));
+        };
+        let revoked = db::is_token_revoked(&self.sql, token_id)
+            .await
+            .map_err(|e| AuthError::RevocationCheck(AUTHENTICATOR_ID.into(), e.to_string()))?;
+        if revoked {
+            return Err(AuthError::InvalidToken(
+                AUTHENTICATOR_ID.into(),
+                "the token has been revoked".into(),
+            ));
+        }
+        Ok(UserData::FirstParty(FirstPartyUserData { principal }))
+    }
+}
@@ -0,0 +1,205 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use jsonwebtoken::{
+    jwk::{JwkSet, PublicKeyUse},
+    Algorithm, DecodingKey,
+};
+use reqwest::{header::CACHE_CONTROL, Client};
+use tokio::sync::{Mutex, RwLock};
+
+use super::errors::AuthError;
+
+/// How long keys are used when the jwks response has no max-age
+pub const DEFAULT_JWKS_TTL: Duration = Duration::from_secs(10 * 60);
+/// Keys are refetched at least this often, however long the server says they can be cached
+pub const MAX_JWKS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Least time between two fetches, so that tokens with made up key ids can't make every request
+/// hit the identity provider
+pub const MIN_JWKS_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A signing key of the set, decoded once when the set is fetched
+#[derive(Clone)]
+pub struct JwkKey {
+    pub key: DecodingKey,
+    /// The algorithm the key is restricted to by its `alg` member
+    pub algorithm: Option<Algorithm>,
+}
+
+struct KeySet {
+    keys: Arc<HashMap<String, JwkKey>>,
+    fetched_at: Instant,
+    expires_at: Instant,
+}
+
+/// The json web key set of an identity provider, fetched when first needed and refreshed as its
+/// Cache-Control header allows, or early when a token is signed with a key id the set doesn't
+/// have. If a refresh fails the keys already fetched keep being used.
+pub struct JwksCache {
+    authenticator: String,
+    uri: String,
+    http: Client,
+    min_refresh_interval: Duration,
+    keys: RwLock<Option<Arc<KeySet>>>,
+    /// Held while fetching, so that concurrent misses share a single fetch
+    refresh: Mutex<()>,
+}
+
+impl fmt::Debug for JwksCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwksCache")
+            .field("authenticator", &self.authenticator)
+            .field("uri", &self.uri)
+            .finish_non_exhaustive()
+    }
+}
+
+impl JwksCache {
+    pub fn new(authenticator: &str, uri: &str) -> Self {
+        Self {
+            authenticator: authenticator.into(),
+            uri: uri.into(),
+            http: Client::new(),
+            min_refresh_interval: MIN_JWKS_REFRESH_INTERVAL,
+            keys: RwLock::default(),
+            refresh: Mutex::default(),
+        }
+    }
+
+    pub fn with_min_refresh_interval(mut self, interval: Duration) -> Self {
+        self.min_refresh_interval = interval;
+        self
+    }
+
+    /// The signing key with a key id
+    pub async fn key(&self, kid: &str) -> Result<JwkKey, AuthError> {
+        let current = self.keys.read().await.clone();
+        if let Some(set) = &current {
+            let now = Instant::now();
+            let found = set.keys.get(kid);
+            let may_refresh = now >= set.fetched_at + self.min_refresh_interval;
+            if now < set.expires_at && (found.is_some() || !may_refresh) {
+                return self.decoding_key(kid, found);
+            }
+        }
+
+        let set = self.refresh(current).await?;
+        self.decoding_key(kid, set.keys.get(kid))
+    }
+
+    fn decoding_key(&self, kid: &str, key: Option<&JwkKey>) -> Result<JwkKey, AuthError> {
+        key.cloned().ok_or_else(|| {
+            AuthError::NoMatchingKey(
+                self.authenticator.clone(),
+                format!("no jwk with key id {kid} found"),
+            )
+        })
+    }
+
+    /// Replace `seen`, the set the caller found wanting, unless another caller already has
+    async fn refresh(&self, seen: Option<Arc<KeySet>>) -> Result<Arc<KeySet>, AuthError> {
+        let _refreshing = self.refresh.lock().await;
+        let current = self.keys.read().await.clone();
+        if let Some(set) = &current {
+            if seen.as_ref().is_none_or(|seen| !Arc::ptr_eq(seen, set)) {
+                return Ok(set.clone());
+            }
+        }
+
+        let set = match self.fetch().await {
+            Ok(set) => set,
+            Err(e) => {
+                let Some(stale) = current else {
+                    return Err(e);
+                };
+                log::warn!("using previously fetched keys, since refreshing them failed: {e}");
+                // retry after the usual interval rather than on every request
+                let now = Instant::now();
+                KeySet {
+                    keys: stale.keys.clone(),
+                    fetched_at: now,
+                    expires_at: now + self.min_refresh_interval,
+                }
+            }
+        };
+        let set = Arc::new(set);
+        *self.keys.write().await = Some(set.clone());
+        Ok(set)
+    }
+
+    async fn fetch(&self) -> Result<KeySet, AuthError> {
+        let fetch_error =
+            |e: reqwest::Error| AuthError::FetchJwks(self.authenticator.clone(), e.to_string());
+        let res = self
+            .http
+            .get(&self.uri)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(fetch_error)?;
+        let ttl = res
+            .headers()
+            .get(CACHE_CONTROL)
+            .and_then(|value| value.to_str().ok())
+            .map_or(DEFAULT_JWKS_TTL, |value| {
+                cache_ttl(value).unwrap_or(DEFAULT_JWKS_TTL)
+            })
+            .clamp(self.min_refresh_interval, MAX_JWKS_TTL);
+        let jwks: JwkSet = res.json().await.map_err(fetch_error)?;
+
+        let keys = jwks
+            .keys
+            .into_iter()
+            .filter_map(|jwk| {
+                let kid = jwk.common.key_id.clone()?;
+                if jwk.common.public_key_use == Some(PublicKeyUse::Encryption) {
+                    return None;
+                }
+                // keys restricted to an algorithm that isn't a signature algorithm are skipped
+                let algorithm = match jwk.common.key_algorithm {
+                    Some(algorithm) => Some(algorithm.to_string().parse().ok()?),
+                    None => None,
+                };
+                match DecodingKey::from_jwk(&jwk) {
+                    Ok(key) => Some((kid, JwkKey { key, algorithm })),
+                    Err(e) => {
+                        log::warn!("skipping jwk {kid}, which is not a valid key: {e}");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        let now = Instant::now();
+        Ok(KeySet {
+            keys: Arc::new(keys),
+            fetched_at: now,
+            expires_at: now + ttl,
+        })
+    }
+}
+
+/// How long a response may be cached according to its Cache-Control header. `no-cache` and
+/// `no-store` allow no caching at all.
+pub fn cache_ttl(cache_control: &str) -> Option<Duration> {
+    cache_control
+        .split(',')
+        .map(str::trim)
+        .find_map(|directive| match directive.split_once('=') {
+            Some((name, seconds)) if name.eq_ignore_ascii_case("max-age") => seconds
+                .trim_matches('"')
+                .parse()
+                .ok()
+                .map(Duration::from_secs),
+            None if directive.eq_ignore_ascii_case("no-cache")
+                || directive.eq_ignore_ascii_case("no-store") =>
+            {
+                Some(Duration::ZERO)
+            }
+            _ => None,
+        })
+}
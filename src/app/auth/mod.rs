@@ -1,7 +1,14 @@
 pub mod auth0;
 pub mod authenticator;
-mod errors;
+pub mod db;
+pub mod errors;
+pub mod first_party;
+pub mod jwks;
 pub mod noop;
+pub mod oidc;
+pub mod password;
+pub mod principal;
+pub mod verifier;
 
 #[cfg(test)]
 mod tests;
@@ -10,31 +17,30 @@ use std::sync::Arc;
 
 use axum::{
     extract::{Request, State},
-    http::{HeaderMap, StatusCode},
+    http::HeaderMap,
     middleware::Next,
-    response::{IntoResponse, Response},
-    Json,
+    response::Response,
 };
 
-use crate::state::AppState;
+use crate::{app::api::errors::ApiError, state::AppState};
 
+/// Authenticates the bearer token of a request and adds its `Principal` to the request's
+/// extensions, where handlers read it with the `AuthUser` extractor
 pub async fn simple_route_guard(
     State(state): State<Arc<AppState>>,
     headers: HeaderMap,
-    req: Request,
+    mut req: Request,
     next: Next,
-) -> Response {
+) -> Result<Response, ApiError> {
     let auth_header = headers.get("Authorization");
 
     let raw_auth_header = match auth_header {
         Some(opaque) => match opaque.to_str() {
             Ok(bearer_token) => bearer_token,
             Err(_) => {
-                return (
-                    StatusCode::BAD_REQUEST,
-                    Json(serde_json::json!({"msg": "missing authorization header"})),
-                )
-                    .into_response()
+                return Err(ApiError::BadRequest(
+                    "malformed authorization header".into(),
+                ))
             }
         },
         None => "",
@@ -49,16 +55,10 @@ pub async fn simple_route_guard(
     let authenticator = &state.services.auth;
     match authenticator.authenticate(token).await {
         Ok(data) => {
+            req.extensions_mut().insert(data.principal());
             let res = next.run(req).await;
-            res
-        }
-        Err(e) => {
-            log::error!("{}", e.to_string());
-            (
-                StatusCode::UNAUTHORIZED,
-                Json(serde_json::json!({"msg": "unauthorized"})),
-            )
-                .into_response()
+            Ok(res)
         }
+        Err(e) => Err(e.into()),
     }
 }
@@ -0,0 +1,165 @@
+use std::time::Duration;
+
+use jsonwebtoken::{Algorithm, Validation};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use super::{
+    authenticator::{Authenticator, UserData},
+    errors::AuthError,
+    jwks::JwksCache,
+    principal::Principal,
+    verifier::TokenVerifier,
+};
+
+/// How far the clocks of the issuer and this service may drift apart by default
+pub const DEFAULT_LEEWAY: Duration = Duration::from_secs(60);
+
+/// Fetch the discovery document of an issuer
+pub async fn discover<T: DeserializeOwned>(
+    authenticator: &str,
+    issuer: &str,
+) -> Result<T, AuthError> {
+    let discovery_endpoint =
+        issuer.trim_end_matches('/').to_owned() + "/.well-known/openid-configuration";
+    let res = reqwest::get(&discovery_endpoint)
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| AuthError::Init(authenticator.into(), e.to_string()))?;
+    res.json()
+        .await
+        .map_err(|e| AuthError::Init(authenticator.into(), e.to_string()))
+}
+
+/// The parts of an OpenID Connect discovery document needed to validate access tokens
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OidcConfiguration {
+    pub issuer: String,
+    pub jwks_uri: String,
+}
+
+/// Identity providers whose tokens carry roles in their own claims
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OidcProvider {
+    /// Realm roles are read from `realm_access.roles`
+    Keycloak,
+    /// Groups are read from `groups`, which needs a groups claim on the authorization server
+    Okta,
+    /// Roles are read from `roles`
+    Generic,
+}
+
+impl OidcProvider {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OidcProvider::Keycloak => "Keycloak",
+            OidcProvider::Okta => "Okta",
+            OidcProvider::Generic => "OIDC",
+        }
+    }
+
+    /// The principal of a token, with the provider's roles
+    pub fn principal(&self, claims: &Value) -> Principal {
+        let roles = match self {
+            OidcProvider::Keycloak => &claims["realm_access"]["roles"],
+            OidcProvider::Okta => &claims["groups"],
+            OidcProvider::Generic => &claims["roles"],
+        };
+        let mut principal = Principal::from_claims(claims);
+        principal.roles = roles
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|role| role.as_str().map(str::to_owned))
+            .collect();
+        principal
+    }
+}
+
+/// Data of users authenticated by an OpenID Connect issuer
+pub struct OidcUserData {
+    pub principal: Principal,
+}
+
+/// Validates access tokens issued by any OpenID Connect issuer. The issuer's signing keys are
+/// found through its discovery document, and tokens must name the issuer and one of the
+/// audiences, and be used between their `nbf` and `exp` give or take the leeway.
+#[derive(Debug, Clone)]
+pub struct OidcAuthenticator {
+    pub provider: OidcProvider,
+    pub audiences: Vec<String>,
+    pub configuration: OidcConfiguration,
+    pub leeway: Duration,
+    pub verifier: TokenVerifier,
+}
+
+impl OidcAuthenticator {
+    pub async fn new(
+        provider: OidcProvider,
+        issuer: &str,
+        audiences: Vec<String>,
+    ) -> Result<Self, AuthError> {
+        let configuration: OidcConfiguration = discover(provider.name(), issuer).await?;
+        // a document served for another issuer could hand out keys that aren't the issuer's
+        if configuration.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+            return Err(AuthError::Init(
+                provider.name().into(),
+                format!(
+                    "discovery document is for issuer {}, not {issuer}",
+                    configuration.issuer
+                ),
+            ));
+        }
+
+        let jwks = JwksCache::new(provider.name(), &configuration.jwks_uri);
+        Ok(Self {
+            provider,
+            audiences,
+            configuration,
+            leeway: DEFAULT_LEEWAY,
+            verifier: TokenVerifier::new(provider.name(), jwks),
+        })
+    }
+
+    /// Accept tokens signed with these algorithms only. RS256 is the default.
+    pub fn with_algorithms(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.verifier = self.verifier.with_algorithms(algorithms);
+        self
+    }
+
+    /// Verify HMAC signed tokens with the client secret
+    pub fn with_client_secret(mut self, secret: &str) -> Self {
+        self.verifier = self.verifier.with_shared_secret(secret.as_bytes());
+        self
+    }
+
+    pub fn with_leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    fn validation(&self) -> Validation {
+        let mut validation = Validation::default();
+        validation.set_issuer(&[&self.configuration.issuer]);
+        validation.set_audience(&self.audiences);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_secs();
+        validation
+    }
+}
+
+#[async_trait::async_trait]
+impl Authenticator for OidcAuthenticator {
+    async fn authenticate(&self, token: &str) -> Result<UserData, AuthError> {
+        let claims = self.verifier.verify(token, &self.validation()).await?;
+        let data = OidcUserData {
+            principal: self.provider.principal(&claims),
+        };
+        Ok(match self.provider {
+            OidcProvider::Keycloak => UserData::Keycloak(data),
+            OidcProvider::Okta => UserData::Okta(data),
+            OidcProvider::Generic => UserData::Oidc(data),
+        })
+    }
+}
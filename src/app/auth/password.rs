@@ -0,0 +1,74 @@
+use std::sync::OnceLock;
+
+use rand::RngCore;
+
+use crate::app::types::HashAlgorithm;
+
+use super::errors::AccountError;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// Longer passwords are refused rather than hashed, so that hashing can't be made arbitrarily slow
+pub const MAX_PASSWORD_LENGTH: usize = 256;
+
+const SALT_LENGTH: usize = 16;
+
+/// Hash a password with argon2id, the algorithm new hashes use
+pub fn hash(password: &str) -> Result<(String, HashAlgorithm), AccountError> {
+    let mut salt = [0u8; SALT_LENGTH];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let hash = argon2::hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+        .map_err(|e| AccountError::Hash(e.to_string()))?;
+    Ok((hash, HashAlgorithm::Argon2))
+}
+
+/// Whether a password matches a hash made with `algorithm`
+pub fn verify(password: &str, hash: &str, algorithm: HashAlgorithm) -> Result<bool, AccountError> {
+    match algorithm {
+        HashAlgorithm::Argon2 => argon2::verify_encoded(hash, password.as_bytes())
+            .map_err(|e| AccountError::Hash(e.to_string())),
+        HashAlgorithm::Bcrypt => {
+            bcrypt::verify(password, hash).map_err(|e| AccountError::Hash(e.to_string()))
+        }
+    }
+}
+
+/// Hashes made with anything but the current algorithm are replaced at the next login
+pub fn needs_rehash(algorithm: HashAlgorithm) -> bool {
+    algorithm != HashAlgorithm::Argon2
+}
+
+/// Check a password against a hash no one has the password of, so that logins for unknown
+/// emails take as long as logins with a wrong password
+pub fn verify_dummy(password: &str) {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    let dummy = DUMMY.get_or_init(|| {
+        let mut password = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut password);
+        hash(&hex::encode(password))
+            .map(|(hash, _)| hash)
+            .unwrap_or_default()
+    });
+    let _ = verify(password, dummy, HashAlgorithm::Argon2);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_hash_and_verify() {
+        let (hash, algorithm) = hash("correct horse battery staple").unwrap();
+        assert_eq!(algorithm, HashAlgorithm::Argon2);
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify("correct horse battery staple", &hash, algorithm).unwrap());
+        assert!(!verify("correct horse battery", &hash, algorithm).unwrap());
+        assert!(!needs_rehash(algorithm));
+
+        let legacy = bcrypt::hash("hunter2hunter2", 4).unwrap();
+        assert!(verify("hunter2hunter2", &legacy, HashAlgorithm::Bcrypt).unwrap());
+        assert!(!verify("hunter3hunter3", &legacy, HashAlgorithm::Bcrypt).unwrap());
+        assert!(needs_rehash(HashAlgorithm::Bcrypt));
+
+        assert!(verify("anything", "not a hash", HashAlgorithm::Argon2).is_err());
+    }
+}
@@ -0,0 +1,106 @@
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use chrono::{DateTime, Utc};
+use serde_json::Value;
+
+use crate::app::api::errors::ApiError;
+
+use super::errors::AuthError;
+
+/// Requests let through without an identified user, e.g. by the no op authenticator, are made by
+/// this subject
+pub const ANONYMOUS_SUBJECT: &str = "anonymous";
+
+/// Who made a request, from the validated claims of its token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    /// The `sub` claim
+    pub subject: String,
+    pub email: Option<String>,
+    /// The `org_id` claim of tokens issued for an organization
+    pub organization: Option<String>,
+    /// The token's `scope` (or `scp`) entries followed by its `permissions`
+    pub scopes: Vec<String>,
+    /// The `exp` claim
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Roles or groups, from the claim the identity provider puts them in
+    pub roles: Vec<String>,
+    /// The `jti` claim
+    pub token_id: Option<String>,
+}
+
+impl Principal {
+    pub fn anonymous() -> Self {
+        Self {
+            subject: ANONYMOUS_SUBJECT.into(),
+            email: None,
+            organization: None,
+            scopes: vec![],
+            expires_at: None,
+            roles: vec![],
+            token_id: None,
+        }
+    }
+
+    pub fn from_claims(claims: &Value) -> Self {
+        let string = |name: &str| claims[name].as_str().map(str::to_owned);
+        let strings = |value: &Value| -> Vec<String> {
+            match value {
+                Value::String(s) => s.split_whitespace().map(str::to_owned).collect(),
+                Value::Array(values) => values
+                    .iter()
+                    .filter_map(|value| value.as_str().map(str::to_owned))
+                    .collect(),
+                _ => vec![],
+            }
+        };
+
+        let mut scopes = strings(&claims["scope"]);
+        scopes.extend(strings(&claims["scp"]));
+        for permission in strings(&claims["permissions"]) {
+            if !scopes.contains(&permission) {
+                scopes.push(permission);
+            }
+        }
+
+        Self {
+            subject: string("sub").unwrap_or_default(),
+            email: string("email"),
+            organization: string("org_id"),
+            scopes,
+            expires_at: claims["exp"]
+                .as_i64()
+                .and_then(|exp| DateTime::from_timestamp(exp, 0)),
+            roles: vec![],
+            token_id: string("jti"),
+        }
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|r| r == role)
+    }
+}
+
+/// The principal of a request that passed the route guard. Rejects requests to routes without
+/// the guard as unauthenticated.
+#[derive(Debug, Clone)]
+pub struct AuthUser(pub Principal);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Principal>()
+            .cloned()
+            .map(AuthUser)
+            .ok_or_else(|| {
+                AuthError::Unauthenticated("the request was not authenticated".into()).into()
+            })
+    }
+}
@@ -1,8 +1,108 @@
-use std::env;
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
-use crate::app::{auth::authenticator::Authenticator, util::test_util};
+use axum::{
+    extract::{FromRequestParts, State},
+    http::{Request, StatusCode},
+    routing, Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::DateTime;
+use jsonwebtoken::{Algorithm, EncodingKey, Header, Validation};
+use ring::{
+    rand::SystemRandom,
+    signature::{self, EcdsaKeyPair, Ed25519KeyPair, KeyPair},
+};
+use serde_json::{json, Value};
 
-use super::auth0::Auth0;
+use crate::app::{
+    auth::authenticator::{Authenticator, UserData},
+    util::test_util,
+};
+
+use super::{
+    auth0::Auth0,
+    db::User,
+    errors::AuthError,
+    first_party::{self, FirstPartyAuth},
+    jwks::{cache_ttl, JwksCache},
+    oidc::{OidcAuthenticator, OidcProvider},
+    principal::{AuthUser, Principal},
+    verifier::TokenVerifier,
+};
+
+/// The modulus of an rsa public key. Keys are only decoded, never used, by the jwks cache tests.
+const RSA_MODULUS: &str = "sXchDaQebHnPiGvyDOAT4saGEUetSyo9MKLOoWFsueri23bOdgWp4Dy1WlUzewbgBHod5pcM9H95GQRV3JDXboIRROSBigeC5yjU1hGzHHyXss8UDprecbAYxknTcQkhslANGRUZmdTOQ5qTRsLAt6BTYuyvVRdhS8exSZEy_c4gs_7svlJJQ4H9_NxsiIoLwAEk7-Q3UXERGYw_75IDrGA84-lA_-Ct4eTlXHBIY2EaV7t7LjJaynVJCpkv4LKjTTAumiGUIuQhrNhZLuF_RJLqHpM2kgWFLU7-VTdL1VbC2tejvcI2BlMkEpk1BzBZI0KQB0GaDWFLN-aEAw3vRw";
+
+/// What the stand-in jwks endpoint answers, and how often it was asked. Discovery documents are
+/// served for the `melody` realm, the way keycloak serves them.
+#[derive(Default)]
+struct JwksServer {
+    base_uri: String,
+    response: Mutex<(StatusCode, String, Vec<Value>)>,
+    hits: AtomicUsize,
+}
+
+impl JwksServer {
+    fn serve(&self, status: StatusCode, cache_control: &str, keys: Vec<Value>) {
+        *self.response.lock().unwrap() = (status, cache_control.into(), keys);
+    }
+
+    fn hits(&self) -> usize {
+        self.hits.load(Ordering::SeqCst)
+    }
+}
+
+fn rsa_jwk(kid: &str) -> Value {
+    json!({"kty": "RSA", "kid": kid, "use": "sig", "alg": "RS256", "n": RSA_MODULUS, "e": "AQAB"})
+}
+
+async fn jwks(
+    State(server): State<Arc<JwksServer>>,
+) -> (StatusCode, [(&'static str, String); 1], String) {
+    server.hits.fetch_add(1, Ordering::SeqCst);
+    let (status, cache_control, keys) = server.response.lock().unwrap().clone();
+    (
+        status,
+        [("cache-control", cache_control)],
+        json!({ "keys": keys }).to_string(),
+    )
+}
+
+async fn discovery(State(server): State<Arc<JwksServer>>) -> axum::Json<Value> {
+    axum::Json(json!({
+        "issuer": format!("{}/realms/melody", server.base_uri),
+        "jwks_uri": format!("{}/.well-known/jwks.json", server.base_uri),
+    }))
+}
+
+async fn jwks_server() -> (Arc<JwksServer>, String) {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr: SocketAddr = listener.local_addr().unwrap();
+    let server = Arc::new(JwksServer {
+        base_uri: format!("http://{addr}"),
+        ..Default::default()
+    });
+    let router = Router::new()
+        .route("/.well-known/jwks.json", routing::get(jwks))
+        .route(
+            "/realms/:realm/.well-known/openid-configuration",
+            routing::get(discovery),
+        )
+        .with_state(server.clone());
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    (
+        server.clone(),
+        format!("{}/.well-known/jwks.json", server.base_uri),
+    )
+}
 
 #[tokio::test]
 pub async fn test_build_auth0_provider() {
@@ -33,3 +133,354 @@ pub async fn test_auth0_authenticate() {
 
     auth0.authenticate("").await.unwrap();
 }
+
+#[tokio::test]
+pub async fn test_jwks_cache() {
+    let (server, uri) = jwks_server().await;
+    let interval = Duration::from_millis(200);
+    let jwks = JwksCache::new("test", &uri).with_min_refresh_interval(interval);
+
+    server.serve(StatusCode::INTERNAL_SERVER_ERROR, "", vec![]);
+    assert!(matches!(jwks.key("a").await, Err(AuthError::FetchJwks(..))));
+
+    // cached for max-age
+    server.serve(StatusCode::OK, "public, max-age=60", vec![rsa_jwk("a")]);
+    jwks.key("a").await.expect("key a should be served");
+    jwks.key("a").await.expect("key a should be cached");
+    assert_eq!(server.hits(), 2);
+
+    // unknown key ids refetch at most once per interval
+    server.serve(
+        StatusCode::OK,
+        "public, max-age=60",
+        vec![rsa_jwk("a"), rsa_jwk("b")],
+    );
+    assert!(matches!(
+        jwks.key("b").await,
+        Err(AuthError::NoMatchingKey(..))
+    ));
+    assert_eq!(server.hits(), 2);
+    tokio::time::sleep(interval).await;
+    jwks.key("b")
+        .await
+        .expect("key b should be fetched on a miss");
+    assert_eq!(server.hits(), 3);
+
+    // stale keys are served while the endpoint fails
+    server.serve(StatusCode::SERVICE_UNAVAILABLE, "", vec![]);
+    tokio::time::sleep(interval).await;
+    assert!(matches!(
+        jwks.key("c").await,
+        Err(AuthError::NoMatchingKey(..))
+    ));
+    jwks.key("a").await.expect("stale key a should be served");
+    assert_eq!(server.hits(), 4);
+
+    // keys expire with no-cache, once the interval has passed
+    server.serve(StatusCode::OK, "no-cache", vec![rsa_jwk("c")]);
+    tokio::time::sleep(interval).await;
+    jwks.key("c").await.expect("key c should be fetched");
+    assert!(matches!(
+        jwks.key("a").await,
+        Err(AuthError::NoMatchingKey(..))
+    ));
+    assert_eq!(server.hits(), 5);
+    tokio::time::sleep(interval).await;
+    jwks.key("c").await.expect("key c should be refetched");
+    assert_eq!(server.hits(), 6);
+
+    assert_eq!(
+        cache_ttl("public, max-age=3600, must-revalidate"),
+        Some(Duration::from_secs(3600))
+    );
+    assert_eq!(cache_ttl("no-store"), Some(Duration::ZERO));
+    assert_eq!(cache_ttl("public"), None);
+}
+
+#[tokio::test]
+pub async fn test_auth_user() {
+    let claims = json!({
+        "sub": "auth0|123",
+        "email": "jenny@example.com",
+        "org_id": "org_abc",
+        "scope": "openid read:assets",
+        "permissions": ["read:assets", "write:assets"],
+        "exp": 1_700_000_000,
+    });
+    let principal = Principal::from_claims(&claims);
+    assert_eq!(
+        principal,
+        Principal {
+            subject: "auth0|123".into(),
+            email: Some("jenny@example.com".into()),
+            organization: Some("org_abc".into()),
+            scopes: vec!["openid".into(), "read:assets".into(), "write:assets".into()],
+            expires_at: DateTime::from_timestamp(1_700_000_000, 0),
+            roles: vec![],
+            token_id: None,
+        }
+    );
+    assert!(principal.has_scope("write:assets"));
+    assert!(!principal.has_scope("admin"));
+
+    let okta = Principal::from_claims(&json!({"sub": "00u1", "scp": ["openid", "email"]}));
+    assert_eq!(okta.scopes, vec!["openid", "email"]);
+    assert_eq!(okta.email, None);
+
+    let (mut parts, _) = Request::new(()).into_parts();
+    let rejected = AuthUser::from_request_parts(&mut parts, &()).await;
+    assert_eq!(
+        rejected.unwrap_err().status_code(),
+        StatusCode::UNAUTHORIZED
+    );
+
+    parts.extensions.insert(principal.clone());
+    let AuthUser(user) = AuthUser::from_request_parts(&mut parts, &()).await.unwrap();
+    assert_eq!(user, principal);
+}
+
+/// A freshly generated signing key, and its public jwk
+fn signing_key(alg: Algorithm, kid: &str) -> (EncodingKey, Value) {
+    let rng = SystemRandom::new();
+    let b64 = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+    match alg {
+        Algorithm::ES256 | Algorithm::ES384 => {
+            let (signing, crv) = match alg {
+                Algorithm::ES256 => (&signature::ECDSA_P256_SHA256_FIXED_SIGNING, "P-256"),
+                _ => (&signature::ECDSA_P384_SHA384_FIXED_SIGNING, "P-384"),
+            };
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(signing, &rng).unwrap();
+            let pair = EcdsaKeyPair::from_pkcs8(signing, pkcs8.as_ref(), &rng).unwrap();
+            // an uncompressed point: 0x04, x, then y
+            let (x, y) =
+                pair.public_key().as_ref()[1..].split_at(if crv == "P-256" { 32 } else { 48 });
+            let jwk = json!({"kty": "EC", "crv": crv, "kid": kid, "alg": format!("{alg:?}"), "x": b64(x), "y": b64(y)});
+            (EncodingKey::from_ec_der(pkcs8.as_ref()), jwk)
+        }
+        Algorithm::EdDSA => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
+            let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+            let jwk = json!({"kty": "OKP", "crv": "Ed25519", "kid": kid, "x": b64(pair.public_key().as_ref())});
+            (EncodingKey::from_ed_der(pkcs8.as_ref()), jwk)
+        }
+        _ => unreachable!("no test keys for {alg:?}"),
+    }
+}
+
+fn sign(alg: Algorithm, kid: Option<&str>, key: &EncodingKey, aud: &str) -> String {
+    let mut header = Header::new(alg);
+    header.kid = kid.map(str::to_owned);
+    let claims = json!({"sub": format!("{alg:?}-user"), "aud": aud, "exp": chrono::Utc::now().timestamp() + 60});
+    jsonwebtoken::encode(&header, &claims, key).unwrap()
+}
+
+#[tokio::test]
+pub async fn test_token_verifier() {
+    let (server, uri) = jwks_server().await;
+    let (es256, es256_jwk) = signing_key(Algorithm::ES256, "es256");
+    let (es384, es384_jwk) = signing_key(Algorithm::ES384, "es384");
+    let (eddsa, eddsa_jwk) = signing_key(Algorithm::EdDSA, "eddsa");
+    let hs256 = EncodingKey::from_secret(b"client-secret");
+    server.serve(
+        StatusCode::OK,
+        "max-age=60",
+        vec![es256_jwk, es384_jwk, eddsa_jwk, rsa_jwk("rs256")],
+    );
+
+    let verifier = TokenVerifier::new("test", JwksCache::new("test", &uri))
+        .with_algorithms(vec![
+            Algorithm::ES256,
+            Algorithm::ES384,
+            Algorithm::EdDSA,
+            Algorithm::HS256,
+        ])
+        .with_shared_secret(b"client-secret");
+    let mut validation = Validation::default();
+    validation.set_audience(&["melody"]);
+
+    let tokens = [
+        (
+            Algorithm::ES256,
+            sign(Algorithm::ES256, Some("es256"), &es256, "melody"),
+        ),
+        (
+            Algorithm::ES384,
+            sign(Algorithm::ES384, Some("es384"), &es384, "melody"),
+        ),
+        (
+            Algorithm::EdDSA,
+            sign(Algorithm::EdDSA, Some("eddsa"), &eddsa, "melody"),
+        ),
+        (
+            Algorithm::HS256,
+            sign(Algorithm::HS256, None, &hs256, "melody"),
+        ),
+    ];
+    for (alg, token) in &tokens {
+        let claims = verifier
+            .verify(token, &validation)
+            .await
+            .unwrap_or_else(|e| panic!("{alg:?}: {e}"));
+        assert_eq!(claims["sub"], format!("{alg:?}-user"));
+    }
+
+    let rejected = [
+        // signed for another audience
+        sign(Algorithm::ES256, Some("es256"), &es256, "other"),
+        // signed with another key than the one named
+        sign(
+            Algorithm::ES256,
+            Some("es256"),
+            &signing_key(Algorithm::ES256, "x").0,
+            "melody",
+        ),
+        // a key restricted to ES256 can't verify ES384 tokens
+        sign(Algorithm::ES384, Some("es256"), &es384, "melody"),
+        // HS256 signed with a guessable secret, naming an rsa key
+        sign(
+            Algorithm::HS256,
+            Some("rs256"),
+            &EncodingKey::from_secret(RSA_MODULUS.as_bytes()),
+            "melody",
+        ),
+    ];
+    for token in &rejected {
+        assert!(matches!(
+            verifier.verify(token, &validation).await,
+            Err(AuthError::InvalidToken(..))
+        ));
+    }
+
+    // algorithms outside the allowlist are rejected before any key is looked up
+    let es256_only = TokenVerifier::new("test", JwksCache::new("test", &uri))
+        .with_algorithms(vec![Algorithm::ES256]);
+    assert!(es256_only.verify(&tokens[0].1, &validation).await.is_ok());
+    for (_, token) in &tokens[1..] {
+        assert!(matches!(
+            es256_only.verify(token, &validation).await,
+            Err(AuthError::InvalidToken(..))
+        ));
+    }
+
+    // HS256 needs a shared secret even when it is allowed
+    let no_secret = TokenVerifier::new("test", JwksCache::new("test", &uri))
+        .with_algorithms(vec![Algorithm::HS256]);
+    assert!(matches!(
+        no_secret.verify(&tokens[3].1, &validation).await,
+        Err(AuthError::NoMatchingKey(..))
+    ));
+}
+
+#[tokio::test]
+pub async fn test_oidc_authenticator() {
+    let (server, _) = jwks_server().await;
+    let (key, jwk) = signing_key(Algorithm::ES256, "kc");
+    server.serve(StatusCode::OK, "max-age=60", vec![jwk]);
+
+    let issuer = format!("{}/realms/melody", server.base_uri);
+    let keycloak = OidcAuthenticator::new(OidcProvider::Keycloak, &issuer, vec!["account".into()])
+        .await
+        .expect("error discovering the issuer")
+        .with_algorithms(vec![Algorithm::ES256])
+        .with_leeway(Duration::from_secs(5));
+
+    let now = chrono::Utc::now().timestamp();
+    let claims = json!({
+        "sub": "f3a1",
+        "iss": issuer,
+        "aud": ["account", "melody"],
+        "exp": now + 60,
+        "nbf": now - 1,
+        "email": "jenny@example.com",
+        "realm_access": {"roles": ["admin", "offline_access"]},
+    });
+    let authenticate = |overrides: Value| {
+        let mut claims = claims.clone();
+        claims
+            .as_object_mut()
+            .unwrap()
+            .extend(overrides.as_object().unwrap().clone());
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("kc".into());
+        let token = jsonwebtoken::encode(&header, &claims, &key).unwrap();
+        let keycloak = &keycloak;
+        async move { keycloak.authenticate(&token).await.map(UserData::principal) }
+    };
+
+    let principal = authenticate(json!({})).await.expect("valid token rejected");
+    assert_eq!(principal.subject, "f3a1");
+    assert_eq!(principal.email.as_deref(), Some("jenny@example.com"));
+    assert!(principal.has_role("admin"));
+    // within the leeway
+    assert!(authenticate(json!({"exp": now - 2})).await.is_ok());
+
+    for overrides in [
+        json!({"iss": "https://attacker.example.com/realms/melody"}),
+        json!({"aud": "other"}),
+        json!({"nbf": now + 60}),
+        json!({"exp": now - 30}),
+    ] {
+        assert!(
+            matches!(
+                authenticate(overrides.clone()).await,
+                Err(AuthError::InvalidToken(..))
+            ),
+            "accepted a token with {overrides}"
+        );
+    }
+
+    let okta =
+        OidcProvider::Okta.principal(&json!({"sub": "00u1", "groups": ["Everyone", "Admins"]}));
+    assert_eq!(okta.roles, vec!["Everyone", "Admins"]);
+
+    // the discovery document must be for the configured issuer
+    let other = format!("{}/realms/other", server.base_uri);
+    assert!(matches!(
+        OidcAuthenticator::new(OidcProvider::Generic, &other, vec![]).await,
+        Err(AuthError::Init(..))
+    ));
+}
+
+#[tokio::test]
+pub async fn test_first_party_tokens() {
+    let sql = sqlx::postgres::PgPoolOptions::new()
+        .connect_lazy("postgres://localhost/melody")
+        .unwrap();
+    let auth = FirstPartyAuth::new(sql.clone(), "melody", b"app-secret");
+    let now = chrono::Utc::now();
+    let user = User {
+        id: uuid::Uuid::new_v4(),
+        first_name: "Jenny".into(),
+        last_name: "Cho".into(),
+        email: "jenny@example.com".into(),
+        username: "jenny".into(),
+        image_uri: String::new(),
+        auth_provider: crate::app::types::AuthProvider::DevelopForGood,
+        created_at: now,
+        updated_at: now,
+    };
+
+    let token = auth.issue(&user).unwrap();
+    assert_eq!(token.token_type, "Bearer");
+    assert_eq!(token.expires_in, 3600);
+    let principal = Principal::from_claims(&auth.verify(&token.access_token).unwrap());
+    assert_eq!(principal.subject, user.id.to_string());
+    assert_eq!(principal.email.as_deref(), Some("jenny@example.com"));
+    assert!(first_party::token_id(&principal).is_some());
+    assert!(principal.expires_at.is_some_and(|exp| exp > now));
+
+    // tokens of other secrets or other services are rejected
+    let other_secret = FirstPartyAuth::new(sql.clone(), "melody", b"other-secret");
+    assert!(other_secret.verify(&token.access_token).is_err());
+    let other_service = FirstPartyAuth::new(sql, "other", b"app-secret");
+    assert!(other_service.verify(&token.access_token).is_err());
+
+    assert_eq!(
+        first_party::normalize_email(" Jenny@Example.com ").unwrap(),
+        "jenny@example.com"
+    );
+    assert!(first_party::normalize_email("jenny").is_err());
+    assert!(first_party::normalize_email("@example.com").is_err());
+    assert!(first_party::check_password("hunter2").is_err());
+    assert!(first_party::check_password("correct horse battery staple").is_ok());
+}
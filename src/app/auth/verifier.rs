@@ -0,0 +1,102 @@
+use std::{fmt, sync::Arc};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+
+use super::{errors::AuthError, jwks::JwksCache};
+
+/// Verifies the signature of tokens issued by an identity provider and returns their claims.
+///
+/// Only the configured algorithms are accepted, whatever a token's header asks for, so that a
+/// token can't e.g. be signed with HS256 using the provider's public key as the secret. HMAC
+/// algorithms are verified with the shared secret, the others with the provider's jwks.
+#[derive(Clone)]
+pub struct TokenVerifier {
+    authenticator: String,
+    jwks: Arc<JwksCache>,
+    algorithms: Vec<Algorithm>,
+    shared_secret: Option<DecodingKey>,
+}
+
+impl fmt::Debug for TokenVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TokenVerifier")
+            .field("authenticator", &self.authenticator)
+            .field("jwks", &self.jwks)
+            .field("algorithms", &self.algorithms)
+            .field("shared_secret", &self.shared_secret.is_some())
+            .finish()
+    }
+}
+
+impl TokenVerifier {
+    /// A verifier that accepts RS256 tokens only
+    pub fn new(authenticator: &str, jwks: JwksCache) -> Self {
+        Self {
+            authenticator: authenticator.into(),
+            jwks: Arc::new(jwks),
+            algorithms: vec![Algorithm::RS256],
+            shared_secret: None,
+        }
+    }
+
+    pub fn with_algorithms(mut self, algorithms: Vec<Algorithm>) -> Self {
+        self.algorithms = algorithms;
+        self
+    }
+
+    /// The secret HMAC signed tokens are verified with
+    pub fn with_shared_secret(mut self, secret: &[u8]) -> Self {
+        self.shared_secret = Some(DecodingKey::from_secret(secret));
+        self
+    }
+
+    /// The claims of a token with a valid signature. `validation` holds the claim checks; its
+    /// algorithms are replaced with the token's, once that is known to be allowed.
+    pub async fn verify(&self, token: &str, validation: &Validation) -> Result<Value, AuthError> {
+        let header = jsonwebtoken::decode_header(token)
+            .map_err(|e| AuthError::MalformedToken(self.authenticator.clone(), e.to_string()))?;
+        if !self.algorithms.contains(&header.alg) {
+            return Err(AuthError::InvalidToken(
+                self.authenticator.clone(),
+                format!("{:?} signed tokens are not accepted", header.alg),
+            ));
+        }
+
+        let key = match header.alg {
+            Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+                self.shared_secret.clone().ok_or_else(|| {
+                    AuthError::NoMatchingKey(
+                        self.authenticator.clone(),
+                        "no shared secret is configured".into(),
+                    )
+                })?
+            }
+            _ => {
+                let Some(kid) = header.kid else {
+                    return Err(AuthError::InvalidToken(
+                        self.authenticator.clone(),
+                        "Missing key id (kid) in token header".into(),
+                    ));
+                };
+                let jwk = self.jwks.key(&kid).await?;
+                if jwk
+                    .algorithm
+                    .is_some_and(|algorithm| algorithm != header.alg)
+                {
+                    return Err(AuthError::InvalidToken(
+                        self.authenticator.clone(),
+                        format!("key {kid} can't verify {:?} signed tokens", header.alg),
+                    ));
+                }
+                jwk.key
+            }
+        };
+
+        let mut validation = validation.clone();
+        validation.algorithms = vec![header.alg];
+        jsonwebtoken::decode::<Value>(token, &key, &validation)
+            .map(|decoded| decoded.claims)
+            .map_err(|e| AuthError::InvalidToken(self.authenticator.clone(), e.to_string()))
+    }
+}
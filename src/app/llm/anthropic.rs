@@ -0,0 +1,734 @@
+use futures::{future, StreamExt};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::app::{
+    openai::{
+        chat::{
+            ChatChunkChoice, ChatCompletion, ChatCompletionChunk, ChatDelta, ChatMessage,
+            ChatOptions, ChatResponseChoice, ChatResponseMessage, ChatRole, FunctionCallDelta,
+            ToolCall, ToolCallDelta, ToolType,
+        },
+        errors::OpenAIError,
+        stream::{self, ChatCompletionStream, SseEvent},
+        usage::Usage,
+    },
+    util,
+};
+
+use super::{provider::ChatProvider, retry::RetryPolicy};
+
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+
+#[derive(Clone)]
+pub struct AnthropicClient {
+    pub api_key: String,
+    pub base_uri: String,
+    pub client: Client,
+    pub retry: RetryPolicy,
+}
+
+impl AnthropicClient {
+    pub fn new(api_key: &str, base_uri: &str) -> Self {
+        AnthropicClient {
+            api_key: api_key.to_owned(),
+            base_uri: base_uri.to_owned(),
+            client: Client::new(),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    async fn send(&self, body: &MessagesRequest) -> Result<reqwest::Response, OpenAIError> {
+        let uri = self.base_uri.clone() + "/messages";
+        let req = self
+            .client
+            .post(&uri)
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", ANTHROPIC_VERSION)
+            .json(body);
+        OpenAIError::check(self.retry.send(req).await?).await
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum MessageRole {
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Serialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct Message {
+    role: MessageRole,
+    content: Vec<RequestBlock>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct Tool {
+    name: String,
+    description: Option<String>,
+    input_schema: Value,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize, PartialEq)]
+struct ToolChoice {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: Option<String>,
+    disable_parallel_tool_use: Option<bool>,
+}
+
+impl ToolChoice {
+    /// Map a chat completions `tool_choice` onto the messages api equivalent
+    fn new(tool_choice: Option<&Value>, parallel_tool_calls: Option<bool>) -> Option<Self> {
+        let (kind, name) = match tool_choice {
+            Some(Value::String(choice)) if choice == "none" => ("none", None),
+            Some(Value::String(choice)) if choice == "required" => ("any", None),
+            Some(Value::Object(choice)) => match choice
+                .get("function")
+                .and_then(|function| function.get("name"))
+                .and_then(Value::as_str)
+            {
+                Some(name) => ("tool", Some(name.to_owned())),
+                None => ("auto", None),
+            },
+            None if parallel_tool_calls.is_none() => return None,
+            _ => ("auto", None),
+        };
+        Some(ToolChoice {
+            kind,
+            name,
+            disable_parallel_tool_use: parallel_tool_calls.map(|parallel| !parallel),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Metadata {
+    user_id: String,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct MessagesRequest {
+    model: String,
+    max_tokens: u64,
+    system: Option<String>,
+    messages: Vec<Message>,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    stop_sequences: Option<Vec<String>>,
+    stream: Option<bool>,
+    metadata: Option<Metadata>,
+    tools: Option<Vec<Tool>>,
+    tool_choice: Option<ToolChoice>,
+}
+
+/// Translate a message into content blocks. Tool calls become `tool_use` blocks and tool messages
+/// become `tool_result` blocks, which the messages api expects in a user turn.
+fn content_blocks(message: &ChatMessage) -> Vec<RequestBlock> {
+    let text = message.content.clone().unwrap_or_default();
+    if let (ChatRole::Tool, Some(tool_use_id)) = (&message.role, &message.tool_call_id) {
+        return vec![RequestBlock::ToolResult {
+            tool_use_id: tool_use_id.to_owned(),
+            content: text,
+        }];
+    }
+
+    let mut blocks = vec![];
+    if !text.is_empty() {
+        blocks.push(RequestBlock::Text { text });
+    }
+    for call in message.tool_calls.iter().flatten() {
+        blocks.push(RequestBlock::ToolUse {
+            id: call.id.clone(),
+            name: call.function.name.clone(),
+            input: serde_json::from_str(&call.function.arguments)
+                .unwrap_or_else(|_| Value::Object(Default::default())),
+        });
+    }
+    blocks
+}
+
+impl From<&ChatOptions> for MessagesRequest {
+    /// System messages are lifted into the top level `system` prompt, and consecutive messages
+    /// from the same role are merged since the messages api requires roles to alternate.
+    fn from(opts: &ChatOptions) -> Self {
+        let mut system: Vec<&str> = vec![];
+        let mut messages: Vec<Message> = vec![];
+
+        for message in &opts.messages {
+            let role = match message.role {
+                ChatRole::System => {
+                    system.extend(message.content.as_deref());
+                    continue;
+                }
+                ChatRole::Assistant => MessageRole::Assistant,
+                ChatRole::User | ChatRole::Function | ChatRole::Tool => MessageRole::User,
+            };
+            let blocks = content_blocks(message);
+            match messages.last_mut() {
+                Some(last) if last.role == role => {
+                    for block in blocks {
+                        match (last.content.last_mut(), block) {
+                            (
+                                Some(RequestBlock::Text { text }),
+                                RequestBlock::Text { text: next },
+                            ) => {
+                                text.push_str("\n\n");
+                                text.push_str(&next);
+                            }
+                            (_, block) => last.content.push(block),
+                        }
+                    }
+                }
+                _ => messages.push(Message {
+                    role,
+                    content: blocks,
+                }),
+            }
+        }
+
+        let tools = opts.tools.as_ref().map(|tools| {
+            tools
+                .iter()
+                .map(|tool| Tool {
+                    name: tool.function.name.clone(),
+                    description: tool.function.description.clone(),
+                    input_schema: tool.function.parameters.clone(),
+                })
+                .collect()
+        });
+
+        MessagesRequest {
+            model: opts.model.clone(),
+            max_tokens: opts.max_tokens,
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages,
+            temperature: opts.temperature,
+            top_p: opts.top_p,
+            stop_sequences: opts.stop.as_ref().map(|stop| stop.to_vec()),
+            stream: opts.stream,
+            metadata: opts.user.as_ref().map(|user| Metadata {
+                user_id: user.to_owned(),
+            }),
+            tool_choice: tools
+                .as_ref()
+                .and_then(|_| ToolChoice::new(opts.tool_choice.as_ref(), opts.parallel_tool_calls)),
+            tools,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct MessagesUsage {
+    #[serde(default)]
+    input_tokens: u64,
+    #[serde(default)]
+    output_tokens: u64,
+}
+
+impl From<MessagesUsage> for Usage {
+    fn from(usage: MessagesUsage) -> Self {
+        Usage {
+            prompt_tokens: usage.input_tokens,
+            completion_tokens: Some(usage.output_tokens),
+            total_tokens: usage.input_tokens + usage.output_tokens,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: Value,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesResponse {
+    id: String,
+    model: String,
+    content: Vec<ContentBlock>,
+    stop_reason: Option<String>,
+    usage: MessagesUsage,
+}
+
+/// Map a messages api stop reason onto the equivalent chat completions finish reason
+fn finish_reason(stop_reason: &str) -> String {
+    match stop_reason {
+        "end_turn" | "stop_sequence" => "stop".into(),
+        "max_tokens" => "length".into(),
+        "tool_use" => "tool_calls".into(),
+        other => other.into(),
+    }
+}
+
+impl From<MessagesResponse> for ChatCompletion {
+    fn from(res: MessagesResponse) -> Self {
+        let mut content = String::new();
+        let mut tool_calls = vec![];
+        for block in res.content {
+            match block {
+                ContentBlock::Text { text } => content.push_str(&text),
+                ContentBlock::ToolUse { id, name, input } => {
+                    tool_calls.push(ToolCall::function(&id, &name, &input.to_string()))
+                }
+                ContentBlock::Other => {}
+            }
+        }
+
+        ChatCompletion {
+            id: res.id,
+            object: "chat.completion".into(),
+            created: util::time::now() as u64,
+            model: res.model,
+            choices: vec![ChatResponseChoice {
+                index: 0,
+                message: ChatResponseMessage {
+                    role: "assistant".into(),
+                    content: (!content.is_empty() || tool_calls.is_empty()).then_some(content),
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                },
+                finish_reason: finish_reason(res.stop_reason.as_deref().unwrap_or("end_turn")),
+                content_filter_results: None,
+            }],
+            usage: res.usage.into(),
+            prompt_filter_results: None,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StreamMessage {
+    id: String,
+    model: String,
+    #[serde(default)]
+    usage: MessagesUsage,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamDelta {
+    TextDelta {
+        text: String,
+    },
+    InputJsonDelta {
+        partial_json: String,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Debug, Deserialize)]
+struct MessageDelta {
+    stop_reason: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum StreamEvent {
+    MessageStart {
+        message: StreamMessage,
+    },
+    ContentBlockStart {
+        content_block: ContentBlock,
+    },
+    ContentBlockDelta {
+        delta: StreamDelta,
+    },
+    MessageDelta {
+        delta: MessageDelta,
+        #[serde(default)]
+        usage: MessagesUsage,
+    },
+    MessageStop,
+    Error,
+    #[serde(other)]
+    Other,
+}
+
+/// State carried across the events of a stream, since only `message_start` names the message
+#[derive(Default)]
+struct StreamState {
+    id: String,
+    model: String,
+    created: u64,
+    prompt_tokens: u64,
+    /// Tool calls started so far. Chat completions number tool calls rather than content blocks.
+    tool_calls: usize,
+}
+
+impl StreamState {
+    fn chunk(&self, delta: ChatDelta, finish_reason: Option<String>) -> ChatCompletionChunk {
+        ChatCompletionChunk {
+            id: self.id.clone(),
+            object: "chat.completion.chunk".into(),
+            created: self.created,
+            model: self.model.clone(),
+            choices: vec![ChatChunkChoice {
+                index: 0,
+                delta,
+                finish_reason,
+                content_filter_results: None,
+            }],
+            usage: None,
+            prompt_filter_results: None,
+        }
+    }
+
+    /// A delta for the tool call currently being streamed
+    fn tool_call_delta(&self, call: ToolCallDelta) -> ChatDelta {
+        ChatDelta {
+            tool_calls: Some(vec![ToolCallDelta {
+                index: self.tool_calls.saturating_sub(1),
+                ..call
+            }]),
+            ..Default::default()
+        }
+    }
+
+    /// Translate a single event, returning `None` for events that carry nothing to relay
+    fn translate(&mut self, event: SseEvent) -> Option<Result<ChatCompletionChunk, OpenAIError>> {
+        let data = event.data;
+        let event: StreamEvent = match serde_json::from_str(&data) {
+            Ok(event) => event,
+            Err(e) => {
+                log::error!("{}", e.to_string());
+                return Some(Err(OpenAIError::Serialize(e.to_string())));
+            }
+        };
+
+        match event {
+            StreamEvent::MessageStart { message } => {
+                self.id = message.id;
+                self.model = message.model;
+                self.created = util::time::now() as u64;
+                self.prompt_tokens = message.usage.input_tokens;
+                let delta = ChatDelta {
+                    role: Some("assistant".into()),
+                    ..Default::default()
+                };
+                Some(Ok(self.chunk(delta, None)))
+            }
+            StreamEvent::ContentBlockStart {
+                content_block: ContentBlock::ToolUse { id, name, .. },
+            } => {
+                self.tool_calls += 1;
+                let delta = self.tool_call_delta(ToolCallDelta {
+                    id: Some(id),
+                    kind: Some(ToolType::Function),
+                    function: Some(FunctionCallDelta {
+                        name: Some(name),
+                        arguments: Some(String::new()),
+                    }),
+                    ..Default::default()
+                });
+                Some(Ok(self.chunk(delta, None)))
+            }
+            StreamEvent::ContentBlockDelta {
+                delta: StreamDelta::TextDelta { text },
+            } => {
+                let delta = ChatDelta {
+                    content: Some(text),
+                    ..Default::default()
+                };
+                Some(Ok(self.chunk(delta, None)))
+            }
+            StreamEvent::ContentBlockDelta {
+                delta: StreamDelta::InputJsonDelta { partial_json },
+            } => {
+                let delta = self.tool_call_delta(ToolCallDelta {
+                    function: Some(FunctionCallDelta {
+                        name: None,
+                        arguments: Some(partial_json),
+                    }),
+                    ..Default::default()
+                });
+                Some(Ok(self.chunk(delta, None)))
+            }
+            StreamEvent::MessageDelta { delta, usage } => {
+                let reason = delta.stop_reason.as_deref().map(finish_reason);
+                let mut chunk = self.chunk(ChatDelta::default(), reason);
+                chunk.usage = Some(Usage::from(MessagesUsage {
+                    input_tokens: self.prompt_tokens,
+                    output_tokens: usage.output_tokens,
+                }));
+                Some(Ok(chunk))
+            }
+            StreamEvent::Error => {
+                let err = OpenAIError::from_body(None, &data);
+                log::error!("{}", err.to_string());
+                Some(Err(err))
+            }
+            StreamEvent::ContentBlockStart { .. }
+            | StreamEvent::ContentBlockDelta { .. }
+            | StreamEvent::MessageStop
+            | StreamEvent::Other => None,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl ChatProvider for AnthropicClient {
+    async fn get_chat_completion(&self, opts: &ChatOptions) -> Result<ChatCompletion, OpenAIError> {
+        let mut body = MessagesRequest::from(opts);
+        body.stream = None;
+
+        let res: MessagesResponse = self.send(&body).await?.json().await.map_err(|e| {
+            log::error!("{}", e.to_string());
+            OpenAIError::Serialize(e.to_string())
+        })?;
+        Ok(res.into())
+    }
+
+    async fn stream_chat_completion(
+        &self,
+        opts: &ChatOptions,
+    ) -> Result<ChatCompletionStream, OpenAIError> {
+        let mut body = MessagesRequest::from(opts);
+        body.stream = Some(true);
+
+        let res = self.send(&body).await?;
+        let mut state = StreamState::default();
+        let chunks = stream::sse_events(res.bytes_stream())
+            .take_while(|event| {
+                future::ready(!matches!(event, Ok(e) if e.event.as_deref() == Some("message_stop")))
+            })
+            .filter_map(move |event| {
+                future::ready(match event {
+                    Ok(event) => state.translate(event),
+                    Err(e) => Some(Err(e)),
+                })
+            });
+        Ok(chunks.boxed())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::openai::chat::{ChatFunction, ChatTool};
+
+    #[test]
+    pub fn test_translate_request() {
+        let opts = ChatOptions::default(
+            "claude-3-haiku-20240307",
+            vec![
+                ChatMessage::new(ChatRole::System, "you are a helpful assistant"),
+                ChatMessage::new(ChatRole::User, "hello"),
+                ChatMessage::new(ChatRole::User, "are you there?"),
+                ChatMessage::new(ChatRole::Assistant, "yes"),
+                ChatMessage::new(ChatRole::System, "answer briefly"),
+            ],
+            20,
+        );
+
+        let req = MessagesRequest::from(&opts);
+        assert_eq!(
+            req.system.as_deref(),
+            Some("you are a helpful assistant\n\nanswer briefly")
+        );
+        assert_eq!(req.messages.len(), 2);
+        assert_eq!(req.messages[0].role, MessageRole::User);
+        assert_eq!(
+            req.messages[0].content,
+            vec![RequestBlock::Text {
+                text: "hello\n\nare you there?".into()
+            }]
+        );
+        assert_eq!(req.messages[1].role, MessageRole::Assistant);
+    }
+
+    #[test]
+    pub fn test_translate_response() {
+        let raw = r#"{
+            "id": "msg_01",
+            "type": "message",
+            "role": "assistant",
+            "model": "claude-3-haiku-20240307",
+            "content": [{"type": "text", "text": "scar tissue"}],
+            "stop_reason": "max_tokens",
+            "stop_sequence": null,
+            "usage": {"input_tokens": 12, "output_tokens": 3}
+        }"#;
+
+        let completion: ChatCompletion = serde_json::from_str::<MessagesResponse>(raw)
+            .unwrap()
+            .into();
+        assert_eq!(
+            completion.choices[0].message.content.as_deref(),
+            Some("scar tissue")
+        );
+        assert_eq!(completion.choices[0].finish_reason, "length");
+        assert_eq!(completion.usage.prompt_tokens, 12);
+        assert_eq!(completion.usage.total_tokens, 15);
+    }
+
+    #[test]
+    pub fn test_translate_stream_events() {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_01","model":"claude","usage":{"input_tokens":7,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"hi"}}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":2}}"#,
+        ];
+
+        let mut state = StreamState::default();
+        let chunks: Vec<ChatCompletionChunk> = events
+            .iter()
+            .filter_map(|data| {
+                state.translate(SseEvent {
+                    event: None,
+                    data: data.to_string(),
+                })
+            })
+            .map(|chunk| chunk.unwrap())
+            .collect();
+
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks[1].id, "msg_01");
+        assert_eq!(chunks[1].choices[0].delta.content.as_deref(), Some("hi"));
+        assert_eq!(chunks[2].choices[0].finish_reason.as_deref(), Some("stop"));
+        assert_eq!(chunks[2].usage.as_ref().map(|u| u.total_tokens), Some(9));
+    }
+
+    #[test]
+    pub fn test_translate_tool_use() {
+        let opts = ChatOptions {
+            tools: Some(vec![ChatTool::function(ChatFunction {
+                name: "get_weather".into(),
+                description: Some("current weather for a city".into()),
+                parameters: serde_json::json!({"type": "object"}),
+            })]),
+            tool_choice: Some("required".into()),
+            parallel_tool_calls: Some(false),
+            ..ChatOptions::default(
+                "claude-3-haiku-20240307",
+                vec![
+                    ChatMessage::new(ChatRole::User, "weather in paris?"),
+                    ChatMessage {
+                        tool_calls: Some(vec![ToolCall::function(
+                            "toolu_01",
+                            "get_weather",
+                            r#"{"city":"Paris"}"#,
+                        )]),
+                        content: None,
+                        ..ChatMessage::new(ChatRole::Assistant, "")
+                    },
+                    ChatMessage::tool_result("toolu_01", "sunny"),
+                ],
+                20,
+            )
+        };
+
+        let req = MessagesRequest::from(&opts);
+        assert_eq!(req.tools.as_ref().map(Vec::len), Some(1));
+        assert_eq!(
+            req.tool_choice,
+            Some(ToolChoice {
+                kind: "any",
+                name: None,
+                disable_parallel_tool_use: Some(true),
+            })
+        );
+        assert_eq!(
+            req.messages[1].content,
+            vec![RequestBlock::ToolUse {
+                id: "toolu_01".into(),
+                name: "get_weather".into(),
+                input: serde_json::json!({"city": "Paris"}),
+            }]
+        );
+        assert_eq!(req.messages[2].role, MessageRole::User);
+
+        let raw = r#"{
+            "id": "msg_02",
+            "model": "claude-3-haiku-20240307",
+            "content": [{"type": "tool_use", "id": "toolu_02", "name": "get_weather", "input": {"city": "Lyon"}}],
+            "stop_reason": "tool_use",
+            "usage": {"input_tokens": 30, "output_tokens": 10}
+        }"#;
+        let completion: ChatCompletion = serde_json::from_str::<MessagesResponse>(raw)
+            .unwrap()
+            .into();
+        let message = &completion.choices[0].message;
+        assert!(message.content.is_none());
+        assert_eq!(
+            message.tool_calls,
+            Some(vec![ToolCall::function(
+                "toolu_02",
+                "get_weather",
+                r#"{"city":"Lyon"}"#
+            )])
+        );
+        assert_eq!(completion.choices[0].finish_reason, "tool_calls");
+    }
+
+    #[test]
+    pub fn test_translate_tool_use_stream() {
+        let events = [
+            r#"{"type":"message_start","message":{"id":"msg_01","model":"claude","usage":{"input_tokens":7,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"checking"}}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_01","name":"get_weather","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"city\":"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"Paris\"}"}}"#,
+        ];
+
+        let mut state = StreamState::default();
+        let mut calls = vec![];
+        for data in events {
+            let event = SseEvent {
+                event: None,
+                data: data.to_string(),
+            };
+            if let Some(chunk) = state.translate(event) {
+                for delta in chunk.unwrap().choices[0].delta.tool_calls.iter().flatten() {
+                    delta.apply(&mut calls).unwrap();
+                }
+            }
+        }
+        assert_eq!(
+            calls,
+            vec![ToolCall::function(
+                "toolu_01",
+                "get_weather",
+                r#"{"city":"Paris"}"#
+            )]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::app::{
+    openai::{
+        chat::{ChatCompletion, ChatOptions},
+        errors::OpenAIError,
+    },
+    storage::cache::Cache,
+};
+
+use super::{
+    provider::ChatProvider,
+    semantic_cache::{Lookup, SemanticCache},
+};
+
+/// Bump to invalidate every cached completion when the cached format changes
+const KEY_PREFIX: &str = "chat:completion:v1";
+
+/// Longest a caller may ask for a completion to be cached
+pub const MAX_TTL: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How a request wants the cache used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CachePolicy {
+    pub ttl: Duration,
+    /// Neither read nor write the cache
+    pub bypass: bool,
+    /// Cache the request even if it is not deterministic
+    pub force: bool,
+}
+
+/// Whether a completion came from the cache, reported to clients in the `x-cache` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheStatus {
+    Hit,
+    /// A completion for a similar prompt was reused
+    SemanticHit,
+    Miss,
+    /// The request was not eligible for caching, or asked not to be cached
+    Bypass,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::SemanticHit => "SEMANTIC_HIT",
+            CacheStatus::Miss => "MISS",
+            CacheStatus::Bypass => "BYPASS",
+        }
+    }
+}
+
+/// Requests that sample at temperature 0 for a single choice. Anything else is expected to give
+/// a different answer each time. OpenAI samples at temperature 1 when none is given.
+pub fn is_deterministic(opts: &ChatOptions) -> bool {
+    opts.temperature.is_some_and(|t| t <= 0.0) && opts.n.unwrap_or(1) <= 1
+}
+
+/// A hash of a request's options in a canonical form: object keys are sorted, missing options are
+/// left out and options that don't affect the completion, like streaming and the end user id, are
+/// ignored.
+pub fn options_hash(opts: &ChatOptions) -> String {
+    let mut canonical = serde_json::to_value(opts).unwrap_or(Value::Null);
+    if let Some(fields) = canonical.as_object_mut() {
+        for field in ["stream", "stream_options", "user"] {
+            fields.remove(field);
+        }
+    }
+    hex::encode(Sha256::digest(canonical.to_string().as_bytes()))
+}
+
+/// The cache key of a request. Requests that may also be answered from the semantic cache are
+/// keyed by its namespace too, so that no completion is shared across namespaces.
+pub fn cache_key(opts: &ChatOptions, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) => {
+            let namespace = hex::encode(Sha256::digest(namespace.as_bytes()));
+            format!("{KEY_PREFIX}:{}:{}", &namespace[..32], options_hash(opts))
+        }
+        None => format!("{KEY_PREFIX}:{}", options_hash(opts)),
+    }
+}
+
+/// Get a chat completion, answering from the cache when the request allows it. Exact matches are
+/// tried first, then similar prompts when a semantic cache is given. Completions of similar
+/// prompts are only returned, never cached as exact matches. Concurrent identical requests wait
+/// for a single provider call. Cache failures are logged and the request goes to the provider, so
+/// an unavailable cache never fails a request.
+pub async fn get_chat_completion(
+    provider: &dyn ChatProvider,
+    cache: &Cache,
+    semantic: Option<&SemanticCache<'_>>,
+    opts: &ChatOptions,
+    policy: CachePolicy,
+) -> Result<(ChatCompletion, CacheStatus), OpenAIError> {
+    if policy.bypass || !(policy.force || is_deterministic(opts)) {
+        let completion = provider.get_chat_completion(opts).await?;
+        return Ok((completion, CacheStatus::Bypass));
+    }
+
+    let key = cache_key(opts, semantic.map(SemanticCache::namespace));
+    let mut probe = None;
+    if let Some(semantic) = semantic {
+        match cache.get(&key).await {
+            Ok(Some(completion)) => return Ok((completion, CacheStatus::Hit)),
+            Ok(None) => {}
+            Err(e) => log::error!("unable to read {key} from the cache: {e}"),
+        }
+        match semantic.lookup(opts).await {
+            Ok(Some(Lookup::Hit {
+                completion,
+                similarity,
+            })) => {
+                log::info!("reusing chat completion for a prompt with similarity {similarity}");
+                return Ok((completion, CacheStatus::SemanticHit));
+            }
+            Ok(Some(Lookup::Miss(miss))) => probe = Some(miss),
+            Ok(None) => {}
+            Err(e) => log::error!("unable to search the semantic cache: {e}"),
+        }
+    }
+
+    let mut status = CacheStatus::Hit;
+    let completion = cache
+        .get_or_compute(&key, policy.ttl.min(MAX_TTL), || {
+            let status = &mut status;
+            async move {
+                let completion = provider.get_chat_completion(opts).await?;
+                if let (Some(semantic), Some(probe)) = (semantic, &probe) {
+                    if let Err(e) = semantic.store(probe, &completion).await {
+                        log::error!("unable to add chat completion to the semantic cache: {e}");
+                    }
+                }
+                *status = CacheStatus::Miss;
+                Ok(completion)
+            }
+        })
+        .await?;
+    Ok((completion, status))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::app::openai::chat::{ChatMessage, ChatRole};
+
+    use super::*;
+
+    fn options() -> ChatOptions {
+        ChatOptions {
+            temperature: Some(0.0),
+            ..ChatOptions::default(
+                "gpt-4",
+                vec![ChatMessage::new(ChatRole::User, "What is 2 + 2?")],
+                64,
+            )
+        }
+    }
+
+    #[test]
+    pub fn test_is_deterministic() {
+        assert!(is_deterministic(&options()));
+        assert!(!is_deterministic(&ChatOptions {
+            temperature: Some(0.7),
+            ..options()
+        }));
+        assert!(!is_deterministic(&ChatOptions {
+            temperature: None,
+            ..options()
+        }));
+        assert!(!is_deterministic(&ChatOptions {
+            n: Some(3),
+            ..options()
+        }));
+    }
+
+    #[test]
+    pub fn test_cache_key() {
+        let key = cache_key(&options(), None);
+        assert!(key.starts_with("chat:completion:v1:"));
+
+        // streaming and the end user don't change the key
+        let streamed = ChatOptions {
+            stream: Some(true),
+            user: Some("batch-tool".into()),
+            ..options()
+        };
+        assert_eq!(cache_key(&streamed, None), key);
+
+        // map ordering doesn't change the key
+        let bias = |pairs: &[(&str, i8)]| {
+            let mut map = HashMap::new();
+            for (token, bias) in pairs {
+                map.insert(token.to_string(), *bias);
+            }
+            Some(map)
+        };
+        let forward = ChatOptions {
+            logit_bias: bias(&[("1", 1), ("2", -1), ("3", 5), ("4", 2)]),
+            ..options()
+        };
+        let backward = ChatOptions {
+            logit_bias: bias(&[("4", 2), ("3", 5), ("2", -1), ("1", 1)]),
+            ..options()
+        };
+        assert_eq!(cache_key(&forward, None), cache_key(&backward, None));
+        assert_ne!(cache_key(&forward, None), key);
+
+        let other_model = ChatOptions {
+            model: "gpt-4o".into(),
+            ..options()
+        };
+        assert_ne!(cache_key(&other_model, None), key);
+
+        // namespaced keys are not shared between namespaces
+        let org_a = cache_key(&options(), Some("org:a"));
+        assert_ne!(org_a, key);
+        assert_ne!(org_a, cache_key(&options(), Some("org:b")));
+        assert_eq!(org_a, cache_key(&options(), Some("org:a")));
+    }
+}
@@ -0,0 +1,500 @@
+use futures::StreamExt;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::app::{
+    openai::{
+        chat::{
+            ChatChunkChoice, ChatCompletion, ChatCompletionChunk, ChatDelta, ChatOptions,
+            ChatResponseChoice, ChatResponseMessage, ChatRole, ChatTool, FunctionCallDelta,
+            ToolCall, ToolCallDelta, ToolType,
+        },
+        errors::OpenAIError,
+        stream::{self, ChatCompletionStream},
+        usage::Usage,
+        OpenAIClient,
+    },
+    util,
+};
+
+use super::{provider::ChatProvider, retry::RetryPolicy};
+
+/// The kind of local inference server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalServer {
+    Ollama,
+    LlamaCpp,
+}
+
+/// Options applied to every request. llama.cpp fixes the context size and keeps its model loaded
+/// for the lifetime of the server, so these only apply to ollama.
+#[derive(Debug, Clone, Default)]
+pub struct LocalOptions {
+    /// Context window size in tokens
+    pub num_ctx: Option<u64>,
+    /// How long the model stays loaded after a request (e.g. "5m", "-1")
+    pub keep_alive: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct LocalClient {
+    pub server: LocalServer,
+    pub base_uri: String,
+    pub client: Client,
+    pub options: LocalOptions,
+    /// Models served by the server, discovered on creation
+    pub models: Vec<String>,
+    /// Model used when a request names one the server does not have
+    pub default_model: Option<String>,
+    pub retry: RetryPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaModel {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaTags {
+    models: Vec<OllamaModel>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModel {
+    id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct OpenAIModels {
+    data: Vec<OpenAIModel>,
+}
+
+impl LocalClient {
+    /// Create a client and discover the models the server has available.
+    ///
+    /// * `base_uri`: The root of the server (e.g. http://localhost:11434), without `/v1`
+    /// * `default_model`: Model to use for unknown model names. Defaults to the first model found.
+    pub async fn new(
+        server: LocalServer,
+        base_uri: &str,
+        options: LocalOptions,
+        default_model: Option<String>,
+    ) -> Result<Self, OpenAIError> {
+        if server == LocalServer::LlamaCpp
+            && (options.num_ctx.is_some() || options.keep_alive.is_some())
+        {
+            log::warn!("llama.cpp does not support per request num_ctx or keep_alive, ignoring");
+        }
+
+        let mut client = LocalClient {
+            server,
+            base_uri: base_uri.trim_end_matches('/').to_owned(),
+            client: Client::new(),
+            options,
+            models: vec![],
+            default_model,
+            retry: RetryPolicy::default(),
+        };
+        client.models = client.list_models().await?;
+        if client.default_model.is_none() {
+            client.default_model = client.models.first().cloned();
+        }
+        Ok(client)
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Query the server's model list endpoint
+    pub async fn list_models(&self) -> Result<Vec<String>, OpenAIError> {
+        let res = match self.server {
+            LocalServer::Ollama => self.client.get(self.base_uri.clone() + "/api/tags"),
+            LocalServer::LlamaCpp => self.client.get(self.base_uri.clone() + "/v1/models"),
+        }
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| {
+            log::error!("{}", e.to_string());
+            OpenAIError::ListModels(e.to_string())
+        })?;
+
+        let models = match self.server {
+            LocalServer::Ollama => res
+                .json::<OllamaTags>()
+                .await
+                .map(|tags| tags.models.into_iter().map(|m| m.name).collect()),
+            LocalServer::LlamaCpp => res
+                .json::<OpenAIModels>()
+                .await
+                .map(|models| models.data.into_iter().map(|m| m.id).collect()),
+        };
+        models.map_err(|e| {
+            log::error!("{}", e.to_string());
+            OpenAIError::Serialize(e.to_string())
+        })
+    }
+
+    /// The model to request for `model`. Clients are usually written against hosted model names,
+    /// which a local server will not have.
+    fn resolve_model(&self, model: &str) -> String {
+        if self.models.iter().any(|m| m == model) {
+            return model.to_owned();
+        }
+        match &self.default_model {
+            Some(default) => {
+                log::debug!("model {model} is not available locally, using {default}");
+                default.to_owned()
+            }
+            None => model.to_owned(),
+        }
+    }
+
+    fn openai_compatible(&self) -> OpenAIClient {
+        OpenAIClient::with_client("", &(self.base_uri.clone() + "/v1"), self.client.clone())
+            .with_retry_policy(self.retry.clone())
+    }
+
+    async fn send_ollama(
+        &self,
+        opts: &ChatOptions,
+        stream: bool,
+    ) -> Result<reqwest::Response, OpenAIError> {
+        let body =
+            OllamaChatRequest::new(opts, self.resolve_model(&opts.model), stream, &self.options);
+        let req = self
+            .client
+            .post(self.base_uri.clone() + "/api/chat")
+            .json(&body);
+        OpenAIError::check(self.retry.send(req).await?).await
+    }
+}
+
+/// Ollama takes tool call arguments as an object rather than a JSON encoded string, and does not
+/// name its tool calls
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaFunctionCall {
+    name: String,
+    arguments: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct OllamaToolCall {
+    function: OllamaFunctionCall,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct OllamaMessage<'a> {
+    role: &'a str,
+    content: &'a str,
+    tool_calls: Option<Vec<OllamaToolCall>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct OllamaRequestOptions {
+    num_ctx: Option<u64>,
+    num_predict: u64,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    stop: Option<Vec<String>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+struct OllamaChatRequest<'a> {
+    model: String,
+    messages: Vec<OllamaMessage<'a>>,
+    stream: bool,
+    options: OllamaRequestOptions,
+    keep_alive: Option<String>,
+    tools: Option<&'a Vec<ChatTool>>,
+}
+
+impl<'a> OllamaChatRequest<'a> {
+    fn new(opts: &'a ChatOptions, model: String, stream: bool, local: &LocalOptions) -> Self {
+        let messages = opts
+            .messages
+            .iter()
+            .map(|message| OllamaMessage {
+                role: match message.role {
+                    ChatRole::System => "system",
+                    ChatRole::User => "user",
+                    ChatRole::Assistant => "assistant",
+                    ChatRole::Function | ChatRole::Tool => "tool",
+                },
+                content: message.content.as_deref().unwrap_or_default(),
+                tool_calls: message.tool_calls.as_ref().map(|calls| {
+                    calls
+                        .iter()
+                        .map(|call| OllamaToolCall {
+                            function: OllamaFunctionCall {
+                                name: call.function.name.clone(),
+                                arguments: serde_json::from_str(&call.function.arguments)
+                                    .unwrap_or_else(|_| Value::Object(Default::default())),
+                            },
+                        })
+                        .collect()
+                }),
+            })
+            .collect();
+
+        OllamaChatRequest {
+            model,
+            messages,
+            stream,
+            options: OllamaRequestOptions {
+                num_ctx: local.num_ctx,
+                num_predict: opts.max_tokens,
+                temperature: opts.temperature,
+                top_p: opts.top_p,
+                stop: opts.stop.as_ref().map(|stop| stop.to_vec()),
+            },
+            keep_alive: local.keep_alive.clone(),
+            tools: opts.tools.as_ref(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct OllamaResponseMessage {
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    tool_calls: Vec<OllamaToolCall>,
+}
+
+impl OllamaResponseMessage {
+    fn tool_calls(&self) -> Vec<ToolCall> {
+        self.tool_calls
+            .iter()
+            .map(|call| {
+                let id = "call_".to_owned() + &util::rng::random_alphanumeric_string(24);
+                let arguments = call.function.arguments.to_string();
+                ToolCall::function(&id, &call.function.name, &arguments)
+            })
+            .collect()
+    }
+}
+
+/// A response (or streamed line) from ollama's `/api/chat`. Token counts are only present on the
+/// final message, and `prompt_eval_count` is left out when the prompt was cached.
+#[derive(Debug, Deserialize)]
+struct OllamaChatResponse {
+    model: String,
+    #[serde(default)]
+    message: OllamaResponseMessage,
+    done: bool,
+    done_reason: Option<String>,
+    prompt_eval_count: Option<u64>,
+    eval_count: Option<u64>,
+}
+
+impl OllamaChatResponse {
+    fn usage(&self) -> Usage {
+        let prompt_tokens = self.prompt_eval_count.unwrap_or(0);
+        let completion_tokens = self.eval_count.unwrap_or(0);
+        Usage {
+            prompt_tokens,
+            completion_tokens: Some(completion_tokens),
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    /// Ollama reports `stop` when the model calls tools, so the reason is corrected from the
+    /// message itself
+    fn finish_reason(&self, tool_calls: bool) -> Option<String> {
+        match tool_calls {
+            true => Some("tool_calls".into()),
+            false => self
+                .done
+                .then(|| self.done_reason.clone().unwrap_or("stop".into())),
+        }
+    }
+
+    fn into_completion(self, id: String) -> ChatCompletion {
+        let usage = self.usage();
+        let tool_calls = self.message.tool_calls();
+        let finish_reason = self
+            .finish_reason(!tool_calls.is_empty())
+            .unwrap_or("stop".into());
+        ChatCompletion {
+            id,
+            object: "chat.completion".into(),
+            created: util::time::now() as u64,
+            model: self.model,
+            choices: vec![ChatResponseChoice {
+                index: 0,
+                message: ChatResponseMessage {
+                    role: "assistant".into(),
+                    content: Some(self.message.content)
+                        .filter(|c| !c.is_empty() || tool_calls.is_empty()),
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                },
+                finish_reason,
+                content_filter_results: None,
+            }],
+            usage,
+            prompt_filter_results: None,
+        }
+    }
+
+    fn into_chunk(self, id: String, created: u64) -> ChatCompletionChunk {
+        let usage = self.done.then(|| self.usage());
+        // ollama sends each tool call whole, in a single line
+        let tool_calls: Vec<ToolCallDelta> = self
+            .message
+            .tool_calls()
+            .into_iter()
+            .enumerate()
+            .map(|(index, call)| ToolCallDelta {
+                index,
+                id: Some(call.id),
+                kind: Some(ToolType::Function),
+                function: Some(FunctionCallDelta {
+                    name: Some(call.function.name),
+                    arguments: Some(call.function.arguments),
+                }),
+            })
+            .collect();
+        let finish_reason = self.finish_reason(!tool_calls.is_empty());
+        ChatCompletionChunk {
+            id,
+            object: "chat.completion.chunk".into(),
+            created,
+            model: self.model,
+            choices: vec![ChatChunkChoice {
+                index: 0,
+                delta: ChatDelta {
+                    role: None,
+                    content: Some(self.message.content).filter(|c| !c.is_empty()),
+                    tool_calls: (!tool_calls.is_empty()).then_some(tool_calls),
+                },
+                finish_reason,
+                content_filter_results: None,
+            }],
+            usage,
+            prompt_filter_results: None,
+        }
+    }
+}
+
+fn completion_id() -> String {
+    "chatcmpl-".to_owned() + &util::rng::random_alphanumeric_string(24)
+}
+
+#[async_trait::async_trait]
+impl ChatProvider for LocalClient {
+    async fn get_chat_completion(&self, opts: &ChatOptions) -> Result<ChatCompletion, OpenAIError> {
+        match self.server {
+            LocalServer::Ollama => {
+                let res: OllamaChatResponse = self
+                    .send_ollama(opts, false)
+                    .await?
+                    .json()
+                    .await
+                    .map_err(|e| {
+                        log::error!("{}", e.to_string());
+                        OpenAIError::Serialize(e.to_string())
+                    })?;
+                Ok(res.into_completion(completion_id()))
+            }
+            LocalServer::LlamaCpp => {
+                let mut opts = opts.clone();
+                opts.model = self.resolve_model(&opts.model);
+                self.openai_compatible().get_chat_completion(&opts).await
+            }
+        }
+    }
+
+    async fn stream_chat_completion(
+        &self,
+        opts: &ChatOptions,
+    ) -> Result<ChatCompletionStream, OpenAIError> {
+        match self.server {
+            LocalServer::Ollama => {
+                let res = self.send_ollama(opts, true).await?;
+                let (id, created) = (completion_id(), util::time::now() as u64);
+                let chunks = stream::json_lines::<OllamaChatResponse, _, _, _>(res.bytes_stream())
+                    .map(move |line| line.map(|line| line.into_chunk(id.clone(), created)));
+                Ok(chunks.boxed())
+            }
+            LocalServer::LlamaCpp => {
+                let mut opts = opts.clone();
+                opts.model = self.resolve_model(&opts.model);
+                self.openai_compatible().stream_chat_completion(&opts).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app::openai::chat::ChatMessage;
+
+    #[test]
+    pub fn test_ollama_request() {
+        let opts = ChatOptions::default(
+            "gpt-3.5-turbo",
+            vec![ChatMessage::new(ChatRole::User, "hello")],
+            32,
+        );
+        let local = LocalOptions {
+            num_ctx: Some(8192),
+            keep_alive: Some("10m".into()),
+        };
+
+        let body = serde_json::to_value(OllamaChatRequest::new(
+            &opts,
+            "llama3".into(),
+            false,
+            &local,
+        ))
+        .unwrap();
+        assert_eq!(body["model"], "llama3");
+        assert_eq!(body["options"]["num_ctx"], 8192);
+        assert_eq!(body["options"]["num_predict"], 32);
+        assert_eq!(body["keep_alive"], "10m");
+        assert_eq!(body["messages"][0]["role"], "user");
+    }
+
+    #[test]
+    pub fn test_ollama_response_without_prompt_count() {
+        let raw = r#"{"model":"llama3","created_at":"2024-02-17T00:00:00Z","message":{"role":"assistant","content":"hi"},"done":true,"eval_count":2}"#;
+
+        let completion = serde_json::from_str::<OllamaChatResponse>(raw)
+            .unwrap()
+            .into_completion("chatcmpl-1".into());
+        assert_eq!(completion.choices[0].message.content.as_deref(), Some("hi"));
+        assert_eq!(completion.usage.prompt_tokens, 0);
+        assert_eq!(completion.usage.total_tokens, 2);
+    }
+
+    #[test]
+    pub fn test_ollama_tool_calls() {
+        let raw = r#"{"model":"llama3.1","message":{"role":"assistant","content":"","tool_calls":[{"function":{"name":"get_weather","arguments":{"city":"Paris"}}}]},"done":true,"done_reason":"stop"}"#;
+
+        let completion = serde_json::from_str::<OllamaChatResponse>(raw)
+            .unwrap()
+            .into_completion("chatcmpl-1".into());
+        let calls = completion.choices[0].message.tool_calls.as_ref().unwrap();
+        assert_eq!(calls[0].function.name, "get_weather");
+        assert_eq!(calls[0].function.arguments, r#"{"city":"Paris"}"#);
+        assert_eq!(completion.choices[0].finish_reason, "tool_calls");
+    }
+
+    #[test]
+    pub fn test_usage_tolerance() {
+        let raw = r#"{"id":"c","object":"chat.completion","created":1,"model":"m","choices":[{"index":0,"message":{"role":"assistant","content":"hi"},"finish_reason":"stop"}],"usage":{"completion_tokens":1}}"#;
+        let completion: ChatCompletion = serde_json::from_str(raw).unwrap();
+        assert_eq!(completion.usage.completion_tokens, Some(1));
+
+        let raw = r#"{"id":"c","object":"chat.completion","created":1,"model":"m","choices":[]}"#;
+        assert!(serde_json::from_str::<ChatCompletion>(raw).is_ok());
+    }
+}
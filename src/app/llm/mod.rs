@@ -0,0 +1,6 @@
+pub mod anthropic;
+pub mod cache;
+pub mod local;
+pub mod provider;
+pub mod retry;
+pub mod semantic_cache;
@@ -0,0 +1,18 @@
+use crate::app::openai::{
+    chat::{ChatCompletion, ChatOptions},
+    errors::OpenAIError,
+    stream::ChatCompletionStream,
+};
+
+/// A chat completion backend. Requests and responses use the OpenAI chat types; providers with a
+/// different wire format translate to and from them.
+#[async_trait::async_trait]
+pub trait ChatProvider: Send + Sync {
+    async fn get_chat_completion(&self, opts: &ChatOptions) -> Result<ChatCompletion, OpenAIError>;
+
+    /// Request a streamed chat completion
+    async fn stream_chat_completion(
+        &self,
+        opts: &ChatOptions,
+    ) -> Result<ChatCompletionStream, OpenAIError>;
+}
@@ -0,0 +1,312 @@
+use std::time::Duration;
+
+use rand::Rng;
+use reqwest::{header::HeaderMap, RequestBuilder, Response, StatusCode};
+
+use crate::app::openai::errors::OpenAIError;
+
+/// How upstream provider calls are retried. Rate limited (429), timed out (408) and server error
+/// (5xx) responses are retried, as are connection errors and attempts that exceed `timeout`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries allowed after the first attempt
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled for each retry after it
+    pub base_delay: Duration,
+    /// Longest wait between attempts. A provider asking for a longer wait ends the retries.
+    pub max_delay: Duration,
+    /// Time allowed for each attempt to return response headers
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            timeout: Some(Duration::from_secs(120)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(max_retries: u32, timeout: Option<Duration>) -> Self {
+        Self {
+            max_retries,
+            timeout,
+            ..Default::default()
+        }
+    }
+
+    /// Jittered exponential backoff for a retry. The delay is drawn from the upper half of the
+    /// window so that retries are spread out without ever being immediate.
+    fn backoff(&self, retry: u32) -> Duration {
+        let window = self
+            .base_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        let half = window / 2;
+        half + rand::thread_rng().gen_range(Duration::ZERO..=half)
+    }
+
+    /// Send a request, retrying according to the policy. Responses with an error status are
+    /// returned as is once the retries run out, so callers can read the error body.
+    pub async fn send(&self, req: RequestBuilder) -> Result<Response, OpenAIError> {
+        let mut retry = 0;
+        loop {
+            let attempt = req
+                .try_clone()
+                .ok_or_else(|| OpenAIError::CreateChat("request body can not be retried".into()))?;
+
+            let sent: Result<Response, String> = match self.timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, attempt.send()).await {
+                    Ok(sent) => sent.map_err(|e| e.to_string()),
+                    Err(_) => Err(format!("no response within {timeout:?}")),
+                },
+                None => attempt.send().await.map_err(|e| e.to_string()),
+            };
+
+            let delay = match &sent {
+                Ok(res) if !retryable(res.status()) => None,
+                Ok(res) => Some(
+                    retry_after(res.status(), res.headers()).unwrap_or_else(|| self.backoff(retry)),
+                ),
+                Err(_) => Some(self.backoff(retry)),
+            };
+
+            match delay {
+                Some(delay) if retry < self.max_retries && delay <= self.max_delay => {
+                    let reason = match &sent {
+                        Ok(res) => res.status().to_string(),
+                        Err(e) => e.to_owned(),
+                    };
+                    log::warn!(
+                        "upstream request failed ({reason}), retrying in {delay:?} ({} of {})",
+                        retry + 1,
+                        self.max_retries
+                    );
+                    tokio::time::sleep(delay).await;
+                    retry += 1;
+                }
+                _ => {
+                    return sent.map_err(|e| {
+                        log::error!("{e}");
+                        OpenAIError::CreateChat(e)
+                    })
+                }
+            }
+        }
+    }
+}
+
+fn retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS
+        || status == StatusCode::REQUEST_TIMEOUT
+        || status.is_server_error()
+}
+
+/// The wait requested by the provider, from (in order of preference) `retry-after-ms`,
+/// `retry-after` (seconds or an http date), or for rate limited responses the later of the
+/// `x-ratelimit-reset-*` headers. Those are sent with every response, and say nothing about when
+/// a failed request may succeed.
+fn retry_after(status: StatusCode, headers: &HeaderMap) -> Option<Duration> {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+    // values that aren't a duration, like `inf` or negative numbers, are ignored
+    if let Some(ms) = header("retry-after-ms").and_then(|ms| ms.trim().parse::<f64>().ok()) {
+        return Duration::try_from_secs_f64(ms / 1000.0).ok();
+    }
+
+    if let Some(value) = header("retry-after") {
+        if let Ok(secs) = value.trim().parse::<f64>() {
+            return Duration::try_from_secs_f64(secs).ok();
+        }
+        if let Ok(date) = chrono::DateTime::parse_from_rfc2822(value.trim()) {
+            let wait = date.signed_duration_since(chrono::Utc::now());
+            return Some(wait.to_std().unwrap_or(Duration::ZERO));
+        }
+    }
+
+    if status != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    ["x-ratelimit-reset-requests", "x-ratelimit-reset-tokens"]
+        .into_iter()
+        .filter_map(|name| header(name).and_then(parse_reset))
+        .max()
+}
+
+/// Parse a rate limit reset duration such as `1s`, `6m0s`, `20ms` or `1h2m3.5s`
+fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut rest = value.trim();
+    if rest.is_empty() {
+        return None;
+    }
+
+    while !rest.is_empty() {
+        let split = rest
+            .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+            .unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(split);
+        let number: f64 = number.parse().ok()?;
+        let unit_len = tail
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        total += number
+            * match unit {
+                "ms" => 0.001,
+                "s" | "" => 1.0,
+                "m" => 60.0,
+                "h" => 3600.0,
+                _ => return None,
+            };
+        rest = tail;
+    }
+
+    Duration::try_from_secs_f64(total).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use axum::{extract::State, http, routing, Router};
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    pub fn test_parse_reset() {
+        assert_eq!(parse_reset("1s"), Some(Duration::from_secs(1)));
+        assert_eq!(parse_reset("6m0s"), Some(Duration::from_secs(360)));
+        assert_eq!(parse_reset("20ms"), Some(Duration::from_millis(20)));
+        assert_eq!(
+            parse_reset("1h2m3.5s"),
+            Some(Duration::from_secs_f64(3723.5))
+        );
+        assert_eq!(parse_reset("soon"), None);
+        assert_eq!(parse_reset("inf"), None);
+        assert_eq!(parse_reset(&format!("{}h", "9".repeat(30))), None);
+    }
+
+    #[test]
+    pub fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-ratelimit-reset-requests", HeaderValue::from_static("2s"));
+        headers.insert("x-ratelimit-reset-tokens", HeaderValue::from_static("1m"));
+        let limited = StatusCode::TOO_MANY_REQUESTS;
+        assert_eq!(
+            retry_after(limited, &headers),
+            Some(Duration::from_secs(60))
+        );
+        // server errors back off rather than wait for the rate limit to reset
+        assert_eq!(
+            retry_after(StatusCode::INTERNAL_SERVER_ERROR, &headers),
+            None
+        );
+        assert_eq!(retry_after(StatusCode::REQUEST_TIMEOUT, &headers), None);
+
+        headers.insert("retry-after", HeaderValue::from_static("3"));
+        assert_eq!(retry_after(limited, &headers), Some(Duration::from_secs(3)));
+        assert_eq!(
+            retry_after(StatusCode::SERVICE_UNAVAILABLE, &headers),
+            Some(Duration::from_secs(3))
+        );
+
+        headers.insert("retry-after-ms", HeaderValue::from_static("250"));
+        assert_eq!(
+            retry_after(limited, &headers),
+            Some(Duration::from_millis(250))
+        );
+    }
+
+    #[test]
+    pub fn test_retry_after_out_of_range() {
+        let limited = StatusCode::TOO_MANY_REQUESTS;
+        for value in ["inf", "NaN", "1e30"] {
+            let mut headers = HeaderMap::new();
+            headers.insert("retry-after", HeaderValue::from_str(value).unwrap());
+            assert_eq!(retry_after(limited, &headers), None, "retry-after: {value}");
+
+            let mut headers = HeaderMap::new();
+            headers.insert("retry-after-ms", HeaderValue::from_str(value).unwrap());
+            assert_eq!(
+                retry_after(limited, &headers),
+                None,
+                "retry-after-ms: {value}"
+            );
+        }
+    }
+
+    #[test]
+    pub fn test_backoff() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            ..Default::default()
+        };
+
+        let first = policy.backoff(0);
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let capped = policy.backoff(10);
+        assert!(capped >= Duration::from_millis(150) && capped <= Duration::from_millis(300));
+    }
+
+    /// Serve `failures` rate limited responses before succeeding, returning the server's uri and
+    /// the number of requests it has seen
+    async fn flaky_server(failures: u32) -> (String, Arc<AtomicU32>) {
+        let seen = Arc::new(AtomicU32::new(0));
+        let app = Router::new()
+            .route(
+                "/",
+                routing::get(move |State(seen): State<Arc<AtomicU32>>| async move {
+                    if seen.fetch_add(1, Ordering::SeqCst) < failures {
+                        (
+                            http::StatusCode::TOO_MANY_REQUESTS,
+                            [("retry-after-ms", "5")],
+                        )
+                    } else {
+                        (http::StatusCode::OK, [("retry-after-ms", "0")])
+                    }
+                }),
+            )
+            .with_state(seen.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (uri, seen)
+    }
+
+    #[tokio::test]
+    pub async fn test_send_retries_rate_limits() {
+        let (uri, seen) = flaky_server(2).await;
+        let client = reqwest::Client::new();
+
+        let res = RetryPolicy::new(3, None)
+            .send(client.get(&uri))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(seen.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    pub async fn test_send_stops_after_budget() {
+        let (uri, seen) = flaky_server(5).await;
+        let client = reqwest::Client::new();
+
+        let res = RetryPolicy::new(1, None)
+            .send(client.get(&uri))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(seen.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,294 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use mobc_redis::redis::{self, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use uuid::Uuid;
+
+use crate::app::{
+    openai::{
+        chat::{ChatCompletion, ChatOptions, ChatRole},
+        embeddings::EmbeddingOptions,
+        errors::OpenAIError,
+        OpenAIClient,
+    },
+    storage::cache::{errors::CacheError, Cache},
+};
+
+use super::cache::options_hash;
+
+/// Bump to invalidate every entry when the stored format changes
+const KEY_PREFIX: &str = "chat:semantic:v1";
+
+/// Most entries kept for the same namespace and request context. Only the newest entries are
+/// compared against a prompt, so lookups stay cheap without a vector index.
+const MAX_ENTRIES: isize = 256;
+
+/// Keys deleted per command when purging
+const PURGE_BATCH_SIZE: usize = 500;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SemanticCacheConfig {
+    /// Smallest cosine similarity between prompts for a stored completion to be reused
+    pub threshold: f32,
+    pub ttl: Duration,
+    pub embedding_model: String,
+}
+
+#[derive(Debug, Error)]
+pub enum SemanticCacheError {
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+    #[error(transparent)]
+    Embeddings(#[from] OpenAIError),
+}
+
+impl From<redis::RedisError> for SemanticCacheError {
+    fn from(e: redis::RedisError) -> Self {
+        SemanticCacheError::Cache(e.into())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Entry {
+    embedding: Vec<f32>,
+    completion: ChatCompletion,
+}
+
+/// The stored form of an entry. The prompt is kept to make entries easier to inspect.
+#[derive(Debug, Serialize)]
+struct NewEntry<'a> {
+    prompt: &'a str,
+    embedding: &'a [f32],
+    completion: &'a ChatCompletion,
+}
+
+/// A prompt that was embedded and not found, kept to store its completion without embedding it
+/// again
+#[derive(Debug)]
+pub struct Probe {
+    index: String,
+    prompt: String,
+    embedding: Vec<f32>,
+}
+
+#[derive(Debug)]
+pub enum Lookup {
+    Hit {
+        completion: ChatCompletion,
+        similarity: f32,
+    },
+    Miss(Probe),
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm = |v: &[f32]| v.iter().map(|x| x * x).sum::<f32>().sqrt();
+    match norm(a) * norm(b) {
+        n if n > 0.0 => dot / n,
+        _ => 0.0,
+    }
+}
+
+/// Split a request into the prompt that is compared by meaning, the content of its last user
+/// message, and a hash of everything else, which must match exactly
+pub fn split_prompt(opts: &ChatOptions) -> Option<(String, String)> {
+    let mut context = opts.clone();
+    let last = context
+        .messages
+        .iter_mut()
+        .rev()
+        .find(|message| matches!(message.role, ChatRole::User))?;
+    let prompt = last
+        .content
+        .take()
+        .filter(|prompt| !prompt.trim().is_empty())?;
+    Some((prompt, options_hash(&context)))
+}
+
+/// Namespaces are hashed into keys so that any organization or user id is a safe key part. Keys
+/// are relative to the cache's prefix.
+fn namespace_key(namespace: &str) -> String {
+    let hash = hex::encode(Sha256::digest(namespace.as_bytes()));
+    format!("{KEY_PREFIX}:{}", &hash[..32])
+}
+
+/// Completions reused across prompts that mean the same thing. Entries are isolated by
+/// namespace, and only shared between requests that are identical apart from the last user
+/// message.
+pub struct SemanticCache<'a> {
+    cache: &'a Cache,
+    embeddings: &'a OpenAIClient,
+    config: &'a SemanticCacheConfig,
+    namespace: String,
+}
+
+impl<'a> SemanticCache<'a> {
+    pub fn new(
+        cache: &'a Cache,
+        embeddings: &'a OpenAIClient,
+        config: &'a SemanticCacheConfig,
+        namespace: &str,
+    ) -> Self {
+        Self {
+            cache,
+            embeddings,
+            config,
+            namespace: namespace.to_owned(),
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.namespace
+    }
+
+    async fn embed(&self, prompt: &str) -> Result<Vec<f32>, SemanticCacheError> {
+        let opts = EmbeddingOptions::new(&self.config.embedding_model, vec![prompt.to_owned()]);
+        let res = self.embeddings.create_embeddings(&opts).await?;
+        match res.data.first() {
+            Some(embedding) => Ok(embedding.embedding.to_floats()?),
+            None => Err(OpenAIError::Serialize("prompt could not be embedded".into()).into()),
+        }
+    }
+
+    /// Find the stored completion whose prompt is most similar to the request's, if it passes the
+    /// threshold. Requests without a user prompt are not looked up.
+    pub async fn lookup(&self, opts: &ChatOptions) -> Result<Option<Lookup>, SemanticCacheError> {
+        let Some((prompt, context)) = split_prompt(opts) else {
+            return Ok(None);
+        };
+        let index = format!("{}:{context}", namespace_key(&self.namespace));
+        let embedding = self.embed(&prompt).await?;
+
+        let ids: Vec<String> = {
+            let mut conn = self.cache.conn().await?;
+            let index = self.cache.key(&index);
+            conn.zrembyscore::<_, _, _, ()>(&index, "-inf", Utc::now().timestamp())
+                .await?;
+            conn.zrevrange(&index, 0, MAX_ENTRIES - 1).await?
+        };
+        let keys: Vec<String> = ids.iter().map(|id| format!("{index}:{id}")).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let entries: Vec<Option<Entry>> = self.cache.mget(&keys).await?;
+
+        let mut best: Option<(Entry, f32)> = None;
+        for entry in entries.into_iter().flatten() {
+            let similarity = cosine_similarity(&embedding, &entry.embedding);
+            if best.as_ref().is_none_or(|(_, best)| similarity > *best) {
+                best = Some((entry, similarity));
+            }
+        }
+
+        Ok(Some(match best {
+            Some((entry, similarity)) if similarity >= self.config.threshold => Lookup::Hit {
+                completion: entry.completion,
+                similarity,
+            },
+            _ => Lookup::Miss(Probe {
+                index,
+                prompt,
+                embedding,
+            }),
+        }))
+    }
+
+    /// Remember the completion for a prompt that was looked up and missed
+    pub async fn store(
+        &self,
+        probe: &Probe,
+        completion: &ChatCompletion,
+    ) -> Result<(), SemanticCacheError> {
+        let id = Uuid::new_v4().to_string();
+        let ttl = self.config.ttl.as_secs().max(1);
+        let entry = NewEntry {
+            prompt: &probe.prompt,
+            embedding: &probe.embedding,
+            completion,
+        };
+
+        let key = format!("{}:{id}", probe.index);
+        self.cache.set(&key, &entry, self.config.ttl).await?;
+
+        let mut conn = self.cache.conn().await?;
+        let index = self.cache.key(&probe.index);
+        let expires = Utc::now().timestamp() + ttl as i64;
+        conn.zadd::<_, _, _, ()>(&index, &id, expires).await?;
+        conn.zremrangebyrank::<_, ()>(&index, 0, -(MAX_ENTRIES + 1))
+            .await?;
+        conn.expire::<_, ()>(&index, ttl as usize).await?;
+        Ok(())
+    }
+}
+
+/// Delete every entry of a namespace, or of all namespaces. Returns the number of keys removed.
+pub async fn purge(cache: &Cache, namespace: Option<&str>) -> Result<usize, SemanticCacheError> {
+    let pattern = match namespace {
+        Some(namespace) => cache.key(&format!("{}:*", namespace_key(namespace))),
+        None => cache.key(&format!("{KEY_PREFIX}:*")),
+    };
+    let mut conn = cache.conn().await?;
+
+    let mut keys: Vec<String> = vec![];
+    {
+        let mut iter = conn.scan_match::<_, String>(&pattern).await?;
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+    }
+    for batch in keys.chunks(PURGE_BATCH_SIZE) {
+        conn.del::<_, ()>(batch).await?;
+    }
+    Ok(keys.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::app::openai::chat::ChatMessage;
+
+    use super::*;
+
+    #[test]
+    pub fn test_cosine_similarity() {
+        assert!((cosine_similarity(&[1.0, 0.0], &[2.0, 0.0]) - 1.0).abs() < 1e-6);
+        assert!(cosine_similarity(&[1.0, 0.0], &[0.0, 1.0]).abs() < 1e-6);
+        assert!((cosine_similarity(&[1.0, 1.0], &[1.0, 0.0]) - 0.70710677).abs() < 1e-6);
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 0.0]), 0.0);
+        assert_eq!(cosine_similarity(&[1.0], &[1.0, 0.0]), 0.0);
+    }
+
+    #[test]
+    pub fn test_split_prompt() {
+        let options = |question: &str| {
+            ChatOptions::default(
+                "gpt-4",
+                vec![
+                    ChatMessage::new(ChatRole::System, "Answer briefly."),
+                    ChatMessage::new(ChatRole::User, question),
+                ],
+                64,
+            )
+        };
+
+        let (prompt, context) = split_prompt(&options("What's the capital of France?")).unwrap();
+        assert_eq!(prompt, "What's the capital of France?");
+        // the same context regardless of the question, and a different one for another model
+        let (_, other) = split_prompt(&options("Capital of France?")).unwrap();
+        assert_eq!(context, other);
+        let mut gpt4o = options("Capital of France?");
+        gpt4o.model = "gpt-4o".into();
+        assert_ne!(split_prompt(&gpt4o).unwrap().1, context);
+
+        let no_prompt = ChatOptions::default(
+            "gpt-4",
+            vec![ChatMessage::new(ChatRole::System, "Answer briefly.")],
+            64,
+        );
+        assert!(split_prompt(&no_prompt).is_none());
+        assert_ne!(namespace_key("org:a"), namespace_key("org:b"));
+    }
+}
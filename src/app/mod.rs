@@ -1,6 +1,10 @@
+pub mod agent;
 pub mod api;
+pub mod assets;
 pub mod auth;
+pub mod llm;
 pub mod openai;
+pub mod rag;
 pub mod storage;
 pub mod types;
 pub mod util;
@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Settings for an Azure OpenAI resource
+#[derive(Debug, Clone, Default)]
+pub struct AzureOptions {
+    pub api_version: String,
+    /// Deployment names keyed by the model name clients send in `ChatOptions.model`
+    pub deployments: HashMap<String, String>,
+}
+
+impl AzureOptions {
+    pub fn new(api_version: &str, deployments: HashMap<String, String>) -> Self {
+        Self {
+            api_version: api_version.to_owned(),
+            deployments,
+        }
+    }
+
+    /// The deployment serving a model. Models without a mapping are assumed to be deployed under
+    /// their own name.
+    pub fn deployment<'a>(&'a self, model: &'a str) -> &'a str {
+        self.deployments
+            .get(model)
+            .map(String::as_str)
+            .unwrap_or(model)
+    }
+}
+
+/// Parse a whitespace separated list of `model=deployment` pairs
+pub fn parse_deployments(raw: &str) -> HashMap<String, String> {
+    raw.split_ascii_whitespace()
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(model, deployment)| (model.to_owned(), deployment.to_owned()))
+        .collect()
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentFilterResult {
+    #[serde(default)]
+    pub filtered: bool,
+    pub severity: Option<String>,
+    pub detected: Option<bool>,
+}
+
+/// Per category results of the Azure content filter, attached to prompts and choices
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContentFilterResults {
+    pub hate: Option<ContentFilterResult>,
+    pub self_harm: Option<ContentFilterResult>,
+    pub sexual: Option<ContentFilterResult>,
+    pub violence: Option<ContentFilterResult>,
+    pub jailbreak: Option<ContentFilterResult>,
+    pub profanity: Option<ContentFilterResult>,
+    pub protected_material_text: Option<ContentFilterResult>,
+    pub protected_material_code: Option<ContentFilterResult>,
+    /// Set when the filter could not run
+    pub error: Option<Value>,
+    /// Categories added by newer api versions (custom blocklists, etc.)
+    #[serde(flatten)]
+    pub other: HashMap<String, Value>,
+}
+
+impl ContentFilterResults {
+    pub fn filtered(&self) -> bool {
+        [
+            &self.hate,
+            &self.self_harm,
+            &self.sexual,
+            &self.violence,
+            &self.jailbreak,
+            &self.profanity,
+            &self.protected_material_text,
+            &self.protected_material_code,
+        ]
+        .into_iter()
+        .flatten()
+        .any(|result| result.filtered)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptFilterResult {
+    pub prompt_index: u64,
+    pub content_filter_results: ContentFilterResults,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_parse_deployments() {
+        let deployments = parse_deployments("gpt-4=dfg-gpt4  gpt-35-turbo=dfg-gpt35 invalid");
+        let azure = AzureOptions::new("2024-02-01", deployments);
+
+        assert_eq!(azure.deployments.len(), 2);
+        assert_eq!(azure.deployment("gpt-4"), "dfg-gpt4");
+        assert_eq!(azure.deployment("gpt-4o"), "gpt-4o");
+    }
+
+    #[test]
+    pub fn test_content_filter_results() {
+        let raw = r#"{
+            "hate": {"filtered": false, "severity": "safe"},
+            "violence": {"filtered": true, "severity": "medium"},
+            "jailbreak": {"filtered": false, "detected": false},
+            "custom_blocklists": []
+        }"#;
+
+        let results: ContentFilterResults = serde_json::from_str(raw).unwrap();
+        assert!(results.filtered());
+        assert!(results.other.contains_key("custom_blocklists"));
+    }
+}
@@ -5,12 +5,16 @@ use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use serde_with::skip_serializing_none;
 
+use crate::app::llm::provider::ChatProvider;
+
+use super::azure::{ContentFilterResults, PromptFilterResult};
 use super::errors::OpenAIError;
+use super::stream::{self, ChatCompletionStream};
 use super::OpenAIClient;
 
 use super::usage::Usage;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ChatRole {
     #[serde(rename = "system")]
     System,
@@ -20,36 +24,126 @@ pub enum ChatRole {
     Assistant,
     #[serde(rename = "function")]
     Function,
+    #[serde(rename = "tool")]
+    Tool,
 }
 
+/// A message in the conversation. `content` is null on assistant messages that only call tools,
+/// and `tool_call_id` names the call a `tool` message is answering.
 #[serde_with::skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
-    role: ChatRole,
-    content: String,
-    name: Option<String>,
-    function_call: Option<Value>,
+    pub role: ChatRole,
+    pub content: Option<String>,
+    pub name: Option<String>,
+    pub function_call: Option<Value>,
+    pub tool_calls: Option<Vec<ToolCall>>,
+    pub tool_call_id: Option<String>,
+}
+
+impl ChatMessage {
+    pub fn new(role: ChatRole, content: &str) -> Self {
+        Self {
+            role,
+            content: Some(content.to_owned()),
+            name: None,
+            function_call: None,
+            tool_calls: None,
+            tool_call_id: None,
+        }
+    }
+
+    /// The result of a tool call, to be sent back to the model
+    pub fn tool_result(tool_call_id: &str, content: &str) -> Self {
+        Self {
+            tool_call_id: Some(tool_call_id.to_owned()),
+            ..Self::new(ChatRole::Tool, content)
+        }
+    }
 }
 
 #[serde_with::skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatFunction {
     pub name: String,
     pub description: Option<String>,
     pub parameters: Value,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ToolType {
+    Function,
+}
+
+/// A tool the model may call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChatTool {
+    #[serde(rename = "type")]
+    pub kind: ToolType,
+    pub function: ChatFunction,
+}
+
+impl ChatTool {
+    pub fn function(function: ChatFunction) -> Self {
+        Self {
+            kind: ToolType::Function,
+            function,
+        }
+    }
+}
+
+/// A function invocation requested by the model. `arguments` is a JSON encoded object, which the
+/// model does not always get right, so it is left to the caller to parse.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunctionCall {
+    pub name: String,
+    pub arguments: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: ToolType,
+    pub function: FunctionCall,
+}
+
+impl ToolCall {
+    pub fn function(id: &str, name: &str, arguments: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            kind: ToolType::Function,
+            function: FunctionCall {
+                name: name.to_owned(),
+                arguments: arguments.to_owned(),
+            },
+        }
+    }
+}
+
 #[serde_with::skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamOptions {
+    pub include_usage: Option<bool>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatOptions {
     pub model: String,
     pub messages: Vec<ChatMessage>,
     pub functions: Option<Vec<ChatFunction>>,
     pub function_call: Option<Value>,
+    pub tools: Option<Vec<ChatTool>>,
+    /// `"none"`, `"auto"`, `"required"` or `{"type": "function", "function": {"name": ...}}`
+    pub tool_choice: Option<Value>,
+    pub parallel_tool_calls: Option<bool>,
     pub temperature: Option<f32>,
     pub top_p: Option<f32>,
     pub n: Option<u32>,
     pub stream: Option<bool>,
+    pub stream_options: Option<StreamOptions>,
     pub stop: Option<[String; 4]>,
     pub max_tokens: u64,
     pub presence_penalty: Option<i8>,
@@ -65,10 +159,14 @@ impl ChatOptions {
             messages,
             functions: None,
             function_call: None,
+            tools: None,
+            tool_choice: None,
+            parallel_tool_calls: None,
             temperature: Some(1.0),
             top_p: Some(1.0),
             n: Some(1),
             stream: Some(false),
+            stream_options: None,
             stop: None,
             max_tokens,
             presence_penalty: Some(0),
@@ -79,19 +177,36 @@ impl ChatOptions {
     }
 }
 
+/// The message generated by the model. `content` is null when the model only calls tools, and
+/// missing when the content filter stops a generation.
+#[serde_with::skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatResponseMessage {
     pub role: String,
-    pub content: String,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ToolCall>>,
+}
+
+impl From<ChatResponseMessage> for ChatMessage {
+    fn from(message: ChatResponseMessage) -> Self {
+        ChatMessage {
+            tool_calls: message.tool_calls,
+            content: message.content,
+            ..ChatMessage::new(ChatRole::Assistant, "")
+        }
+    }
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatResponseChoice {
     pub index: u64,
     pub message: ChatResponseMessage,
     pub finish_reason: String,
+    pub content_filter_results: Option<ContentFilterResults>,
 }
 
+#[serde_with::skip_serializing_none]
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatCompletion {
     pub id: String,
@@ -99,33 +214,122 @@ pub struct ChatCompletion {
     pub created: u64,
     pub model: String,
     pub choices: Vec<ChatResponseChoice>,
+    #[serde(default)]
     pub usage: Usage,
+    pub prompt_filter_results: Option<Vec<PromptFilterResult>>,
 }
 
-impl OpenAIClient {
-    pub async fn get_chat_completion(
-        &self,
-        opts: &ChatOptions,
-    ) -> Result<ChatCompletion, OpenAIError> {
-        let uri = self.base_uri.clone() + "/chat/completions";
-        let api_key = &self.api_key;
-        let res = self
-            .client
-            .post(&uri)
-            .header("Authorization", format!("Bearer {api_key}"))
-            .json(&opts)
-            .send()
-            .await
-            .map_err(|e| {
-                log::error!("{}", e.to_string());
-                OpenAIError::CreateChat(e.to_string())
-            })?;
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChatDelta {
+    pub role: Option<String>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FunctionCallDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+/// A fragment of a tool call. The first fragment of a call carries its id and name, the rest
+/// carry pieces of the arguments for the call at `index`.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ToolCallDelta {
+    pub index: usize,
+    pub id: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<ToolType>,
+    pub function: Option<FunctionCallDelta>,
+}
+
+impl ToolCallDelta {
+    /// Fold the fragment into the calls assembled so far. Calls are streamed in order, so a
+    /// fragment may only continue a call already started or start the next one.
+    pub fn apply(&self, calls: &mut Vec<ToolCall>) -> Result<(), OpenAIError> {
+        if self.index > calls.len() {
+            return Err(OpenAIError::Serialize(format!(
+                "tool call {} streamed before tool call {}",
+                self.index,
+                calls.len()
+            )));
+        }
+        if self.index == calls.len() {
+            calls.push(ToolCall::function("", "", ""));
+        }
+        let call = &mut calls[self.index];
+        if let Some(id) = &self.id {
+            call.id.push_str(id);
+        }
+        if let Some(function) = &self.function {
+            if let Some(name) = &function.name {
+                call.function.name.push_str(name);
+            }
+            if let Some(arguments) = &function.arguments {
+                call.function.arguments.push_str(arguments);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatChunkChoice {
+    pub index: u64,
+    #[serde(default)]
+    pub delta: ChatDelta,
+    pub finish_reason: Option<String>,
+    pub content_filter_results: Option<ContentFilterResults>,
+}
+
+/// A partial completion, as emitted by the provider while streaming
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatCompletionChunk {
+    pub id: String,
+    pub object: String,
+    pub created: u64,
+    pub model: String,
+    pub choices: Vec<ChatChunkChoice>,
+    pub usage: Option<Usage>,
+    pub prompt_filter_results: Option<Vec<PromptFilterResult>>,
+}
+
+#[async_trait::async_trait]
+impl ChatProvider for OpenAIClient {
+    async fn get_chat_completion(&self, opts: &ChatOptions) -> Result<ChatCompletion, OpenAIError> {
+        let uri = self.endpoint("/chat/completions", &opts.model);
+        let req = self.authorize(self.client.post(&uri)).json(&opts);
+        let res = OpenAIError::check(self.retry.send(req).await?).await?;
         let completion = res.json().await.map_err(|e| {
             log::error!("{}", e.to_string());
             OpenAIError::Serialize(e.to_string())
         })?;
         Ok(completion)
     }
+
+    /// The provider is asked to report usage in a final chunk so that callers can account for the
+    /// request once the stream finishes.
+    async fn stream_chat_completion(
+        &self,
+        opts: &ChatOptions,
+    ) -> Result<ChatCompletionStream, OpenAIError> {
+        let mut opts = opts.clone();
+        opts.stream = Some(true);
+        opts.stream_options = Some(StreamOptions {
+            include_usage: Some(true),
+        });
+
+        let uri = self.endpoint("/chat/completions", &opts.model);
+        let req = self.authorize(self.client.post(&uri)).json(&opts);
+        let res = OpenAIError::check(self.retry.send(req).await?).await?;
+
+        Ok(stream::chat_chunks(stream::sse_events(res.bytes_stream())))
+    }
 }
 
 #[cfg(test)]
@@ -141,27 +345,78 @@ mod tests {
         test_util::init();
         let api_key = env::var("OPENAI_API_KEY").expect("error loading API key");
         let client = OpenAIClient::new(&api_key, "https://api.openai.com/v1");
-        let x = ChatMessage {
-            role: ChatRole::System,
-            name: None,
-            content: "you are a helpful assistant".to_owned(),
-            function_call: None,
-        };
+        let x = ChatMessage::new(ChatRole::System, "you are a helpful assistant");
 
         println!("{:#?}", x);
         let completion = client
             .get_chat_completion(&ChatOptions::default(
                 "gpt-3.5-turbo",
-                vec![ChatMessage {
-                    role: ChatRole::System,
-                    name: None,
-                    content: "complete the lyric: scar tissue that i wish you saw...".to_owned(),
-                    function_call: None,
-                }],
+                vec![ChatMessage::new(
+                    ChatRole::System,
+                    "complete the lyric: scar tissue that i wish you saw...",
+                )],
                 20,
             ))
             .await
             .expect("error fetching chat completion");
         println!("{:#?}", completion);
     }
+
+    #[test]
+    pub fn test_tool_call_response() {
+        let raw = r#"{"id":"chatcmpl-1","object":"chat.completion","created":1,"model":"gpt-4o","choices":[{"index":0,"message":{"role":"assistant","content":null,"tool_calls":[{"id":"call_1","type":"function","function":{"name":"get_weather","arguments":"{\"city\":\"Paris\"}"}}]},"finish_reason":"tool_calls"}]}"#;
+        let completion: ChatCompletion = serde_json::from_str(raw).unwrap();
+        let message = ChatMessage::from(completion.choices.into_iter().next().unwrap().message);
+        assert!(message.content.is_none());
+
+        let opts = ChatOptions {
+            tools: Some(vec![ChatTool::function(ChatFunction {
+                name: "get_weather".into(),
+                description: None,
+                parameters: serde_json::json!({"type": "object"}),
+            })]),
+            tool_choice: Some("auto".into()),
+            ..ChatOptions::default(
+                "gpt-4o",
+                vec![message, ChatMessage::tool_result("call_1", "sunny")],
+                20,
+            )
+        };
+        let body = serde_json::to_value(&opts).unwrap();
+        assert_eq!(body["tools"][0]["type"], "function");
+        assert_eq!(body["messages"][0]["tool_calls"][0]["id"], "call_1");
+        assert!(body["messages"][0].get("content").is_none());
+        assert_eq!(body["messages"][1]["role"], "tool");
+        assert_eq!(body["messages"][1]["tool_call_id"], "call_1");
+    }
+
+    #[test]
+    pub fn test_apply_tool_call_deltas() {
+        let raw = [
+            r#"{"index":0,"id":"call_1","type":"function","function":{"name":"get_weather","arguments":""}}"#,
+            r#"{"index":0,"function":{"arguments":"{\"city\":"}}"#,
+            r#"{"index":0,"function":{"arguments":"\"Paris\"}"}}"#,
+            r#"{"index":1,"id":"call_2","type":"function","function":{"name":"get_time","arguments":"{}"}}"#,
+        ];
+
+        let mut calls = vec![];
+        for delta in raw {
+            serde_json::from_str::<ToolCallDelta>(delta)
+                .unwrap()
+                .apply(&mut calls)
+                .unwrap();
+        }
+        assert_eq!(
+            calls,
+            vec![
+                ToolCall::function("call_1", "get_weather", r#"{"city":"Paris"}"#),
+                ToolCall::function("call_2", "get_time", "{}"),
+            ]
+        );
+
+        let skipping: ToolCallDelta =
+            serde_json::from_str(r#"{"index":4000000000,"function":{"arguments":"{}"}}"#).unwrap();
+        assert!(skipping.apply(&mut calls).is_err());
+        assert_eq!(calls.len(), 2);
+    }
 }
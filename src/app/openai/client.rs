@@ -1,10 +1,23 @@
-use reqwest::Client;
+use reqwest::{Client, RequestBuilder};
+
+use crate::app::llm::retry::RetryPolicy;
+
+use super::azure::AzureOptions;
+
+/// The flavor of api a client talks to
+#[derive(Debug, Clone)]
+pub enum OpenAIFlavor {
+    OpenAI,
+    Azure(AzureOptions),
+}
 
 #[derive(Clone)]
 pub struct OpenAIClient {
     pub api_key: String,
     pub base_uri: String,
     pub client: Client,
+    pub flavor: OpenAIFlavor,
+    pub retry: RetryPolicy,
 }
 
 impl OpenAIClient {
@@ -13,6 +26,8 @@ impl OpenAIClient {
             api_key: api_key.to_owned(),
             base_uri: base_uri.to_string(),
             client: Client::new(),
+            flavor: OpenAIFlavor::OpenAI,
+            retry: RetryPolicy::default(),
         }
     }
 
@@ -21,15 +36,61 @@ impl OpenAIClient {
             api_key: api_key.into(),
             base_uri: base_uri.into(),
             client,
+            flavor: OpenAIFlavor::OpenAI,
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    /// Create a client for an Azure OpenAI resource
+    ///
+    /// * `endpoint`: The resource endpoint (e.g. https://<resource>.openai.azure.com)
+    pub fn azure(api_key: &str, endpoint: &str, options: AzureOptions) -> Self {
+        OpenAIClient {
+            api_key: api_key.to_owned(),
+            base_uri: endpoint.trim_end_matches('/').to_owned(),
+            client: Client::new(),
+            flavor: OpenAIFlavor::Azure(options),
+            retry: RetryPolicy::default(),
+        }
+    }
+
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Build the uri of an api path (e.g. `/chat/completions`). Azure scopes paths to the
+    /// deployment serving `model`.
+    pub fn endpoint(&self, path: &str, model: &str) -> String {
+        match &self.flavor {
+            OpenAIFlavor::OpenAI => self.base_uri.clone() + path,
+            OpenAIFlavor::Azure(azure) => format!(
+                "{}/openai/deployments/{}{path}?api-version={}",
+                self.base_uri,
+                azure.deployment(model),
+                azure.api_version
+            ),
+        }
+    }
+
+    /// Attach credentials to a request
+    pub fn authorize(&self, req: RequestBuilder) -> RequestBuilder {
+        let api_key = &self.api_key;
+        match &self.flavor {
+            OpenAIFlavor::OpenAI => req.header("Authorization", format!("Bearer {api_key}")),
+            OpenAIFlavor::Azure(_) => req.header("api-key", api_key),
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::env;
+    use std::{collections::HashMap, env};
 
-    use crate::app::{openai::OpenAIClient, util::test_util};
+    use crate::app::{
+        openai::{azure::AzureOptions, OpenAIClient},
+        util::test_util,
+    };
 
     #[tokio::test]
     pub async fn test_create_client() {
@@ -38,4 +99,24 @@ mod tests {
             env::var("OPENAI_API_KEY").expect("environment variable OPENAI_API_KEY not defined");
         let _ = OpenAIClient::new(&api_key, "https://api.openai.com/v1");
     }
+
+    #[test]
+    pub fn test_endpoint() {
+        let openai = OpenAIClient::new("key", "https://api.openai.com/v1");
+        assert_eq!(
+            openai.endpoint("/chat/completions", "gpt-4"),
+            "https://api.openai.com/v1/chat/completions"
+        );
+
+        let deployments = HashMap::from([("gpt-4".to_owned(), "dfg-gpt4".to_owned())]);
+        let azure = OpenAIClient::azure(
+            "key",
+            "https://dfg.openai.azure.com/",
+            AzureOptions::new("2024-02-01", deployments),
+        );
+        assert_eq!(
+            azure.endpoint("/chat/completions", "gpt-4"),
+            "https://dfg.openai.azure.com/openai/deployments/dfg-gpt4/chat/completions?api-version=2024-02-01"
+        );
+    }
 }
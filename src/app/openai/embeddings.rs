@@ -0,0 +1,224 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use super::{errors::OpenAIError, usage::Usage, OpenAIClient};
+
+/// Most inputs accepted by a single embeddings request
+pub const MAX_BATCH_SIZE: usize = 2048;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    Single(String),
+    Batch(Vec<String>),
+}
+
+impl EmbeddingInput {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::Single(input) => vec![input],
+            EmbeddingInput::Batch(inputs) => inputs,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EncodingFormat {
+    #[default]
+    Float,
+    /// Little endian f32s, base64 encoded. Roughly a quarter of the size of the float encoding.
+    Base64,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddingOptions {
+    pub model: String,
+    pub input: EmbeddingInput,
+    /// Truncate embeddings to this many dimensions (text-embedding-3 models only)
+    pub dimensions: Option<u32>,
+    pub encoding_format: Option<EncodingFormat>,
+    pub user: Option<String>,
+}
+
+impl EmbeddingOptions {
+    pub fn new(model: &str, input: Vec<String>) -> Self {
+        Self {
+            model: model.to_owned(),
+            input: EmbeddingInput::Batch(input),
+            dimensions: None,
+            encoding_format: None,
+            user: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingVector {
+    Float(Vec<f32>),
+    Base64(String),
+}
+
+impl EmbeddingVector {
+    /// The embedding as floats, decoding it if it was requested as base64
+    pub fn to_floats(&self) -> Result<Vec<f32>, OpenAIError> {
+        match self {
+            EmbeddingVector::Float(floats) => Ok(floats.clone()),
+            EmbeddingVector::Base64(encoded) => {
+                let bytes = STANDARD
+                    .decode(encoded)
+                    .map_err(|e| OpenAIError::Serialize(e.to_string()))?;
+                Ok(bytes
+                    .chunks_exact(4)
+                    .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                    .collect())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Embedding {
+    pub object: String,
+    pub index: usize,
+    pub embedding: EmbeddingVector,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    #[serde(default)]
+    pub usage: Usage,
+}
+
+impl OpenAIClient {
+    /// Embed the input, splitting it into as many requests as needed. Embeddings are returned in
+    /// input order and usage is summed across the requests.
+    pub async fn create_embeddings(
+        &self,
+        opts: &EmbeddingOptions,
+    ) -> Result<EmbeddingResponse, OpenAIError> {
+        self.create_embeddings_in_batches(opts, MAX_BATCH_SIZE)
+            .await
+    }
+
+    async fn create_embeddings_in_batches(
+        &self,
+        opts: &EmbeddingOptions,
+        batch_size: usize,
+    ) -> Result<EmbeddingResponse, OpenAIError> {
+        let inputs = opts.input.clone().into_vec();
+        let uri = self.endpoint("/embeddings", &opts.model);
+
+        let mut combined = EmbeddingResponse {
+            object: "list".into(),
+            data: Vec::with_capacity(inputs.len()),
+            model: opts.model.clone(),
+            usage: Usage::default(),
+        };
+
+        for (batch, chunk) in inputs.chunks(batch_size).enumerate() {
+            let body = EmbeddingOptions {
+                input: EmbeddingInput::Batch(chunk.to_vec()),
+                ..opts.clone()
+            };
+            let req = self.authorize(self.client.post(&uri)).json(&body);
+            let res = OpenAIError::check(self.retry.send(req).await?).await?;
+            let mut res: EmbeddingResponse = res.json().await.map_err(|e| {
+                log::error!("{}", e.to_string());
+                OpenAIError::Serialize(e.to_string())
+            })?;
+
+            // indices are relative to the batch
+            res.data.sort_by_key(|embedding| embedding.index);
+            for mut embedding in res.data {
+                embedding.index += batch * batch_size;
+                combined.data.push(embedding);
+            }
+            combined.model = res.model;
+            combined.usage += &res.usage;
+        }
+
+        Ok(combined)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing, Json, Router};
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    /// Serve embeddings whose only component is the length of the input, returned in reverse
+    async fn embeddings_server() -> String {
+        let app = Router::new().route(
+            "/embeddings",
+            routing::post(|Json(body): Json<Value>| async move {
+                let inputs = body["input"].as_array().cloned().unwrap_or_default();
+                let data: Vec<Value> = inputs
+                    .iter()
+                    .enumerate()
+                    .rev()
+                    .map(|(index, input)| {
+                        let len = input.as_str().unwrap_or_default().len();
+                        json!({"object": "embedding", "index": index, "embedding": [len]})
+                    })
+                    .collect();
+                Json(json!({
+                    "object": "list",
+                    "data": data,
+                    "model": body["model"],
+                    "usage": {"prompt_tokens": inputs.len(), "total_tokens": inputs.len()}
+                }))
+            }),
+        );
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        uri
+    }
+
+    #[tokio::test]
+    pub async fn test_create_embeddings_in_batches() {
+        let client = OpenAIClient::new("key", &embeddings_server().await);
+        let inputs = ["a", "bb", "ccc", "dddd", "eeeee"]
+            .map(String::from)
+            .to_vec();
+
+        let res = client
+            .create_embeddings_in_batches(
+                &EmbeddingOptions::new("text-embedding-3-small", inputs),
+                2,
+            )
+            .await
+            .unwrap();
+        let lengths: Vec<f32> = res
+            .data
+            .iter()
+            .map(|embedding| embedding.embedding.to_floats().unwrap()[0])
+            .collect();
+        assert_eq!(lengths, vec![1.0, 2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(
+            res.data.iter().map(|e| e.index).collect::<Vec<_>>(),
+            vec![0, 1, 2, 3, 4]
+        );
+        assert_eq!(res.usage.total_tokens, 5);
+    }
+
+    #[test]
+    pub fn test_decode_base64_embedding() {
+        let bytes: Vec<u8> = [0.5f32, -1.0]
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect();
+        let vector: EmbeddingVector =
+            serde_json::from_value(json!(STANDARD.encode(bytes))).unwrap();
+        assert_eq!(vector.to_floats().unwrap(), vec![0.5, -1.0]);
+    }
+}
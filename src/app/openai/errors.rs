@@ -1,3 +1,6 @@
+use axum::http::StatusCode;
+use serde::Deserialize;
+use serde_json::Value;
 use thiserror::Error;
 
 #[derive(Error, Debug, Eq, PartialEq)]
@@ -6,4 +9,208 @@ pub enum OpenAIError {
     CreateChat(String),
     #[error("unable to serialize response. error: {0}")]
     Serialize(String),
+    #[error("unable to list models. error: {0}")]
+    ListModels(String),
+    #[error("provider rejected the request. error: {0}")]
+    InvalidRequest(String),
+    #[error("provider rejected our credentials. error: {0}")]
+    Authentication(String),
+    #[error("provider rate limit exceeded. error: {0}")]
+    RateLimit(String),
+    #[error("context length exceeded. error: {0}")]
+    ContextLengthExceeded(String),
+    #[error("provider content filter blocked the request. error: {0}")]
+    ContentFilter(String),
+    #[error("provider server error. error: {0}")]
+    Server(String),
+    #[error("provider unavailable. error: {0}")]
+    Unavailable(String),
+}
+
+/// The error object inside a provider error envelope. OpenAI, Azure and Anthropic send an object
+/// (`{"error": {"message": ..., "type": ..., "code": ...}}`), ollama sends a bare string.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ErrorBody {
+    Detailed {
+        message: String,
+        #[serde(rename = "type")]
+        kind: Option<String>,
+        code: Option<Value>,
+    },
+    Message(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct ErrorEnvelope {
+    error: ErrorBody,
+}
+
+impl OpenAIError {
+    /// Classify a provider error from its status (when known) and body
+    pub fn from_body(status: Option<u16>, body: &str) -> Self {
+        let (message, kind, code) = match serde_json::from_str::<ErrorEnvelope>(body) {
+            Ok(ErrorEnvelope {
+                error:
+                    ErrorBody::Detailed {
+                        message,
+                        kind,
+                        code,
+                    },
+            }) => {
+                let code = code.map(|code| match code {
+                    Value::String(code) => code,
+                    code => code.to_string(),
+                });
+                (message, kind.unwrap_or_default(), code.unwrap_or_default())
+            }
+            Ok(ErrorEnvelope {
+                error: ErrorBody::Message(message),
+            }) => (message, String::new(), String::new()),
+            Err(_) => (body.trim().to_owned(), String::new(), String::new()),
+        };
+
+        let hint = |needle: &str| kind.contains(needle) || code.contains(needle);
+        let lowercase = message.to_lowercase();
+
+        if hint("context_length")
+            || lowercase.contains("maximum context length")
+            || lowercase.contains("prompt is too long")
+        {
+            OpenAIError::ContextLengthExceeded(message)
+        } else if hint("content_filter") || hint("content_policy") {
+            OpenAIError::ContentFilter(message)
+        } else if hint("authentication")
+            || hint("permission")
+            || hint("invalid_api_key")
+            || matches!(status, Some(401 | 403))
+        {
+            OpenAIError::Authentication(message)
+        } else if hint("rate_limit") || hint("insufficient_quota") || status == Some(429) {
+            OpenAIError::RateLimit(message)
+        } else if hint("overloaded") || matches!(status, Some(503 | 529)) {
+            OpenAIError::Unavailable(message)
+        } else if matches!(status, Some(400..=499)) || hint("invalid_request") {
+            OpenAIError::InvalidRequest(message)
+        } else {
+            OpenAIError::Server(message)
+        }
+    }
+
+    /// Read and classify an unsuccessful provider response
+    pub async fn from_response(res: reqwest::Response) -> Self {
+        let status = res.status().as_u16();
+        let err = match res.text().await {
+            Ok(body) => OpenAIError::from_body(Some(status), &body),
+            Err(e) => OpenAIError::from_body(Some(status), &e.to_string()),
+        };
+        log::error!("{}", err.to_string());
+        err
+    }
+
+    /// Pass successful responses through, turning anything else into a classified error
+    pub async fn check(res: reqwest::Response) -> Result<reqwest::Response, Self> {
+        if res.status().is_success() {
+            Ok(res)
+        } else {
+            Err(OpenAIError::from_response(res).await)
+        }
+    }
+
+    /// The status to report to our own clients
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            OpenAIError::InvalidRequest(_) | OpenAIError::ContentFilter(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            OpenAIError::Authentication(_) => StatusCode::UNAUTHORIZED,
+            OpenAIError::RateLimit(_) => StatusCode::TOO_MANY_REQUESTS,
+            OpenAIError::ContextLengthExceeded(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            OpenAIError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            OpenAIError::CreateChat(_)
+            | OpenAIError::Serialize(_)
+            | OpenAIError::ListModels(_)
+            | OpenAIError::Server(_) => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// A stable, machine readable code for the error
+    pub fn code(&self) -> &'static str {
+        match self {
+            OpenAIError::CreateChat(_) => "upstream_request_failed",
+            OpenAIError::Serialize(_) => "upstream_invalid_response",
+            OpenAIError::ListModels(_) => "upstream_models_unavailable",
+            OpenAIError::InvalidRequest(_) => "invalid_request",
+            OpenAIError::Authentication(_) => "upstream_authentication_failed",
+            OpenAIError::RateLimit(_) => "rate_limited",
+            OpenAIError::ContextLengthExceeded(_) => "context_length_exceeded",
+            OpenAIError::ContentFilter(_) => "content_filtered",
+            OpenAIError::Server(_) => "upstream_server_error",
+            OpenAIError::Unavailable(_) => "upstream_unavailable",
+        }
+    }
+
+    /// A message that is safe to show to clients. Provider messages are only passed through when
+    /// they describe a problem with the client's request.
+    pub fn public_message(&self) -> String {
+        match self {
+            OpenAIError::InvalidRequest(message)
+            | OpenAIError::ContextLengthExceeded(message)
+            | OpenAIError::ContentFilter(message) => message.to_owned(),
+            OpenAIError::RateLimit(_) => "rate limit exceeded, try again later".into(),
+            OpenAIError::Unavailable(_) => "ai provider is unavailable, try again later".into(),
+            _ => "unable to retrieve chat completion".into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_classify_openai_errors() {
+        let body = r#"{"error":{"message":"This model's maximum context length is 4097 tokens.","type":"invalid_request_error","param":"messages","code":"context_length_exceeded"}}"#;
+        let err = OpenAIError::from_body(Some(400), body);
+        assert!(matches!(err, OpenAIError::ContextLengthExceeded(_)));
+        assert_eq!(err.status_code(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        let body = r#"{"error":{"message":"Incorrect API key provided","type":"invalid_request_error","param":null,"code":"invalid_api_key"}}"#;
+        let err = OpenAIError::from_body(Some(401), body);
+        assert!(matches!(err, OpenAIError::Authentication(_)));
+        assert_eq!(err.public_message(), "unable to retrieve chat completion");
+
+        let body = r#"{"error":{"message":"Rate limit reached","type":"requests","param":null,"code":"rate_limit_exceeded"}}"#;
+        assert_eq!(
+            OpenAIError::from_body(Some(429), body).status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    pub fn test_classify_other_providers() {
+        let azure = r#"{"error":{"message":"The response was filtered","type":null,"param":"prompt","code":"content_filter","status":400}}"#;
+        assert_eq!(
+            OpenAIError::from_body(Some(400), azure).code(),
+            "content_filtered"
+        );
+
+        let anthropic =
+            r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#;
+        assert_eq!(
+            OpenAIError::from_body(Some(529), anthropic).status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        let ollama = r#"{"error":"model 'llama9' not found"}"#;
+        assert_eq!(
+            OpenAIError::from_body(Some(404), ollama),
+            OpenAIError::InvalidRequest("model 'llama9' not found".into())
+        );
+
+        assert_eq!(
+            OpenAIError::from_body(Some(500), "<html>bad gateway</html>").status_code(),
+            StatusCode::BAD_GATEWAY
+        );
+    }
 }
@@ -1,6 +1,9 @@
+pub mod azure;
 pub mod chat;
 pub mod client;
+pub mod embeddings;
 pub mod errors;
+pub mod stream;
 pub mod usage;
 
 pub use client::OpenAIClient;
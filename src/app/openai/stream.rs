@@ -0,0 +1,228 @@
+use futures::{
+    future,
+    stream::{self, BoxStream, StreamExt},
+};
+use serde::de::DeserializeOwned;
+
+use super::{chat::ChatCompletionChunk, errors::OpenAIError};
+
+pub type ChatCompletionStream = BoxStream<'static, Result<ChatCompletionChunk, OpenAIError>>;
+
+/// Marker sent by the provider in place of a final chunk
+const DONE: &str = "[DONE]";
+
+/// A single server-sent event
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    pub event: Option<String>,
+    pub data: String,
+}
+
+impl SseEvent {
+    /// Parse a raw event block (the text between two blank lines)
+    fn parse(block: &str) -> Option<Self> {
+        let mut event = SseEvent::default();
+        let mut data: Vec<&str> = vec![];
+
+        for line in block.lines() {
+            // lines beginning with a colon are comments (used as keep alives)
+            if line.is_empty() || line.starts_with(':') {
+                continue;
+            }
+            let (field, value) = line.split_once(':').unwrap_or((line, ""));
+            let value = value.strip_prefix(' ').unwrap_or(value);
+            match field {
+                "event" => event.event = Some(value.to_owned()),
+                "data" => data.push(value),
+                _ => {}
+            }
+        }
+
+        if data.is_empty() && event.event.is_none() {
+            return None;
+        }
+        event.data = data.join("\n");
+        Some(event)
+    }
+}
+
+/// Split a streamed response body into blocks ending with `delimiter`. Carriage returns are
+/// dropped so that crlf line endings behave like lf.
+fn split_body<S, B, E>(
+    body: S,
+    delimiter: &'static [u8],
+) -> BoxStream<'static, Result<String, OpenAIError>>
+where
+    S: futures::Stream<Item = Result<B, E>> + Send + 'static,
+    B: AsRef<[u8]> + 'static,
+    E: std::fmt::Display + 'static,
+{
+    let state = (body.boxed(), Vec::<u8>::new(), false);
+    stream::unfold(
+        state,
+        move |(mut body, mut buffer, mut exhausted)| async move {
+            loop {
+                let boundary = buffer.windows(delimiter.len()).position(|w| w == delimiter);
+                if let Some(end) = boundary {
+                    // delimiters are ascii, so a complete block is never a partial utf-8 sequence
+                    let block: Vec<u8> = buffer.drain(..end + delimiter.len()).collect();
+                    let block = String::from_utf8_lossy(&block).into_owned();
+                    return Some((Ok(block), (body, buffer, exhausted)));
+                }
+
+                if exhausted {
+                    if buffer.is_empty() {
+                        return None;
+                    }
+                    let block = String::from_utf8_lossy(&buffer).into_owned();
+                    return Some((Ok(block), (body, vec![], true)));
+                }
+
+                match body.next().await {
+                    Some(Ok(bytes)) => {
+                        buffer.extend(bytes.as_ref().iter().filter(|b| **b != b'\r'))
+                    }
+                    Some(Err(e)) => {
+                        log::error!("{}", e.to_string());
+                        let err = OpenAIError::CreateChat(e.to_string());
+                        return Some((Err(err), (body, vec![], true)));
+                    }
+                    None => exhausted = true,
+                }
+            }
+        },
+    )
+    .boxed()
+}
+
+/// Split a streamed response body into server-sent events.
+///
+/// * `body`: The raw byte stream of the response
+pub fn sse_events<S, B, E>(body: S) -> BoxStream<'static, Result<SseEvent, OpenAIError>>
+where
+    S: futures::Stream<Item = Result<B, E>> + Send + 'static,
+    B: AsRef<[u8]> + 'static,
+    E: std::fmt::Display + 'static,
+{
+    split_body(body, b"\n\n")
+        .filter_map(|block| {
+            future::ready(match block {
+                Ok(block) => SseEvent::parse(&block).map(Ok),
+                Err(e) => Some(Err(e)),
+            })
+        })
+        .boxed()
+}
+
+/// Parse a streamed body of newline delimited json objects (as used by ollama).
+///
+/// * `body`: The raw byte stream of the response
+pub fn json_lines<T, S, B, E>(body: S) -> BoxStream<'static, Result<T, OpenAIError>>
+where
+    T: DeserializeOwned + Send + 'static,
+    S: futures::Stream<Item = Result<B, E>> + Send + 'static,
+    B: AsRef<[u8]> + 'static,
+    E: std::fmt::Display + 'static,
+{
+    split_body(body, b"\n")
+        .filter(|line| future::ready(!matches!(line, Ok(line) if line.trim().is_empty())))
+        .map(|line| {
+            serde_json::from_str(line?.trim()).map_err(|e| {
+                log::error!("{}", e.to_string());
+                OpenAIError::Serialize(e.to_string())
+            })
+        })
+        .boxed()
+}
+
+/// Turn the server-sent events of an OpenAI style stream into typed chunks, stopping at the
+/// `[DONE]` marker.
+pub fn chat_chunks(
+    events: BoxStream<'static, Result<SseEvent, OpenAIError>>,
+) -> ChatCompletionStream {
+    events
+        .take_while(|event| {
+            let done = matches!(event, Ok(SseEvent { data, .. }) if data == DONE);
+            future::ready(!done)
+        })
+        .filter(|event| future::ready(!matches!(event, Ok(e) if e.data.is_empty())))
+        .map(|event| {
+            let event = event?;
+            serde_json::from_str::<ChatCompletionChunk>(&event.data).map_err(|e| {
+                // providers report failures part way through a stream as an error envelope
+                if event.data.contains("\"error\"") {
+                    let err = OpenAIError::from_body(None, &event.data);
+                    log::error!("{}", err.to_string());
+                    return err;
+                }
+                log::error!("{}", e.to_string());
+                OpenAIError::Serialize(e.to_string())
+            })
+        })
+        .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(parts: Vec<&'static str>) -> impl futures::Stream<Item = Result<&'static str, String>> {
+        stream::iter(parts.into_iter().map(Ok))
+    }
+
+    #[tokio::test]
+    pub async fn test_sse_events_across_chunk_boundaries() {
+        let events: Vec<SseEvent> = sse_events(body(vec![
+            ": keep-alive\n\nevent: mess",
+            "age_start\ndata: {\"a\":\r\n",
+            "data: 1}\r",
+            "\n\r\ndata: [DONE]",
+        ]))
+        .map(|e| e.unwrap())
+        .collect()
+        .await;
+
+        assert_eq!(
+            events,
+            vec![
+                SseEvent {
+                    event: Some("message_start".into()),
+                    data: "{\"a\":\n1}".into(),
+                },
+                SseEvent {
+                    event: None,
+                    data: "[DONE]".into(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    pub async fn test_json_lines() {
+        let values: Vec<serde_json::Value> =
+            json_lines(body(vec!["{\"a\": 1}\n{\"a\"", ": 2}\r\n\n{\"a\": 3}"]))
+                .map(|v| v.unwrap())
+                .collect()
+                .await;
+
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[2]["a"], 3);
+    }
+
+    #[tokio::test]
+    pub async fn test_chat_chunks() {
+        let chunk = r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[{"index":0,"delta":{"content":"hi"},"finish_reason":null}]}"#;
+        let usage = r#"{"id":"c","object":"chat.completion.chunk","created":1,"model":"m","choices":[],"usage":{"prompt_tokens":3,"completion_tokens":1,"total_tokens":4}}"#;
+        let raw = format!("data: {chunk}\n\ndata: {usage}\n\ndata: [DONE]\n\ndata: {chunk}\n\n");
+
+        let chunks: Vec<ChatCompletionChunk> =
+            chat_chunks(sse_events(stream::iter(vec![Ok::<_, String>(raw)])))
+                .map(|c| c.unwrap())
+                .collect()
+                .await;
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].choices[0].delta.content.as_deref(), Some("hi"));
+        assert_eq!(chunks[1].usage.as_ref().map(|u| u.total_tokens), Some(4));
+    }
+}
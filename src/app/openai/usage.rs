@@ -1,9 +1,25 @@
+use std::ops::AddAssign;
+
 use serde::{Deserialize, Serialize};
 
 #[serde_with::skip_serializing_none]
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Usage {
+    // some openai compatible servers (ollama, llama.cpp) omit or partially fill usage
+    #[serde(default)]
     pub prompt_tokens: u64,
     pub completion_tokens: Option<u64>,
+    #[serde(default)]
     pub total_tokens: u64,
 }
+
+impl AddAssign<&Usage> for Usage {
+    fn add_assign(&mut self, rhs: &Usage) {
+        self.prompt_tokens += rhs.prompt_tokens;
+        self.completion_tokens = match (self.completion_tokens, rhs.completion_tokens) {
+            (None, None) => None,
+            (lhs, rhs) => Some(lhs.unwrap_or(0) + rhs.unwrap_or(0)),
+        };
+        self.total_tokens += rhs.total_tokens;
+    }
+}
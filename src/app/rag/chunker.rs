@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 1000;
+pub const DEFAULT_CHUNK_OVERLAP: usize = 200;
+/// Largest chunk a collection may ask for, far beyond what any embedding model accepts
+pub const MAX_CHUNK_SIZE: usize = 100_000;
+
+/// How documents are split before they are embedded. Sizes are in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkOptions {
+    pub size: usize,
+    pub overlap: usize,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_CHUNK_SIZE,
+            overlap: DEFAULT_CHUNK_OVERLAP,
+        }
+    }
+}
+
+impl ChunkOptions {
+    pub fn new(size: usize, overlap: usize) -> Result<Self, String> {
+        if size == 0 || size > MAX_CHUNK_SIZE {
+            return Err(format!("chunk size must be between 1 and {MAX_CHUNK_SIZE}"));
+        }
+        if overlap >= size {
+            return Err("chunk overlap must be smaller than the chunk size".into());
+        }
+        Ok(Self { size, overlap })
+    }
+}
+
+/// Where a chunk may end, from most to least preferred
+const BREAKS: [&[&str]; 3] = [&["\n\n"], &[". ", "? ", "! ", ".\n", "\n"], &[" ", "\t"]];
+
+/// Find the end of a chunk starting at `start`. Chunks end on a paragraph, sentence or word
+/// boundary when there is one in the second half of the window, and are cut at `size` otherwise.
+fn chunk_end(chars: &[char], start: usize, size: usize) -> usize {
+    let limit = (start + size).min(chars.len());
+    if limit == chars.len() {
+        return limit;
+    }
+
+    let window: String = chars[start..limit].iter().collect();
+    let earliest = size / 2;
+    for separators in BREAKS {
+        let found = separators
+            .iter()
+            .filter_map(|separator| {
+                window
+                    .rfind(separator)
+                    .map(|at| window[..at + separator.len()].chars().count())
+            })
+            .filter(|&end| end > earliest)
+            .max();
+        if let Some(end) = found {
+            return start + end;
+        }
+    }
+    limit
+}
+
+/// Split text into overlapping chunks. Each chunk after the first starts `overlap` characters
+/// before the end of the previous one, moved forward to the start of a word.
+pub fn chunk(text: &str, options: ChunkOptions) -> Vec<String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut chunks = vec![];
+    let mut start = 0;
+
+    while start < chars.len() {
+        let end = chunk_end(&chars, start, options.size);
+        let content: String = chars[start..end].iter().collect();
+        let content = content.trim();
+        if !content.is_empty() {
+            chunks.push(content.to_owned());
+        }
+        if end == chars.len() {
+            break;
+        }
+
+        let mut next = end.saturating_sub(options.overlap).max(start + 1);
+        while next < end && !chars[next - 1].is_whitespace() {
+            next += 1;
+        }
+        start = next;
+    }
+
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_chunk_options() {
+        assert!(ChunkOptions::new(0, 0).is_err());
+        assert!(ChunkOptions::new(100, 100).is_err());
+        assert!(ChunkOptions::new(MAX_CHUNK_SIZE + 1, 0).is_err());
+        assert!(ChunkOptions::new(3_000_000_000, 0).is_err());
+        assert_eq!(
+            ChunkOptions::new(100, 20),
+            Ok(ChunkOptions {
+                size: 100,
+                overlap: 20
+            })
+        );
+    }
+
+    #[test]
+    pub fn test_chunk_short_text() {
+        assert_eq!(
+            chunk("  a short note  ", ChunkOptions::default()),
+            vec!["a short note"]
+        );
+        assert!(chunk("", ChunkOptions::default()).is_empty());
+    }
+
+    #[test]
+    pub fn test_chunk_on_boundaries() {
+        let text =
+            "First paragraph here.\n\nSecond paragraph is a bit longer. It has two sentences.";
+        let chunks = chunk(text, ChunkOptions::new(40, 0).unwrap());
+        assert_eq!(chunks[0], "First paragraph here.");
+        assert_eq!(chunks[1], "Second paragraph is a bit longer.");
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 40));
+    }
+
+    #[test]
+    pub fn test_chunk_overlap() {
+        let text = "one two three four five six seven eight nine ten eleven twelve";
+        let chunks = chunk(text, ChunkOptions::new(20, 8).unwrap());
+        assert!(chunks.len() > 3);
+        for pair in chunks.windows(2) {
+            let last_word = pair[0].split_whitespace().last().unwrap();
+            assert!(pair[1].contains(last_word), "{pair:?}");
+        }
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 20));
+    }
+
+    #[test]
+    pub fn test_chunk_multibyte_text() {
+        let text = "héllo wörld ".repeat(50);
+        let chunks = chunk(&text, ChunkOptions::new(30, 5).unwrap());
+        assert!(chunks.iter().all(|chunk| chunk.chars().count() <= 30));
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.starts_with('h') || chunk.starts_with('w')));
+    }
+}
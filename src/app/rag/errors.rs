@@ -0,0 +1,43 @@
+use thiserror::Error;
+
+use crate::app::{openai::errors::OpenAIError, storage::errors::DbError};
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum RagError {
+    #[error(transparent)]
+    Db(#[from] DbError),
+    #[error(transparent)]
+    Ai(#[from] OpenAIError),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    InvalidInput(String),
+    #[error(transparent)]
+    Extract(#[from] ExtractError),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ExtractError {
+    #[error("{0}")]
+    Unsupported(String),
+    #[error("{0}")]
+    Malformed(String),
+}
+
+impl From<sqlx::Error> for RagError {
+    fn from(e: sqlx::Error) -> Self {
+        let unique_violation = e
+            .as_database_error()
+            .and_then(|e| e.code())
+            .is_some_and(|code| code == "23505");
+        match unique_violation {
+            true => RagError::Conflict("a record with the same name already exists".into()),
+            false => {
+                log::error!("{}", e.to_string());
+                RagError::Db(DbError::Query(e.to_string()))
+            }
+        }
+    }
+}
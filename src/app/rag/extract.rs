@@ -0,0 +1,855 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read},
+};
+
+use pulldown_cmark::{Event, HeadingLevel, Parser, Tag};
+use quick_xml::events::{BytesStart, Event as XmlEvent};
+use scraper::{ElementRef, Html, Node, Selector};
+use serde::Serialize;
+
+use super::errors::ExtractError;
+
+/// Most bytes read from a single entry of a docx archive, so a small upload can't expand into an
+/// unbounded amount of xml
+const MAX_DOCX_ENTRY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Html elements that hold navigation, scripts and other page chrome rather than content
+const HTML_BOILERPLATE: [&str; 14] = [
+    "script", "style", "noscript", "template", "nav", "header", "footer", "aside", "form",
+    "button", "iframe", "svg", "canvas", "select",
+];
+
+/// Html elements that separate paragraphs
+const HTML_BLOCKS: [&str; 22] = [
+    "p",
+    "div",
+    "section",
+    "article",
+    "main",
+    "li",
+    "ul",
+    "ol",
+    "dl",
+    "dt",
+    "dd",
+    "table",
+    "tr",
+    "blockquote",
+    "pre",
+    "figure",
+    "figcaption",
+    "hr",
+    "address",
+    "details",
+    "summary",
+    "body",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DocumentFormat {
+    Pdf,
+    Docx,
+    Html,
+    Markdown,
+    Text,
+}
+
+impl DocumentFormat {
+    /// Detect the format of an upload from its content type, falling back to the file extension
+    /// since browsers send `application/octet-stream` for types they don't recognize
+    pub fn detect(filename: &str, content_type: Option<&str>) -> Option<Self> {
+        let mime = content_type
+            .and_then(|content_type| content_type.split(';').next())
+            .map(|mime| mime.trim().to_lowercase());
+        let from_mime = match mime.as_deref() {
+            Some("application/pdf") => Some(Self::Pdf),
+            Some("application/vnd.openxmlformats-officedocument.wordprocessingml.document") => {
+                Some(Self::Docx)
+            }
+            Some("text/html" | "application/xhtml+xml") => Some(Self::Html),
+            Some("text/markdown" | "text/x-markdown") => Some(Self::Markdown),
+            Some("text/plain") => Some(Self::Text),
+            _ => None,
+        };
+
+        let extension = filename
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_lowercase());
+        from_mime.or(match extension.as_deref() {
+            Some("pdf") => Some(Self::Pdf),
+            Some("docx") => Some(Self::Docx),
+            Some("html" | "htm" | "xhtml") => Some(Self::Html),
+            Some("md" | "markdown") => Some(Self::Markdown),
+            Some("txt" | "text") => Some(Self::Text),
+            _ => None,
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pdf => "pdf",
+            Self::Docx => "docx",
+            Self::Html => "html",
+            Self::Markdown => "markdown",
+            Self::Text => "text",
+        }
+    }
+}
+
+/// A page of a pdf, or a section under a heading for the other formats
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Segment {
+    pub page: Option<u32>,
+    pub heading: Option<String>,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Extracted {
+    pub title: Option<String>,
+    pub segments: Vec<Segment>,
+}
+
+impl Extracted {
+    /// The text of the whole document, with each segment under its heading
+    pub fn text(&self) -> String {
+        self.segments
+            .iter()
+            .map(|segment| match &segment.heading {
+                Some(heading) => format!("{heading}\n{}", segment.content),
+                None => segment.content.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// Extract the text of a document, split into pages or sections
+pub fn extract(format: DocumentFormat, bytes: &[u8]) -> Result<Extracted, ExtractError> {
+    match format {
+        DocumentFormat::Pdf => extract_pdf(bytes),
+        DocumentFormat::Docx => extract_docx(bytes),
+        DocumentFormat::Html => Ok(extract_html(&decode(bytes)?)),
+        DocumentFormat::Markdown => Ok(extract_markdown(&decode(bytes)?)),
+        DocumentFormat::Text => {
+            let content = normalize(&decode(bytes)?);
+            Ok(Extracted {
+                title: None,
+                segments: match content.is_empty() {
+                    true => vec![],
+                    false => vec![Segment {
+                        page: None,
+                        heading: None,
+                        content,
+                    }],
+                },
+            })
+        }
+    }
+}
+
+fn decode(bytes: &[u8]) -> Result<String, ExtractError> {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| ExtractError::Malformed("text documents must be utf-8".into()))
+}
+
+/// Normalize whitespace: runs of spaces collapse to one, lines are trimmed, and blank lines
+/// collapse to a single paragraph break. Control characters other than newlines are dropped.
+pub fn normalize(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let mut out = String::with_capacity(text.len());
+    let mut blank = false;
+
+    for line in text.split('\n') {
+        let line = line
+            .split(|c: char| c.is_whitespace() || c.is_control())
+            .filter(|word| !word.is_empty())
+            .collect::<Vec<_>>()
+            .join(" ");
+        if line.is_empty() {
+            blank = !out.is_empty();
+            continue;
+        }
+        if !out.is_empty() {
+            out.push_str(if blank { "\n\n" } else { "\n" });
+        }
+        out.push_str(&line);
+        blank = false;
+    }
+    out
+}
+
+/// Collects text into segments, starting a new one at each heading
+#[derive(Default)]
+struct Sections {
+    segments: Vec<Segment>,
+    heading: Option<String>,
+    text: String,
+}
+
+impl Sections {
+    fn push(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    fn paragraph(&mut self) {
+        self.text.push_str("\n\n");
+    }
+
+    fn heading(&mut self, heading: &str) {
+        self.flush();
+        self.heading = Some(normalize(heading)).filter(|heading| !heading.is_empty());
+    }
+
+    /// Sections that turn out to be empty are dropped along with their heading
+    fn flush(&mut self) {
+        let content = normalize(&self.text);
+        let heading = self.heading.take();
+        if !content.is_empty() {
+            self.segments.push(Segment {
+                page: None,
+                heading,
+                content,
+            });
+        }
+        self.text.clear();
+    }
+
+    fn finish(mut self) -> Vec<Segment> {
+        self.flush();
+        self.segments
+    }
+}
+
+fn extract_pdf(bytes: &[u8]) -> Result<Extracted, ExtractError> {
+    let document = lopdf::Document::load_mem(bytes)
+        .map_err(|e| ExtractError::Malformed(format!("unable to read pdf: {e}")))?;
+    if document.is_encrypted() {
+        return Err(ExtractError::Unsupported(
+            "encrypted pdfs are not supported".into(),
+        ));
+    }
+
+    let pages: Vec<(u32, String)> = document
+        .get_pages()
+        .into_keys()
+        .map(|page| {
+            let text = document.extract_text(&[page]).unwrap_or_else(|e| {
+                log::warn!("unable to extract text from pdf page {page}: {e}");
+                String::new()
+            });
+            (page, normalize(&text))
+        })
+        .collect();
+
+    let texts: Vec<String> = pages.iter().map(|(_, text)| text.clone()).collect();
+    let segments = pages
+        .into_iter()
+        .zip(strip_running_lines(texts))
+        .filter(|(_, content)| !content.is_empty())
+        .map(|((page, _), content)| Segment {
+            page: Some(page),
+            heading: None,
+            content,
+        })
+        .collect();
+
+    Ok(Extracted {
+        title: None,
+        segments,
+    })
+}
+
+/// A line with its digits masked, so "Page 3 of 10" and "Page 4 of 10" count as the same line
+fn line_pattern(line: &str) -> String {
+    line.chars()
+        .map(|c| if c.is_ascii_digit() { '#' } else { c })
+        .collect()
+}
+
+/// Remove running headers, footers and page numbers from pdf pages. A line near the top or bottom
+/// of a page is dropped when the same line shows up there on at least half of the pages.
+fn strip_running_lines(pages: Vec<String>) -> Vec<String> {
+    const EDGE_LINES: usize = 2;
+
+    let edges = |page: &String| -> Vec<String> {
+        let lines: Vec<&str> = page.lines().filter(|line| !line.is_empty()).collect();
+        let bottom = lines.len().saturating_sub(EDGE_LINES).max(EDGE_LINES);
+        lines
+            .iter()
+            .take(EDGE_LINES)
+            .chain(lines.iter().skip(bottom))
+            .map(|line| line_pattern(line))
+            .collect()
+    };
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    if pages.len() >= 3 {
+        for page in &pages {
+            let mut patterns = edges(page);
+            patterns.sort();
+            patterns.dedup();
+            for pattern in patterns {
+                *counts.entry(pattern).or_default() += 1;
+            }
+        }
+    }
+    let running = |line: &str| {
+        let pattern = line_pattern(line);
+        pattern.chars().all(|c| c == '#')
+            || counts
+                .get(&pattern)
+                .is_some_and(|&count| count * 2 >= pages.len())
+    };
+
+    pages
+        .iter()
+        .map(|page| {
+            let lines: Vec<&str> = page.lines().collect();
+            let last = lines.len().saturating_sub(EDGE_LINES);
+            let kept: Vec<&str> = lines
+                .iter()
+                .enumerate()
+                .filter(|&(i, line)| {
+                    let edge = i < EDGE_LINES || i >= last;
+                    !(edge && !line.is_empty() && running(line))
+                })
+                .map(|(_, line)| *line)
+                .collect();
+            normalize(&kept.join("\n"))
+        })
+        .collect()
+}
+
+fn read_docx_entry(
+    archive: &mut zip::ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<Option<String>, ExtractError> {
+    let entry = match archive.by_name(name) {
+        Ok(entry) => entry,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(ExtractError::Malformed(format!("unable to read docx: {e}"))),
+    };
+    let mut xml = String::new();
+    entry
+        .take(MAX_DOCX_ENTRY_BYTES)
+        .read_to_string(&mut xml)
+        .map_err(|e| ExtractError::Malformed(format!("unable to read docx: {e}")))?;
+    Ok(Some(xml))
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .flatten()
+        .find(|attribute| attribute.key.local_name().as_ref() == name)
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.into_owned())
+}
+
+fn extract_docx(bytes: &[u8]) -> Result<Extracted, ExtractError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(bytes))
+        .map_err(|e| ExtractError::Malformed(format!("unable to read docx: {e}")))?;
+    let body = read_docx_entry(&mut archive, "word/document.xml")?
+        .ok_or_else(|| ExtractError::Malformed("docx has no word/document.xml".into()))?;
+    let title =
+        read_docx_entry(&mut archive, "docProps/core.xml")?.and_then(|core| docx_title(&core));
+
+    Ok(Extracted {
+        title,
+        segments: docx_sections(&body)?,
+    })
+}
+
+/// The `dc:title` from a docx's core properties
+fn docx_title(core: &str) -> Option<String> {
+    let mut reader = quick_xml::Reader::from_str(core);
+    let mut in_title = false;
+    loop {
+        match reader.read_event() {
+            Ok(XmlEvent::Start(e)) => in_title = e.local_name().as_ref() == b"title",
+            Ok(XmlEvent::Text(e)) if in_title => {
+                let title = normalize(&e.unescape().ok()?);
+                return Some(title).filter(|title| !title.is_empty());
+            }
+            Ok(XmlEvent::End(_)) => in_title = false,
+            Ok(XmlEvent::Eof) | Err(_) => return None,
+            _ => {}
+        }
+    }
+}
+
+/// Split the body of a docx into sections at paragraphs styled as headings
+fn docx_sections(body: &str) -> Result<Vec<Segment>, ExtractError> {
+    let mut reader = quick_xml::Reader::from_str(body);
+    let mut sections = Sections::default();
+    let mut paragraph = String::new();
+    let mut style: Option<String> = None;
+    let mut in_text = false;
+
+    loop {
+        let event = reader
+            .read_event()
+            .map_err(|e| ExtractError::Malformed(format!("unable to read docx: {e}")))?;
+        match event {
+            XmlEvent::Start(e) | XmlEvent::Empty(e) => match e.local_name().as_ref() {
+                b"p" => {
+                    paragraph.clear();
+                    style = None;
+                }
+                b"pStyle" => style = attribute(&e, b"val"),
+                b"t" => in_text = true,
+                b"tab" => paragraph.push('\t'),
+                b"br" | b"cr" => paragraph.push('\n'),
+                _ => {}
+            },
+            XmlEvent::Text(e) if in_text => {
+                let text = e
+                    .unescape()
+                    .map_err(|e| ExtractError::Malformed(format!("unable to read docx: {e}")))?;
+                paragraph.push_str(&text);
+            }
+            XmlEvent::End(e) => match e.local_name().as_ref() {
+                b"t" => in_text = false,
+                b"p" => {
+                    let heading = style
+                        .as_deref()
+                        .is_some_and(|style| style.starts_with("Heading") || style == "Title");
+                    match heading {
+                        true => sections.heading(&paragraph),
+                        false => {
+                            sections.push(&paragraph);
+                            sections.paragraph();
+                        }
+                    }
+                    paragraph.clear();
+                }
+                _ => {}
+            },
+            XmlEvent::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(sections.finish())
+}
+
+fn is_boilerplate(element: &ElementRef) -> bool {
+    let value = element.value();
+    HTML_BOILERPLATE.contains(&value.name())
+        || value.attr("hidden").is_some()
+        || value.attr("aria-hidden") == Some("true")
+        || matches!(
+            value.attr("role"),
+            Some("navigation" | "banner" | "contentinfo" | "search")
+        )
+}
+
+/// A step of the walk over an html tree
+enum Walk<N> {
+    Visit(N),
+    /// The end of a block element, which closes its paragraph
+    EndBlock,
+}
+
+/// Collect the text under `root` into sections. The walk keeps its own stack rather than
+/// recursing, so deeply nested markup can't overflow the thread's stack.
+fn walk_html(root: ElementRef, sections: &mut Sections) {
+    let mut stack: Vec<_> = root.children().rev().map(Walk::Visit).collect();
+    while let Some(step) = stack.pop() {
+        let node = match step {
+            Walk::Visit(node) => node,
+            Walk::EndBlock => {
+                sections.paragraph();
+                continue;
+            }
+        };
+        match node.value() {
+            Node::Text(text) => {
+                // whitespace in markup is insignificant, paragraph breaks come from block elements
+                let text: String = text
+                    .chars()
+                    .map(|c| if c.is_whitespace() { ' ' } else { c })
+                    .collect();
+                sections.push(&text);
+            }
+            Node::Element(_) => {
+                let Some(element) = ElementRef::wrap(node) else {
+                    continue;
+                };
+                let name = element.value().name();
+                if is_boilerplate(&element) {
+                    continue;
+                }
+                if matches!(name, "h1" | "h2" | "h3" | "h4" | "h5" | "h6") {
+                    sections.heading(&element.text().collect::<String>());
+                } else if name == "br" {
+                    sections.push("\n");
+                } else {
+                    if HTML_BLOCKS.contains(&name) {
+                        sections.paragraph();
+                        stack.push(Walk::EndBlock);
+                    }
+                    stack.extend(node.children().rev().map(Walk::Visit));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn extract_html(html: &str) -> Extracted {
+    let document = Html::parse_document(html);
+    let select = |selector: &str| {
+        let selector = Selector::parse(selector).expect("invalid selector");
+        document.select(&selector).next()
+    };
+
+    let title = select("title")
+        .or_else(|| select("h1"))
+        .map(|title| normalize(&title.text().collect::<String>()))
+        .filter(|title| !title.is_empty());
+    // prefer the main content of the page when it is marked up
+    let root = select("main")
+        .or_else(|| select("article"))
+        .or_else(|| select("body"))
+        .unwrap_or_else(|| document.root_element());
+
+    let mut sections = Sections::default();
+    walk_html(root, &mut sections);
+    Extracted {
+        title,
+        segments: sections.finish(),
+    }
+}
+
+/// Split yaml front matter off a markdown document, returning its title if it has one
+fn front_matter(markdown: &str) -> (Option<String>, &str) {
+    let Some(rest) = markdown.strip_prefix("---\n") else {
+        return (None, markdown);
+    };
+    let Some(end) = rest.find("\n---") else {
+        return (None, markdown);
+    };
+
+    let title = rest[..end].lines().find_map(|line| {
+        let value = line
+            .strip_prefix("title:")?
+            .trim()
+            .trim_matches(['"', '\'']);
+        Some(value.to_owned()).filter(|value| !value.is_empty())
+    });
+    let body = rest[end + 4..].trim_start_matches(|c| c != '\n');
+    (title, body)
+}
+
+fn extract_markdown(markdown: &str) -> Extracted {
+    let markdown = markdown.replace("\r\n", "\n");
+    let (mut title, body) = front_matter(&markdown);
+
+    let mut sections = Sections::default();
+    let mut heading: Option<(HeadingLevel, String)> = None;
+    for event in Parser::new(body) {
+        match event {
+            Event::Start(Tag::Heading(level, ..)) => heading = Some((level, String::new())),
+            Event::End(Tag::Heading(..)) => {
+                if let Some((level, text)) = heading.take() {
+                    if level == HeadingLevel::H1 && title.is_none() {
+                        title = Some(normalize(&text)).filter(|title| !title.is_empty());
+                    }
+                    sections.heading(&text);
+                }
+            }
+            Event::Text(text) | Event::Code(text) => match heading.as_mut() {
+                Some((_, heading)) => heading.push_str(&text),
+                None => sections.push(&text),
+            },
+            Event::SoftBreak => sections.push(" "),
+            Event::HardBreak => sections.push("\n"),
+            Event::End(
+                Tag::Paragraph
+                | Tag::Item
+                | Tag::CodeBlock(_)
+                | Tag::BlockQuote
+                | Tag::TableRow
+                | Tag::TableHead,
+            ) => sections.paragraph(),
+            Event::End(Tag::TableCell) => sections.push(" | "),
+            // raw html and rules are presentation, not content
+            _ => {}
+        }
+    }
+
+    Extracted {
+        title,
+        segments: sections.finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn segment(heading: Option<&str>, content: &str) -> Segment {
+        Segment {
+            page: None,
+            heading: heading.map(str::to_owned),
+            content: content.into(),
+        }
+    }
+
+    #[test]
+    pub fn test_detect_format() {
+        assert_eq!(
+            DocumentFormat::detect("report.bin", Some("application/pdf")),
+            Some(DocumentFormat::Pdf)
+        );
+        assert_eq!(
+            DocumentFormat::detect("notes.MD", Some("application/octet-stream")),
+            Some(DocumentFormat::Markdown)
+        );
+        assert_eq!(
+            DocumentFormat::detect("page", Some("text/html; charset=utf-8")),
+            Some(DocumentFormat::Html)
+        );
+        assert_eq!(DocumentFormat::detect("image.png", None), None);
+    }
+
+    #[test]
+    pub fn test_normalize() {
+        assert_eq!(
+            normalize("  one\u{a0}\u{a0}two\t three \r\n\r\n\n\nfour\u{7}\nfive  "),
+            "one two three\n\nfour\nfive"
+        );
+        assert_eq!(normalize(" \n \n"), "");
+    }
+
+    #[test]
+    pub fn test_extract_html() {
+        let html = r#"<html><head><title>Volunteer Guide</title><style>p{}</style></head>
+            <body>
+              <nav><a href="/">Home</a> <a href="/about">About</a></nav>
+              <main>
+                <p>Welcome   to the <b>guide</b>.</p>
+                <h2>Signing up</h2>
+                <p>Fill in the form.<br>It takes a minute.</p>
+                <div aria-hidden="true">decoration</div>
+                <script>track()</script>
+              </main>
+              <footer>Copyright</footer>
+            </body></html>"#;
+        let extracted = extract(DocumentFormat::Html, html.as_bytes()).unwrap();
+
+        assert_eq!(extracted.title.as_deref(), Some("Volunteer Guide"));
+        assert_eq!(
+            extracted.segments,
+            vec![
+                segment(None, "Welcome to the guide."),
+                segment(Some("Signing up"), "Fill in the form.\nIt takes a minute."),
+            ]
+        );
+        assert_eq!(
+            extracted.text(),
+            "Welcome to the guide.\n\nSigning up\nFill in the form.\nIt takes a minute."
+        );
+    }
+
+    #[test]
+    pub fn test_extract_deeply_nested_html() {
+        const DEPTH: usize = 5_000;
+        let html = format!(
+            "<html><body>{}deep{}</body></html>",
+            "<div>".repeat(DEPTH),
+            "</div>".repeat(DEPTH)
+        );
+        // a small stack stands in for much deeper documents on the 2MiB blocking threads uploads
+        // are extracted on, since parsing deeper documents is slow
+        let extracted = std::thread::Builder::new()
+            .stack_size(256 * 1024)
+            .spawn(move || extract(DocumentFormat::Html, html.as_bytes()))
+            .unwrap()
+            .join()
+            .unwrap()
+            .unwrap();
+        assert_eq!(extracted.text(), "deep");
+    }
+
+    #[test]
+    pub fn test_extract_markdown() {
+        let markdown = "---\ntitle: \"Onboarding\"\n---\nIntro line one\nline two.\n\n\
+                        # Setup\n\nInstall `cargo`.\n\n## Empty\n\n## Run\n\n- first\n- second\n";
+        let extracted = extract(DocumentFormat::Markdown, markdown.as_bytes()).unwrap();
+
+        assert_eq!(extracted.title.as_deref(), Some("Onboarding"));
+        assert_eq!(
+            extracted.segments,
+            vec![
+                segment(None, "Intro line one line two."),
+                segment(Some("Setup"), "Install cargo."),
+                segment(Some("Run"), "first\n\nsecond"),
+            ]
+        );
+    }
+
+    #[test]
+    pub fn test_extract_docx() {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <w:document xmlns:w="http://schemas.openxmlformats.org/wordprocessingml/2006/main">
+              <w:body>
+                <w:p><w:pPr><w:pStyle w:val="Heading1"/></w:pPr><w:r><w:t>Program overview</w:t></w:r></w:p>
+                <w:p><w:r><w:t xml:space="preserve">Teams meet </w:t></w:r><w:r><w:t>weekly &amp; report.</w:t></w:r></w:p>
+                <w:p><w:r><w:t>Second</w:t><w:br/><w:t>line</w:t></w:r></w:p>
+              </w:body>
+            </w:document>"#;
+        let core = r#"<cp:coreProperties xmlns:cp="cp" xmlns:dc="dc"><dc:title>Handbook</dc:title></cp:coreProperties>"#;
+
+        let mut bytes = vec![];
+        {
+            let mut archive = zip::ZipWriter::new(Cursor::new(&mut bytes));
+            let options = zip::write::FileOptions::default();
+            archive.start_file("word/document.xml", options).unwrap();
+            archive.write_all(body.as_bytes()).unwrap();
+            archive.start_file("docProps/core.xml", options).unwrap();
+            archive.write_all(core.as_bytes()).unwrap();
+            archive.finish().unwrap();
+        }
+        let extracted = extract(DocumentFormat::Docx, &bytes).unwrap();
+
+        assert_eq!(extracted.title.as_deref(), Some("Handbook"));
+        assert_eq!(
+            extracted.segments,
+            vec![segment(
+                Some("Program overview"),
+                "Teams meet weekly & report.\n\nSecond\nline"
+            )]
+        );
+        assert!(matches!(
+            extract(DocumentFormat::Docx, b"not a zip"),
+            Err(ExtractError::Malformed(_))
+        ));
+    }
+
+    /// A pdf with a line of text per text object on each page
+    fn pdf(pages: &[&[&str]]) -> Vec<u8> {
+        use lopdf::{
+            content::{Content, Operation},
+            dictionary, Document, Object, Stream,
+        };
+
+        let mut document = Document::with_version("1.5");
+        let pages_id = document.new_object_id();
+        let font_id = document.add_object(dictionary! {
+            "Type" => "Font",
+            "Subtype" => "Type1",
+            "BaseFont" => "Courier",
+        });
+        let resources_id = document.add_object(dictionary! {
+            "Font" => dictionary! { "F1" => font_id },
+        });
+
+        let kids: Vec<Object> = pages
+            .iter()
+            .map(|lines| {
+                let operations: Vec<Operation> = lines
+                    .iter()
+                    .flat_map(|line| {
+                        [
+                            Operation::new("BT", vec![]),
+                            Operation::new("Tf", vec!["F1".into(), 12.into()]),
+                            Operation::new("Tj", vec![Object::string_literal(*line)]),
+                            Operation::new("ET", vec![]),
+                        ]
+                    })
+                    .collect();
+                let content = Content { operations }.encode().unwrap();
+                let content_id = document.add_object(Stream::new(dictionary! {}, content));
+                document
+                    .add_object(dictionary! {
+                        "Type" => "Page",
+                        "Parent" => pages_id,
+                        "Contents" => content_id,
+                    })
+                    .into()
+            })
+            .collect();
+        document.objects.insert(
+            pages_id,
+            Object::Dictionary(dictionary! {
+                "Type" => "Pages",
+                "Count" => kids.len() as i64,
+                "Kids" => kids,
+                "Resources" => resources_id,
+                "MediaBox" => vec![0.into(), 0.into(), 595.into(), 842.into()],
+            }),
+        );
+        let catalog_id = document.add_object(dictionary! {
+            "Type" => "Catalog",
+            "Pages" => pages_id,
+        });
+        document.trailer.set("Root", catalog_id);
+
+        let mut bytes = vec![];
+        document.save_to(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    pub fn test_extract_pdf() {
+        let bytes = pdf(&[
+            &["Volunteer Handbook", "Welcome aboard.", "1"],
+            &["Volunteer Handbook", "Weekly   check-ins.", "2"],
+            &[],
+            &["Volunteer Handbook", "Offboarding steps.", "4"],
+        ]);
+        let extracted = extract(DocumentFormat::Pdf, &bytes).unwrap();
+
+        let pages: Vec<(Option<u32>, &str)> = extracted
+            .segments
+            .iter()
+            .map(|segment| (segment.page, segment.content.as_str()))
+            .collect();
+        assert_eq!(
+            pages,
+            vec![
+                (Some(1), "Welcome aboard."),
+                (Some(2), "Weekly check-ins."),
+                (Some(4), "Offboarding steps."),
+            ]
+        );
+        assert!(matches!(
+            extract(DocumentFormat::Pdf, b"%PDF-1.5 truncated"),
+            Err(ExtractError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    pub fn test_strip_running_lines() {
+        let words = ["alpha", "beta", "gamma", "delta"];
+        let pages = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| {
+                format!(
+                    "Annual Report\n{word} starts.\n{word} continues.\n{word} ends.\n{}",
+                    i + 1
+                )
+            })
+            .collect();
+        let expected: Vec<String> = words
+            .iter()
+            .map(|word| format!("{word} starts.\n{word} continues.\n{word} ends."))
+            .collect();
+        assert_eq!(strip_running_lines(pages), expected);
+
+        let short = vec![
+            "Annual Report\nonly two pages".to_owned(),
+            "Annual Report".to_owned(),
+        ];
+        assert_eq!(strip_running_lines(short.clone()), short);
+    }
+}
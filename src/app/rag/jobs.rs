@@ -0,0 +1,234 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::json;
+use sqlx::PgPool;
+use tokio::sync::OwnedSemaphorePermit;
+use uuid::Uuid;
+
+use crate::app::openai::OpenAIClient;
+
+use super::{
+    errors::{ExtractError, RagError},
+    extract::{self, DocumentFormat},
+    knowledge_base::KnowledgeBase,
+    store::{Collection, NewDocument},
+};
+
+/// Uploads ingested at once when INGESTION_MAX_JOBS isn't set
+pub const DEFAULT_MAX_INGESTION_JOBS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, sqlx::Type)]
+#[sqlx(type_name = "ingestion_status", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum IngestionStatus {
+    Pending,
+    Processing,
+    Completed,
+    Failed,
+}
+
+/// An uploaded file being extracted and embedded into a collection in the background
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct IngestionJob {
+    pub id: Uuid,
+    pub collection_id: Uuid,
+    /// The document the upload became, once the job completes
+    pub document_id: Option<Uuid>,
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub format: String,
+    pub size: i64,
+    pub status: IngestionStatus,
+    pub error: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Upload {
+    pub filename: String,
+    pub content_type: Option<String>,
+    pub format: DocumentFormat,
+    pub title: Option<String>,
+    pub source: Option<String>,
+    pub bytes: Vec<u8>,
+}
+
+const JOB_COLUMNS: &str = "id, collection_id, document_id, filename, content_type, format, size, \
+     status, error, started_at, finished_at, created_at, updated_at";
+
+pub async fn create_job(
+    sql: &PgPool,
+    collection_id: Uuid,
+    upload: &Upload,
+) -> Result<IngestionJob, RagError> {
+    let query = format!(
+        "insert into ingestion_jobs (collection_id, filename, content_type, format, size) \
+         values ($1, $2, $3, $4, $5) returning {JOB_COLUMNS}"
+    );
+    Ok(sqlx::query_as(&query)
+        .bind(collection_id)
+        .bind(&upload.filename)
+        .bind(&upload.content_type)
+        .bind(upload.format.as_str())
+        .bind(upload.bytes.len() as i64)
+        .fetch_one(sql)
+        .await?)
+}
+
+pub async fn get_job(
+    sql: &PgPool,
+    collection_id: Uuid,
+    id: Uuid,
+) -> Result<IngestionJob, RagError> {
+    let query =
+        format!("select {JOB_COLUMNS} from ingestion_jobs where collection_id = $1 and id = $2");
+    sqlx::query_as(&query)
+        .bind(collection_id)
+        .bind(id)
+        .fetch_optional(sql)
+        .await?
+        .ok_or_else(|| RagError::NotFound(format!("no upload with id {id}")))
+}
+
+pub async fn list_jobs(sql: &PgPool, collection_id: Uuid) -> Result<Vec<IngestionJob>, RagError> {
+    let query = format!(
+        "select {JOB_COLUMNS} from ingestion_jobs where collection_id = $1 \
+         order by created_at desc"
+    );
+    Ok(sqlx::query_as(&query)
+        .bind(collection_id)
+        .fetch_all(sql)
+        .await?)
+}
+
+/// Jobs only run in the process that accepted the upload, so any still pending or processing at
+/// startup were interrupted by a restart and will never finish
+pub async fn fail_interrupted_jobs(sql: &PgPool) -> Result<u64, RagError> {
+    let failed = sqlx::query(
+        "update ingestion_jobs set status = 'failed', error = 'interrupted by a server restart', \
+         finished_at = current_timestamp where status in ('pending', 'processing')",
+    )
+    .execute(sql)
+    .await?;
+    Ok(failed.rows_affected())
+}
+
+async fn set_status(
+    sql: &PgPool,
+    id: Uuid,
+    status: IngestionStatus,
+    document_id: Option<Uuid>,
+    error: Option<String>,
+) -> Result<(), RagError> {
+    sqlx::query(
+        "update ingestion_jobs set status = $2, document_id = $3, error = $4, \
+         started_at = case when $2 = 'processing' then current_timestamp else started_at end, \
+         finished_at = case when $2 in ('completed', 'failed') then current_timestamp end \
+         where id = $1",
+    )
+    .bind(id)
+    .bind(status)
+    .bind(document_id)
+    .bind(error)
+    .execute(sql)
+    .await?;
+    Ok(())
+}
+
+/// Extract the text of an upload and add it to the collection as a document
+async fn ingest(
+    sql: &PgPool,
+    embeddings: &OpenAIClient,
+    collection: &Collection,
+    upload: Upload,
+) -> Result<Uuid, RagError> {
+    let Upload {
+        filename,
+        content_type,
+        format,
+        title,
+        source,
+        bytes,
+    } = upload;
+
+    // extraction is cpu bound, so it runs off of the async workers
+    let extracted = tokio::task::spawn_blocking(move || extract::extract(format, &bytes))
+        .await
+        .map_err(|e| {
+            log::error!("{}", e.to_string());
+            ExtractError::Malformed("extraction stopped unexpectedly".into())
+        })??;
+    if extracted.segments.is_empty() {
+        return Err(RagError::InvalidInput(
+            "no text could be extracted from the file".into(),
+        ));
+    }
+
+    let document = NewDocument {
+        title: title
+            .or(extracted.title.clone())
+            .unwrap_or(filename.clone()),
+        source,
+        metadata: json!({
+            "filename": filename,
+            "content_type": content_type,
+            "format": format,
+            "segments": extracted.segments.len(),
+        }),
+        content: extracted.text(),
+        segments: extracted.segments,
+    };
+    let ingested = KnowledgeBase::new(sql, embeddings)
+        .add_document(collection, document)
+        .await?;
+    Ok(ingested.document.id)
+}
+
+/// Run an ingestion job on a background task. Its outcome is recorded on the job.
+pub fn spawn(
+    sql: PgPool,
+    embeddings: OpenAIClient,
+    collection: Collection,
+    job: &IngestionJob,
+    upload: Upload,
+    permit: OwnedSemaphorePermit,
+) {
+    let id = job.id;
+    tokio::spawn(async move {
+        // released once the job has finished with the upload
+        let _permit = permit;
+        let result = async {
+            set_status(&sql, id, IngestionStatus::Processing, None, None).await?;
+            ingest(&sql, &embeddings, &collection, upload).await
+        }
+        .await;
+
+        let status = match result {
+            Ok(document_id) => {
+                set_status(
+                    &sql,
+                    id,
+                    IngestionStatus::Completed,
+                    Some(document_id),
+                    None,
+                )
+                .await
+            }
+            Err(e) => {
+                log::warn!("ingestion job {id} failed: {e}");
+                let error = match e {
+                    RagError::Db(_) => "unable to store the document".to_owned(),
+                    RagError::Ai(e) => e.public_message(),
+                    e => e.to_string(),
+                };
+                set_status(&sql, id, IngestionStatus::Failed, None, Some(error)).await
+            }
+        };
+        if let Err(e) = status {
+            log::error!("unable to record the outcome of ingestion job {id}: {e}");
+        }
+    });
+}
@@ -0,0 +1,362 @@
+use serde::Serialize;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::app::{
+    llm::provider::ChatProvider,
+    openai::{
+        chat::{ChatMessage, ChatOptions, ChatRole},
+        embeddings::EmbeddingOptions,
+        errors::OpenAIError,
+        usage::Usage,
+        OpenAIClient,
+    },
+};
+
+use super::{
+    chunker,
+    errors::RagError,
+    search::{self, SearchHit, SearchMode, MAX_RERANK_CANDIDATES},
+    store::{self, Collection, Document, NewDocument, ScoredChunk},
+};
+
+pub const DEFAULT_TOP_K: usize = 5;
+pub const MAX_TOP_K: usize = 50;
+
+/// A document that was chunked, embedded and stored
+#[derive(Debug, Serialize)]
+pub struct IngestedDocument {
+    #[serde(flatten)]
+    pub document: Document,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueryResult {
+    pub results: Vec<ScoredChunk>,
+    pub usage: Usage,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions {
+    pub mode: SearchMode,
+    pub top_k: usize,
+    /// Chat model used to rerank the fused candidates. Results are not reranked without one.
+    pub rerank_model: Option<String>,
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Serialize)]
+pub struct SearchResult {
+    pub results: Vec<SearchHit>,
+    /// Embedding usage of the query. Zero for lexical searches.
+    pub usage: Usage,
+    pub rerank_usage: Option<Usage>,
+}
+
+/// A passage given to the model as context. `index` is the number the model cites it by.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Citation {
+    pub index: usize,
+    pub chunk_id: Uuid,
+    pub document_id: Uuid,
+    pub document_title: String,
+    pub position: i32,
+    pub score: f64,
+}
+
+/// Document collections searched by embedding similarity, or by words alone when no embeddings
+/// provider is configured
+pub struct KnowledgeBase<'a> {
+    sql: &'a PgPool,
+    embeddings: Option<&'a OpenAIClient>,
+    reranker: Option<&'a dyn ChatProvider>,
+}
+
+impl<'a> KnowledgeBase<'a> {
+    pub fn new(sql: &'a PgPool, embeddings: &'a OpenAIClient) -> Self {
+        Self {
+            sql,
+            embeddings: Some(embeddings),
+            reranker: None,
+        }
+    }
+
+    /// A knowledge base without an embeddings provider, which can only be searched by words
+    pub fn lexical(sql: &'a PgPool) -> Self {
+        Self {
+            sql,
+            embeddings: None,
+            reranker: None,
+        }
+    }
+
+    /// Use a chat provider to rerank search results
+    pub fn with_reranker(mut self, reranker: &'a dyn ChatProvider) -> Self {
+        self.reranker = Some(reranker);
+        self
+    }
+
+    async fn embed(
+        &self,
+        collection: &Collection,
+        input: Vec<String>,
+    ) -> Result<(Vec<Vec<f32>>, Usage), RagError> {
+        let embeddings = self.embeddings.ok_or_else(|| {
+            OpenAIError::Unavailable("no embeddings provider is configured".into())
+        })?;
+        let opts = EmbeddingOptions {
+            dimensions: collection.dimensions.map(|dimensions| dimensions as u32),
+            ..EmbeddingOptions::new(&collection.embedding_model, input)
+        };
+        let res = embeddings.create_embeddings(&opts).await?;
+        let vectors = res
+            .data
+            .iter()
+            .map(|embedding| embedding.embedding.to_floats())
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((vectors, res.usage))
+    }
+
+    /// Chunk a document with the collection's settings, embed the chunks and store them
+    pub async fn add_document(
+        &self,
+        collection: &Collection,
+        document: NewDocument,
+    ) -> Result<IngestedDocument, RagError> {
+        let chunks = chunker::chunk(&document.content, collection.chunk_options());
+        if chunks.is_empty() {
+            return Err(RagError::InvalidInput("document has no content".into()));
+        }
+
+        let (embeddings, usage) = self.embed(collection, chunks.clone()).await?;
+        let document =
+            store::insert_document(self.sql, collection.id, &document, &chunks, &embeddings)
+                .await?;
+        Ok(IngestedDocument { document, usage })
+    }
+
+    /// The chunks of the collection most similar to the query
+    pub async fn query(
+        &self,
+        collection: &Collection,
+        query: &str,
+        top_k: usize,
+    ) -> Result<QueryResult, RagError> {
+        if query.trim().is_empty() {
+            return Err(RagError::InvalidInput("query must not be empty".into()));
+        }
+
+        let (embeddings, usage) = self.embed(collection, vec![query.to_owned()]).await?;
+        let embedding = embeddings
+            .first()
+            .ok_or_else(|| RagError::InvalidInput("query could not be embedded".into()))?;
+        let results = store::search(
+            self.sql,
+            collection.id,
+            embedding,
+            top_k.clamp(1, MAX_TOP_K),
+        )
+        .await?;
+        Ok(QueryResult { results, usage })
+    }
+
+    /// Search a collection by embedding similarity, by the query's words, or by both with the
+    /// rankings fused. Each retriever contributes a few times `top_k` candidates so that chunks
+    /// ranked lower by one of them can still make it into the results. Only the best
+    /// `MAX_RERANK_CANDIDATES` are reranked, the rest keep their fused order after them.
+    pub async fn search(
+        &self,
+        collection: &Collection,
+        query: &str,
+        opts: &SearchOptions,
+    ) -> Result<SearchResult, RagError> {
+        if query.trim().is_empty() {
+            return Err(RagError::InvalidInput("query must not be empty".into()));
+        }
+        let reranker = match (&opts.rerank_model, self.reranker) {
+            (Some(model), Some(reranker)) => Some((model, reranker)),
+            (Some(_), None) => {
+                return Err(RagError::InvalidInput("reranking is not available".into()))
+            }
+            (None, _) => None,
+        };
+
+        let top_k = opts.top_k.clamp(1, MAX_TOP_K);
+        let candidates = (top_k * 4).max(MAX_RERANK_CANDIDATES);
+        let (vector, usage) = match opts.mode {
+            SearchMode::Lexical => (vec![], Usage::default()),
+            SearchMode::Vector | SearchMode::Hybrid => {
+                let (embeddings, usage) = self.embed(collection, vec![query.to_owned()]).await?;
+                let embedding = embeddings
+                    .first()
+                    .ok_or_else(|| RagError::InvalidInput("query could not be embedded".into()))?;
+                let chunks = store::search(self.sql, collection.id, embedding, candidates).await?;
+                (chunks, usage)
+            }
+        };
+        let lexical = match opts.mode {
+            SearchMode::Vector => vec![],
+            SearchMode::Lexical | SearchMode::Hybrid => {
+                store::lexical_search(self.sql, collection.id, query, candidates).await?
+            }
+        };
+
+        let mut results = search::fuse(vector, lexical);
+        let rerank_usage = match reranker {
+            Some((model, reranker)) => {
+                let reranked = results.len().min(MAX_RERANK_CANDIDATES);
+                Some(search::rerank(reranker, model, query, &mut results[..reranked]).await?)
+            }
+            None => None,
+        };
+        results.truncate(top_k);
+
+        Ok(SearchResult {
+            results,
+            usage,
+            rerank_usage,
+        })
+    }
+}
+
+/// The text of the last user message, which is used as the retrieval query for a chat
+pub fn last_user_message(opts: &ChatOptions) -> Option<&str> {
+    opts.messages
+        .iter()
+        .rev()
+        .find(|message| matches!(message.role, ChatRole::User))
+        .and_then(|message| message.content.as_deref())
+}
+
+/// Add retrieved chunks to a chat as a numbered context message, placed just before the last
+/// user message, and return the citations the numbers refer to
+pub fn augment(opts: &mut ChatOptions, chunks: &[ScoredChunk]) -> Vec<Citation> {
+    if chunks.is_empty() {
+        return vec![];
+    }
+
+    let mut context = String::from(
+        "Answer using the numbered context passages below. Cite the passages you use by their \
+         number, like [1]. If the context does not contain the answer, say that you do not know.",
+    );
+    let citations: Vec<Citation> = chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            context.push_str(&format!(
+                "\n\n[{}] {}\n{}",
+                i + 1,
+                chunk.document_title,
+                chunk.content
+            ));
+            Citation {
+                index: i + 1,
+                chunk_id: chunk.id,
+                document_id: chunk.document_id,
+                document_title: chunk.document_title.clone(),
+                position: chunk.position,
+                score: chunk.score,
+            }
+        })
+        .collect();
+
+    let at = opts
+        .messages
+        .iter()
+        .rposition(|message| matches!(message.role, ChatRole::User))
+        .unwrap_or(opts.messages.len());
+    opts.messages
+        .insert(at, ChatMessage::new(ChatRole::System, &context));
+    citations
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scored(title: &str, content: &str, score: f64) -> ScoredChunk {
+        ScoredChunk {
+            id: Uuid::new_v4(),
+            document_id: Uuid::new_v4(),
+            document_title: title.into(),
+            position: 0,
+            content: content.into(),
+            score,
+        }
+    }
+
+    #[test]
+    pub fn test_augment() {
+        let mut opts = ChatOptions::default(
+            "gpt-4o",
+            vec![
+                ChatMessage::new(ChatRole::System, "you are a helpful assistant"),
+                ChatMessage::new(ChatRole::User, "hi"),
+                ChatMessage::new(ChatRole::Assistant, "hello"),
+                ChatMessage::new(ChatRole::User, "when is the volunteer fair?"),
+            ],
+            64,
+        );
+        let chunks = [
+            scored("Events", "The volunteer fair is on May 4.", 0.91),
+            scored("FAQ", "Volunteers register online.", 0.72),
+        ];
+
+        assert_eq!(
+            last_user_message(&opts),
+            Some("when is the volunteer fair?")
+        );
+        let citations = augment(&mut opts, &chunks);
+        assert_eq!(citations.len(), 2);
+        assert_eq!(citations[1].index, 2);
+        assert_eq!(citations[1].document_title, "FAQ");
+
+        assert_eq!(opts.messages.len(), 5);
+        let context = opts.messages[3].content.as_deref().unwrap();
+        assert!(matches!(opts.messages[3].role, ChatRole::System));
+        assert!(context.contains("[1] Events\nThe volunteer fair is on May 4."));
+        assert!(context.contains("[2] FAQ\nVolunteers register online."));
+        assert!(matches!(opts.messages[4].role, ChatRole::User));
+
+        assert!(augment(&mut opts, &[]).is_empty());
+        assert_eq!(opts.messages.len(), 5);
+    }
+
+    #[tokio::test]
+    pub async fn test_lexical_knowledge_base_needs_no_embeddings() {
+        let sql = sqlx::postgres::PgPoolOptions::new()
+            .connect_lazy("postgres://localhost/melody")
+            .unwrap();
+        let collection = Collection {
+            id: Uuid::new_v4(),
+            owner: "auth0|volunteer".into(),
+            name: "guides".into(),
+            description: None,
+            embedding_model: "text-embedding-3-small".into(),
+            dimensions: None,
+            chunk_size: 1000,
+            chunk_overlap: 200,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        };
+        let opts = SearchOptions {
+            mode: SearchMode::Vector,
+            top_k: 5,
+            rerank_model: None,
+        };
+
+        // modes that embed the query fail before reaching the database
+        let knowledge_base = KnowledgeBase::lexical(&sql);
+        assert!(matches!(
+            knowledge_base
+                .search(&collection, "volunteer fair", &opts)
+                .await,
+            Err(RagError::Ai(OpenAIError::Unavailable(_)))
+        ));
+        assert!(matches!(
+            knowledge_base.query(&collection, "volunteer fair", 5).await,
+            Err(RagError::Ai(OpenAIError::Unavailable(_)))
+        ));
+    }
+}
@@ -0,0 +1,7 @@
+pub mod chunker;
+pub mod errors;
+pub mod extract;
+pub mod jobs;
+pub mod knowledge_base;
+pub mod search;
+pub mod store;
@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::app::{
+    llm::provider::ChatProvider,
+    openai::{
+        chat::{ChatMessage, ChatOptions, ChatRole},
+        usage::Usage,
+    },
+};
+
+use super::{errors::RagError, store::ScoredChunk};
+
+/// Damps the weight of top ranks in reciprocal rank fusion. 60 is the value from the original
+/// paper and works well without tuning.
+pub const RRF_K: f64 = 60.0;
+/// Most candidates sent to the model in one rerank request
+pub const MAX_RERANK_CANDIDATES: usize = 20;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchMode {
+    Vector,
+    Lexical,
+    #[default]
+    Hybrid,
+}
+
+/// How a result scored with each retriever. Ranks start at 1 and are missing when the retriever
+/// did not return the chunk.
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct SearchScores {
+    /// Cosine similarity to the query embedding
+    pub vector: Option<f64>,
+    pub vector_rank: Option<usize>,
+    /// `ts_rank_cd` of the chunk for the query words
+    pub lexical: Option<f64>,
+    pub lexical_rank: Option<usize>,
+    /// Reciprocal rank fusion of the ranks above
+    pub fused: f64,
+    /// Relevance between 0 and 1 assigned by the rerank model
+    pub rerank: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    pub document_title: String,
+    pub position: i32,
+    pub content: String,
+    pub scores: SearchScores,
+}
+
+impl From<ScoredChunk> for SearchHit {
+    fn from(chunk: ScoredChunk) -> Self {
+        Self {
+            id: chunk.id,
+            document_id: chunk.document_id,
+            document_title: chunk.document_title,
+            position: chunk.position,
+            content: chunk.content,
+            scores: SearchScores::default(),
+        }
+    }
+}
+
+/// Merge ranked vector and lexical results with reciprocal rank fusion. Each list adds
+/// `1 / (RRF_K + rank)` to the score of its chunks, so chunks found by both retrievers rise to the
+/// top without having to compare cosine similarities with text ranks.
+pub fn fuse(vector: Vec<ScoredChunk>, lexical: Vec<ScoredChunk>) -> Vec<SearchHit> {
+    let mut hits: Vec<SearchHit> = vec![];
+    let mut seen: HashMap<Uuid, usize> = HashMap::new();
+
+    for (lexical_list, chunks) in [(false, vector), (true, lexical)] {
+        for (i, chunk) in chunks.into_iter().enumerate() {
+            let (rank, score) = (i + 1, chunk.score);
+            let at = *seen.entry(chunk.id).or_insert_with(|| {
+                hits.push(SearchHit::from(chunk));
+                hits.len() - 1
+            });
+
+            let scores = &mut hits[at].scores;
+            scores.fused += 1.0 / (RRF_K + rank as f64);
+            match lexical_list {
+                false => (scores.vector, scores.vector_rank) = (Some(score), Some(rank)),
+                true => (scores.lexical, scores.lexical_rank) = (Some(score), Some(rank)),
+            }
+        }
+    }
+
+    hits.sort_by(|a, b| b.scores.fused.total_cmp(&a.scores.fused));
+    hits
+}
+
+fn rerank_prompt(query: &str, hits: &[SearchHit]) -> String {
+    let mut prompt = format!(
+        "Rate how relevant each passage is to the query on a scale from 0 (unrelated) to 10 \
+         (answers it directly). Reply with only a JSON array of {} numbers, one per passage, in \
+         order.\n\nQuery: {query}",
+        hits.len()
+    );
+    for (i, hit) in hits.iter().enumerate() {
+        prompt.push_str(&format!("\n\n[{}] {}", i + 1, hit.content));
+    }
+    prompt
+}
+
+/// Read the scores from a rerank reply, ignoring any text the model put around the array
+fn parse_rerank_scores(reply: &str, expected: usize) -> Option<Vec<f64>> {
+    let start = reply.find('[')?;
+    let end = reply.rfind(']')?;
+    let scores: Vec<f64> = serde_json::from_str(reply.get(start..=end)?).ok()?;
+    (scores.len() == expected).then_some(scores)
+}
+
+/// Ask a chat model to score the hits for relevance to the query and reorder them by that score.
+/// A reply that can't be read leaves the fused order in place, since the hits are still usable.
+pub async fn rerank(
+    provider: &dyn ChatProvider,
+    model: &str,
+    query: &str,
+    hits: &mut [SearchHit],
+) -> Result<Usage, RagError> {
+    if hits.is_empty() {
+        return Ok(Usage::default());
+    }
+
+    let opts = ChatOptions {
+        temperature: Some(0.0),
+        ..ChatOptions::default(
+            model,
+            vec![ChatMessage::new(
+                ChatRole::User,
+                &rerank_prompt(query, hits),
+            )],
+            (hits.len() * 8) as u64 + 16,
+        )
+    };
+    let completion = provider.get_chat_completion(&opts).await?;
+    let reply = completion
+        .choices
+        .first()
+        .and_then(|choice| choice.message.content.as_deref())
+        .unwrap_or_default();
+
+    match parse_rerank_scores(reply, hits.len()) {
+        Some(scores) => {
+            for (hit, score) in hits.iter_mut().zip(scores) {
+                hit.scores.rerank = Some((score / 10.0).clamp(0.0, 1.0));
+            }
+            let rerank = |hit: &SearchHit| hit.scores.rerank.unwrap_or_default();
+            hits.sort_by(|a, b| {
+                rerank(b)
+                    .total_cmp(&rerank(a))
+                    .then(b.scores.fused.total_cmp(&a.scores.fused))
+            });
+        }
+        None => log::warn!("unable to read rerank scores from {reply:?}"),
+    }
+    Ok(completion.usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scored(id: Uuid, score: f64) -> ScoredChunk {
+        ScoredChunk {
+            id,
+            document_id: Uuid::nil(),
+            document_title: "Programs".into(),
+            position: 0,
+            content: "content".into(),
+            score,
+        }
+    }
+
+    #[test]
+    pub fn test_fuse() {
+        let (a, b, c) = (Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4());
+        let hits = fuse(
+            vec![scored(a, 0.9), scored(b, 0.8)],
+            vec![scored(c, 0.4), scored(b, 0.2)],
+        );
+
+        let ids: Vec<Uuid> = hits.iter().map(|hit| hit.id).collect();
+        assert_eq!(ids, vec![b, a, c]);
+
+        let both = &hits[0].scores;
+        assert_eq!((both.vector, both.vector_rank), (Some(0.8), Some(2)));
+        assert_eq!((both.lexical, both.lexical_rank), (Some(0.2), Some(2)));
+        assert_eq!(both.fused, 2.0 / (RRF_K + 2.0));
+        assert_eq!(hits[1].scores.lexical, None);
+        assert_eq!(hits[1].scores.fused, hits[2].scores.fused);
+    }
+
+    #[test]
+    pub fn test_parse_rerank_scores() {
+        assert_eq!(
+            parse_rerank_scores("Scores:\n```json\n[2, 9.5, 0]\n```", 3),
+            Some(vec![2.0, 9.5, 0.0])
+        );
+        assert_eq!(parse_rerank_scores("[2, 9.5]", 3), None);
+        assert_eq!(parse_rerank_scores("all of them", 3), None);
+    }
+}
@@ -0,0 +1,358 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use super::{chunker::ChunkOptions, errors::RagError, extract::Segment};
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Collection {
+    pub id: Uuid,
+    /// The subject of the token that created the collection
+    pub owner: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub embedding_model: String,
+    pub dimensions: Option<i32>,
+    pub chunk_size: i32,
+    pub chunk_overlap: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Collection {
+    /// Falls back to the default options if the stored ones aren't valid
+    pub fn chunk_options(&self) -> ChunkOptions {
+        let size = usize::try_from(self.chunk_size).unwrap_or_default();
+        let overlap = usize::try_from(self.chunk_overlap).unwrap_or_default();
+        ChunkOptions::new(size, overlap).unwrap_or_else(|e| {
+            log::warn!("collection {} has invalid chunk options: {e}", self.id);
+            ChunkOptions::default()
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NewCollection {
+    pub owner: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub embedding_model: String,
+    pub dimensions: Option<u32>,
+    pub chunking: ChunkOptions,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct Document {
+    pub id: Uuid,
+    pub collection_id: Uuid,
+    pub title: String,
+    pub source: Option<String>,
+    pub metadata: Value,
+    /// Number of chunks the document was split into
+    pub chunks: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewDocument {
+    pub title: String,
+    pub source: Option<String>,
+    pub metadata: Value,
+    pub content: String,
+    /// The pages or sections the content was extracted from, if it came from a file
+    pub segments: Vec<Segment>,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct DocumentSegment {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    pub position: i32,
+    pub page: Option<i32>,
+    pub heading: Option<String>,
+    pub content: String,
+}
+
+/// A chunk returned by a search, with its cosine similarity to the query
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+pub struct ScoredChunk {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    pub document_title: String,
+    pub position: i32,
+    pub content: String,
+    pub score: f64,
+}
+
+const COLLECTION_COLUMNS: &str =
+    "id, owner, name, description, embedding_model, dimensions, chunk_size, \
+     chunk_overlap, created_at, updated_at";
+
+const DOCUMENT_COLUMNS: &str = "d.id, d.collection_id, d.title, d.source, d.metadata, \
+     (select count(*) from chunks c where c.document_id = d.id) as chunks, d.created_at, \
+     d.updated_at";
+
+/// Format an embedding as a pgvector literal. Vectors are bound as text and cast in the query.
+pub fn to_vector(embedding: &[f32]) -> String {
+    let components: Vec<String> = embedding.iter().map(f32::to_string).collect();
+    format!("[{}]", components.join(","))
+}
+
+pub async fn create_collection(sql: &PgPool, new: &NewCollection) -> Result<Collection, RagError> {
+    let int = |value: usize, name: &str| {
+        i32::try_from(value).map_err(|_| RagError::InvalidInput(format!("{name} is too large")))
+    };
+    let dimensions = new
+        .dimensions
+        .map(|dimensions| int(dimensions as usize, "dimensions"))
+        .transpose()?;
+    let query = format!(
+        "insert into collections (owner, name, description, embedding_model, dimensions, \
+         chunk_size, chunk_overlap) values ($1, $2, $3, $4, $5, $6, $7) \
+         returning {COLLECTION_COLUMNS}"
+    );
+    let collection = sqlx::query_as(&query)
+        .bind(&new.owner)
+        .bind(&new.name)
+        .bind(&new.description)
+        .bind(&new.embedding_model)
+        .bind(dimensions)
+        .bind(int(new.chunking.size, "chunk size")?)
+        .bind(int(new.chunking.overlap, "chunk overlap")?)
+        .fetch_one(sql)
+        .await?;
+    Ok(collection)
+}
+
+pub async fn list_collections(sql: &PgPool, owner: &str) -> Result<Vec<Collection>, RagError> {
+    let query =
+        format!("select {COLLECTION_COLUMNS} from collections where owner = $1 order by name");
+    Ok(sqlx::query_as(&query).bind(owner).fetch_all(sql).await?)
+}
+
+/// A collection of `owner`. Other owners' collections are not found.
+pub async fn get_collection(sql: &PgPool, id: Uuid, owner: &str) -> Result<Collection, RagError> {
+    let query =
+        format!("select {COLLECTION_COLUMNS} from collections where id = $1 and owner = $2");
+    sqlx::query_as(&query)
+        .bind(id)
+        .bind(owner)
+        .fetch_optional(sql)
+        .await?
+        .ok_or_else(|| RagError::NotFound(format!("no collection with id {id}")))
+}
+
+pub async fn get_collection_by_name(
+    sql: &PgPool,
+    name: &str,
+    owner: &str,
+) -> Result<Collection, RagError> {
+    let query =
+        format!("select {COLLECTION_COLUMNS} from collections where name = $1 and owner = $2");
+    sqlx::query_as(&query)
+        .bind(name)
+        .bind(owner)
+        .fetch_optional(sql)
+        .await?
+        .ok_or_else(|| RagError::NotFound(format!("no collection named {name}")))
+}
+
+pub async fn delete_collection(sql: &PgPool, id: Uuid, owner: &str) -> Result<(), RagError> {
+    let deleted = sqlx::query("delete from collections where id = $1 and owner = $2")
+        .bind(id)
+        .bind(owner)
+        .execute(sql)
+        .await?;
+    match deleted.rows_affected() {
+        0 => Err(RagError::NotFound(format!("no collection with id {id}"))),
+        _ => Ok(()),
+    }
+}
+
+/// Store a document with its segments, chunks and their embeddings in a single transaction
+pub async fn insert_document(
+    sql: &PgPool,
+    collection_id: Uuid,
+    new: &NewDocument,
+    chunks: &[String],
+    embeddings: &[Vec<f32>],
+) -> Result<Document, RagError> {
+    let mut tx = sql.begin().await?;
+
+    let (document_id,): (Uuid,) = sqlx::query_as(
+        "insert into documents (collection_id, title, source, metadata) values ($1, $2, $3, $4) \
+         returning id",
+    )
+    .bind(collection_id)
+    .bind(&new.title)
+    .bind(&new.source)
+    .bind(&new.metadata)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let positions: Vec<i32> = (0..chunks.len() as i32).collect();
+    let vectors: Vec<String> = embeddings.iter().map(|e| to_vector(e)).collect();
+    sqlx::query(
+        "insert into chunks (collection_id, document_id, position, content, embedding) \
+         select $1, $2, t.position, t.content, t.embedding::vector \
+         from unnest($3::integer[], $4::text[], $5::text[]) as t(position, content, embedding)",
+    )
+    .bind(collection_id)
+    .bind(document_id)
+    .bind(&positions)
+    .bind(chunks)
+    .bind(&vectors)
+    .execute(&mut *tx)
+    .await?;
+
+    if !new.segments.is_empty() {
+        let positions: Vec<i32> = (0..new.segments.len() as i32).collect();
+        let pages: Vec<Option<i32>> = new
+            .segments
+            .iter()
+            .map(|segment| segment.page.map(|page| page as i32))
+            .collect();
+        let headings: Vec<Option<String>> = new
+            .segments
+            .iter()
+            .map(|segment| segment.heading.clone())
+            .collect();
+        let contents: Vec<&str> = new
+            .segments
+            .iter()
+            .map(|segment| segment.content.as_str())
+            .collect();
+        sqlx::query(
+            "insert into document_segments (document_id, position, page, heading, content) \
+             select $1, t.position, t.page, t.heading, t.content \
+             from unnest($2::integer[], $3::integer[], $4::text[], $5::text[]) \
+             as t(position, page, heading, content)",
+        )
+        .bind(document_id)
+        .bind(&positions)
+        .bind(&pages)
+        .bind(&headings)
+        .bind(&contents)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let query = format!("select {DOCUMENT_COLUMNS} from documents d where d.id = $1");
+    let document = sqlx::query_as(&query)
+        .bind(document_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(document)
+}
+
+pub async fn list_documents(sql: &PgPool, collection_id: Uuid) -> Result<Vec<Document>, RagError> {
+    let query = format!(
+        "select {DOCUMENT_COLUMNS} from documents d where d.collection_id = $1 \
+         order by d.created_at"
+    );
+    Ok(sqlx::query_as(&query)
+        .bind(collection_id)
+        .fetch_all(sql)
+        .await?)
+}
+
+pub async fn delete_document(sql: &PgPool, collection_id: Uuid, id: Uuid) -> Result<(), RagError> {
+    let deleted = sqlx::query("delete from documents where collection_id = $1 and id = $2")
+        .bind(collection_id)
+        .bind(id)
+        .execute(sql)
+        .await?;
+    match deleted.rows_affected() {
+        0 => Err(RagError::NotFound(format!("no document with id {id}"))),
+        _ => Ok(()),
+    }
+}
+
+/// The pages or sections of a document, in order. Documents created from text have none.
+pub async fn list_segments(
+    sql: &PgPool,
+    collection_id: Uuid,
+    document_id: Uuid,
+) -> Result<Vec<DocumentSegment>, RagError> {
+    let exists: Option<(Uuid,)> =
+        sqlx::query_as("select id from documents where collection_id = $1 and id = $2")
+            .bind(collection_id)
+            .bind(document_id)
+            .fetch_optional(sql)
+            .await?;
+    if exists.is_none() {
+        return Err(RagError::NotFound(format!(
+            "no document with id {document_id}"
+        )));
+    }
+
+    Ok(sqlx::query_as(
+        "select id, document_id, position, page, heading, content from document_segments \
+         where document_id = $1 order by position",
+    )
+    .bind(document_id)
+    .fetch_all(sql)
+    .await?)
+}
+
+/// The `top_k` chunks of a collection closest to `embedding` by cosine distance
+pub async fn search(
+    sql: &PgPool,
+    collection_id: Uuid,
+    embedding: &[f32],
+    top_k: usize,
+) -> Result<Vec<ScoredChunk>, RagError> {
+    Ok(sqlx::query_as(
+        "select c.id, c.document_id, d.title as document_title, c.position, c.content, \
+         1 - (c.embedding <=> $2::vector) as score \
+         from chunks c join documents d on d.id = c.document_id \
+         where c.collection_id = $1 \
+         order by c.embedding <=> $2::vector \
+         limit $3",
+    )
+    .bind(collection_id)
+    .bind(to_vector(embedding))
+    .bind(top_k as i64)
+    .fetch_all(sql)
+    .await?)
+}
+
+/// The `top_k` chunks of a collection that match the words of `query`, ranked by `ts_rank_cd`.
+/// The query uses web search syntax, so quoted phrases and `-word` exclusions work.
+pub async fn lexical_search(
+    sql: &PgPool,
+    collection_id: Uuid,
+    query: &str,
+    top_k: usize,
+) -> Result<Vec<ScoredChunk>, RagError> {
+    Ok(sqlx::query_as(
+        "select c.id, c.document_id, d.title as document_title, c.position, c.content, \
+         ts_rank_cd(c.search_vector, q)::float8 as score \
+         from chunks c join documents d on d.id = c.document_id, \
+         websearch_to_tsquery('english', $2) q \
+         where c.collection_id = $1 and c.search_vector @@ q \
+         order by score desc \
+         limit $3",
+    )
+    .bind(collection_id)
+    .bind(query)
+    .bind(top_k as i64)
+    .fetch_all(sql)
+    .await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_to_vector() {
+        assert_eq!(to_vector(&[0.5, -1.0, 0.125]), "[0.5,-1,0.125]");
+        assert_eq!(to_vector(&[]), "[]");
+    }
+}
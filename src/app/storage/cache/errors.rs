@@ -0,0 +1,40 @@
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum CacheError {
+    /// No connection could be taken from the pool, usually because redis is unreachable
+    #[error("unable to acquire cache connection. error: {0}")]
+    Connection(String),
+    #[error("cache command failed. error: {0}")]
+    Command(String),
+    #[error("unable to serialize value for cache. error: {0}")]
+    Serialize(String),
+    #[error("unable to parse cached value at {0}. error: {1}")]
+    Deserialize(String, String),
+}
+
+impl CacheError {
+    /// Whether redis itself is unreachable, as opposed to a single command failing
+    pub fn is_unavailable(&self) -> bool {
+        matches!(self, CacheError::Connection(_))
+    }
+}
+
+impl From<mobc_redis::redis::RedisError> for CacheError {
+    fn from(e: mobc_redis::redis::RedisError) -> Self {
+        match e.is_io_error()
+            || e.is_connection_refusal()
+            || e.is_connection_dropped()
+            || e.is_timeout()
+        {
+            true => CacheError::Connection(e.to_string()),
+            false => CacheError::Command(e.to_string()),
+        }
+    }
+}
+
+impl From<mobc::Error<mobc_redis::redis::RedisError>> for CacheError {
+    fn from(e: mobc::Error<mobc_redis::redis::RedisError>) -> Self {
+        CacheError::Connection(e.to_string())
+    }
+}
@@ -0,0 +1,80 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use hashlink::LruCache;
+
+/// Json values kept in process memory, evicting the least recently used once full
+pub struct MemoryCache {
+    entries: Mutex<LruCache<String, (String, Instant)>>,
+}
+
+impl MemoryCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(capacity.max(1))),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some((json, expires)) if *expires > Instant::now() => Some(json.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, key: &str, json: String, ttl: Duration) {
+        let expires = Instant::now() + ttl;
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), (json, expires));
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.entries.lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Entries held, including expired ones that haven't been read since they expired
+    pub fn entry_count(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    pub fn test_memory_cache() {
+        let memory = MemoryCache::new(2);
+        let minute = Duration::from_secs(60);
+
+        memory.insert("a", "1".into(), minute);
+        memory.insert("b", "2".into(), minute);
+        // reading a makes b the least recently used
+        assert_eq!(memory.get("a").as_deref(), Some("1"));
+        memory.insert("c", "3".into(), minute);
+        assert_eq!(memory.get("b"), None);
+        assert_eq!(memory.get("c").as_deref(), Some("3"));
+        assert_eq!(memory.entry_count(), 2);
+
+        memory.insert("expired", "4".into(), Duration::ZERO);
+        assert_eq!(memory.get("expired"), None);
+
+        memory.remove("a");
+        assert_eq!(memory.get("a"), None);
+        memory.clear();
+        assert_eq!(memory.entry_count(), 0);
+    }
+}
@@ -1,9 +1,21 @@
+pub mod errors;
+pub mod memory;
+
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
 use mobc::{Connection, Pool};
-use mobc_redis::redis::AsyncCommands;
+use mobc_redis::redis::FromRedisValue;
 use mobc_redis::{redis, RedisConnectionManager};
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::time::Duration;
+use serde::{de::DeserializeOwned, Serialize};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::launch::LaunchMode;
 
+use self::{errors::CacheError, memory::MemoryCache};
 use super::errors::DbError;
 
 pub type RedisPool = Pool<RedisConnectionManager>;
@@ -14,6 +26,32 @@ const CACHE_POOL_MAX_IDLE: u64 = 8;
 const CACHE_POOL_TIMEOUT_SECONDS: u64 = 1;
 const CACHE_POOL_EXPIRE_SECONDS: u64 = 60;
 
+/// Bump to abandon every key written by an older release when the stored formats change
+const CACHE_VERSION: &str = "v1";
+
+const DEFAULT_MEMORY_CAPACITY: usize = 10_000;
+/// Longest a value is held in process memory, which bounds how stale it can get if an
+/// invalidation is missed
+const MEMORY_MAX_TTL: Duration = Duration::from_secs(60);
+/// How long redis is skipped after it fails, so that requests don't each wait for the pool
+/// timeout during an outage
+const REDIS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a `get_or_compute` lock is held at most, in case its holder dies before releasing it
+const LOCK_TTL: Duration = Duration::from_secs(30);
+/// How long a `get_or_compute` miss waits for another caller's computation before doing its own
+const LOCK_WAIT: Duration = Duration::from_secs(10);
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Deletes a lock only if it is still held by the caller's token
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call("get", KEYS[1]) == ARGV[1] then
+    return redis.call("del", KEYS[1])
+else
+    return 0
+end
+"#;
+
 pub async fn create_pool(cache_url: &str) -> Result<RedisPool, DbError> {
     let client =
         redis::Client::open(cache_url).map_err(|e| DbError::CreateClient(e.to_string()))?;
@@ -27,56 +65,425 @@ pub async fn create_pool(cache_url: &str) -> Result<RedisPool, DbError> {
     Ok(pool)
 }
 
-pub async fn set_json<'a, T>(conn: &mut RedisConn, key: &str, value: T) -> Result<(), DbError>
-where
-    T: Serialize + Deserialize<'a>,
-{
-    let bytes = serde_json::to_string(&value)
-        .map(|s| s.as_bytes().to_vec())
-        .map_err(|e| DbError::InvalidCacheValue(e.to_string()))?;
+/// Expiries are sent in whole seconds, rounded up so that short ttls don't become no expiry
+fn ttl_seconds(ttl: Duration) -> usize {
+    let seconds = ttl.as_secs() + u64::from(ttl.subsec_nanos() > 0);
+    seconds.max(1) as usize
+}
+
+fn to_json<T: Serialize + ?Sized>(value: &T) -> Result<String, CacheError> {
+    serde_json::to_string(value).map_err(|e| CacheError::Serialize(e.to_string()))
+}
 
-    conn.set(key.to_owned(), bytes)
-        .await
-        .map_err(|e| DbError::ServerError(e.to_string()))?;
+fn from_json<T: DeserializeOwned>(key: &str, json: &str) -> Result<T, CacheError> {
+    serde_json::from_str(json).map_err(|e| CacheError::Deserialize(key.to_owned(), e.to_string()))
+}
 
-    Ok(())
+/// Why redis is being skipped
+#[derive(Debug, Clone)]
+struct Outage {
+    since: DateTime<Utc>,
+    retry_at: Instant,
 }
 
-pub async fn get_json<'a, T>(conn: &mut RedisConn, key: &str) -> Result<Option<T>, DbError>
-where
-    T: Serialize + DeserializeOwned,
-{
-    let json: Option<String> = conn
-        .get(key)
-        .await
-        .map_err(|e| DbError::ServerError(e.to_string()))?;
-    match json {
-        Some(value) => {
-            let s: T = serde_json::from_str(&value).map_err(|e| DbError::Parse(e.to_string()))?;
-            Ok(Some(s))
-        }
-        None => Ok(None),
+/// How the cache tiers are doing, reported by the health endpoint
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CacheHealth {
+    /// `up`, or `down` while only process memory is used
+    pub redis: &'static str,
+    pub redis_down_since: Option<DateTime<Utc>>,
+    pub memory_entries: usize,
+}
+
+impl CacheHealth {
+    pub fn is_degraded(&self) -> bool {
+        self.redis_down_since.is_some()
     }
 }
 
-pub async fn evict(
-    conn: &mut Connection<RedisConnectionManager>,
-    key: &str,
-) -> Result<(), DbError> {
-    conn.del(key)
-        .await
-        .map_err(|e| DbError::ServerError(e.to_string()))?;
-    Ok(())
+/// Json values under a prefix for the app and launch mode, so that apps and environments sharing
+/// a redis server don't read each other's keys. Every value is written with an expiry.
+///
+/// Values are kept in redis with hot values also held in process memory. Writes are broadcast
+/// over pub/sub so other instances drop their copies. When redis can't be reached the cache
+/// keeps working from memory alone, and redis is retried after a short pause rather than on
+/// every request.
+#[derive(Clone)]
+pub struct Cache {
+    pool: RedisPool,
+    prefix: String,
+    memory: Arc<MemoryCache>,
+    outage: Arc<Mutex<Option<Outage>>>,
+    /// Identifies this process's own invalidations
+    instance: Arc<str>,
+}
+
+impl Cache {
+    pub fn new(pool: RedisPool, name: &str, launch_mode: &LaunchMode) -> Self {
+        Self {
+            pool,
+            prefix: format!("{name}:{launch_mode}:{CACHE_VERSION}"),
+            memory: Arc::new(MemoryCache::new(DEFAULT_MEMORY_CAPACITY)),
+            outage: Arc::default(),
+            instance: Uuid::new_v4().to_string().into(),
+        }
+    }
+
+    /// Hold at most `capacity` values in process memory
+    pub fn with_memory_capacity(mut self, capacity: usize) -> Self {
+        self.memory = Arc::new(MemoryCache::new(capacity));
+        self
+    }
+
+    /// The full redis key for a key of this cache
+    pub fn key(&self, key: &str) -> String {
+        format!("{}:{key}", self.prefix)
+    }
+
+    fn channel(&self) -> String {
+        format!("{}:invalidate", self.prefix)
+    }
+
+    fn redis_available(&self) -> bool {
+        match &*self.outage.lock().unwrap() {
+            Some(outage) => Instant::now() >= outage.retry_at,
+            None => true,
+        }
+    }
+
+    /// Note the outcome of a redis call, opening or closing the outage
+    fn record<T>(&self, res: &Result<T, CacheError>) {
+        let mut outage = self.outage.lock().unwrap();
+        match res {
+            Err(e) if e.is_unavailable() => {
+                if outage.is_none() {
+                    log::warn!("redis is unavailable, caching in memory only: {e}");
+                }
+                *outage = Some(Outage {
+                    since: outage.as_ref().map_or_else(Utc::now, |outage| outage.since),
+                    retry_at: Instant::now() + REDIS_RETRY_INTERVAL,
+                });
+            }
+            _ => {
+                if outage.take().is_some() {
+                    log::info!("redis is reachable again");
+                    // writes made while it was down never reached other instances
+                    self.memory.clear();
+                }
+            }
+        }
+    }
+
+    /// A connection for commands the wrapper doesn't cover. Keys must be built with `key`. Fails
+    /// straight away while redis is known to be down.
+    pub async fn conn(&self) -> Result<RedisConn, CacheError> {
+        if !self.redis_available() {
+            return Err(CacheError::Connection("redis is unavailable".into()));
+        }
+        let conn = self.pool.get().await.map_err(CacheError::from);
+        self.record(&conn);
+        conn
+    }
+
+    /// Run a pipeline against redis. An unreachable redis is reported as `Ok(None)`, so callers
+    /// can carry on with memory alone.
+    async fn query<T: FromRedisValue>(
+        &self,
+        pipe: &redis::Pipeline,
+    ) -> Result<Option<T>, CacheError> {
+        let mut conn = match self.conn().await {
+            Ok(conn) => conn,
+            Err(e) if e.is_unavailable() => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let res = pipe.query_async(&mut *conn).await.map_err(CacheError::from);
+        self.record(&res);
+        match res {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.is_unavailable() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn invalidation(&self, key: &str) -> String {
+        format!("{} {}", self.instance, self.key(key))
+    }
+
+    pub async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, CacheError> {
+        if let Some(json) = self.memory.get(key) {
+            return from_json(key, &json).map(Some);
+        }
+
+        let full_key = self.key(key);
+        let mut pipe = redis::pipe();
+        pipe.get(&full_key).cmd("PTTL").arg(&full_key);
+        let Some((json, pttl)) = self.query::<(Option<String>, i64)>(&pipe).await? else {
+            return Ok(None);
+        };
+        let Some(json) = json else {
+            return Ok(None);
+        };
+        let value = from_json(key, &json)?;
+        if pttl > 0 {
+            let ttl = Duration::from_millis(pttl as u64).min(MEMORY_MAX_TTL);
+            self.memory.insert(key, json, ttl);
+        }
+        Ok(Some(value))
+    }
+
+    pub async fn set<T: Serialize + ?Sized>(
+        &self,
+        key: &str,
+        value: &T,
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        let json = to_json(value)?;
+        self.memory
+            .insert(key, json.clone(), ttl.min(MEMORY_MAX_TTL));
+
+        let mut pipe = redis::pipe();
+        pipe.set_ex(self.key(key), json, ttl_seconds(ttl))
+            .ignore()
+            .publish(self.channel(), self.invalidation(key))
+            .ignore();
+        self.query::<()>(&pipe).await?;
+        Ok(())
+    }
+
+    /// Deleting a key that does not exist succeeds
+    pub async fn delete(&self, key: &str) -> Result<(), CacheError> {
+        self.memory.remove(key);
+
+        let mut pipe = redis::pipe();
+        pipe.del(self.key(key))
+            .ignore()
+            .publish(self.channel(), self.invalidation(key))
+            .ignore();
+        self.query::<()>(&pipe).await?;
+        Ok(())
+    }
+
+    /// Get several keys in one round trip. Values are returned in the order of `keys`.
+    pub async fn mget<T: DeserializeOwned>(
+        &self,
+        keys: &[&str],
+    ) -> Result<Vec<Option<T>>, CacheError> {
+        let mut values: Vec<Option<String>> = keys.iter().map(|key| self.memory.get(key)).collect();
+        let missing: Vec<usize> = (0..keys.len()).filter(|i| values[*i].is_none()).collect();
+
+        if !missing.is_empty() {
+            let mut pipe = redis::pipe();
+            pipe.cmd("MGET").arg(
+                missing
+                    .iter()
+                    .map(|i| self.key(keys[*i]))
+                    .collect::<Vec<_>>(),
+            );
+            if let Some((fetched,)) = self.query::<(Vec<Option<String>>,)>(&pipe).await? {
+                for (i, json) in missing.into_iter().zip(fetched) {
+                    values[i] = json;
+                }
+            }
+        }
+
+        keys.iter()
+            .zip(values)
+            .map(|(key, json)| json.map(|json| from_json(key, &json)).transpose())
+            .collect()
+    }
+
+    /// Set several keys with the same ttl in one atomic round trip
+    pub async fn mset<T: Serialize>(
+        &self,
+        entries: &[(&str, T)],
+        ttl: Duration,
+    ) -> Result<(), CacheError> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (key, value) in entries {
+            let json = to_json(value)?;
+            self.memory
+                .insert(key, json.clone(), ttl.min(MEMORY_MAX_TTL));
+            pipe.set_ex(self.key(key), json, ttl_seconds(ttl))
+                .ignore()
+                .publish(self.channel(), self.invalidation(key))
+                .ignore();
+        }
+        self.query::<()>(&pipe).await?;
+        Ok(())
+    }
+
+    async fn lock(&self, key: &str, token: &str) -> Result<bool, CacheError> {
+        let acquired: Option<String> = redis::cmd("SET")
+            .arg(self.key(&format!("{key}:lock")))
+            .arg(token)
+            .arg("NX")
+            .arg("PX")
+            .arg(LOCK_TTL.as_millis() as u64)
+            .query_async(&mut *self.conn().await?)
+            .await?;
+        Ok(acquired.is_some())
+    }
+
+    async fn unlock(&self, key: &str, token: &str) -> Result<(), CacheError> {
+        redis::Script::new(UNLOCK_SCRIPT)
+            .key(self.key(&format!("{key}:lock")))
+            .arg(token)
+            .invoke_async::<_, ()>(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    /// The cached value of `key`, or the result of `compute`, which is cached for `ttl`. Concurrent
+    /// misses, from this process or another, are single flighted through a lock in redis: one
+    /// caller computes the value while the others wait for it to be cached. A waiter gives up
+    /// and computes the value itself after a while, and any cache failure falls back to
+    /// computing, so the cache never makes a value unavailable.
+    pub async fn get_or_compute<T, E, F, Fut>(
+        &self,
+        key: &str,
+        ttl: Duration,
+        compute: F,
+    ) -> Result<T, E>
+    where
+        T: Serialize + DeserializeOwned,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<T, E>>,
+    {
+        let token = Uuid::new_v4().to_string();
+        let deadline = Instant::now() + LOCK_WAIT;
+        let locked = loop {
+            match self.get(key).await {
+                Ok(Some(value)) => return Ok(value),
+                Ok(None) => {}
+                Err(e) => {
+                    log::error!("unable to read {key} from the cache: {e}");
+                    break false;
+                }
+            }
+            match self.lock(key, &token).await {
+                Ok(true) => break true,
+                Ok(false) if Instant::now() < deadline => {
+                    tokio::time::sleep(LOCK_POLL_INTERVAL).await
+                }
+                Ok(false) => {
+                    log::warn!("gave up waiting for {key} to be computed by another caller");
+                    break false;
+                }
+                Err(e) => {
+                    // already logged when redis went down
+                    if !e.is_unavailable() {
+                        log::error!("unable to lock {key} in the cache: {e}");
+                    }
+                    break false;
+                }
+            }
+        };
+
+        // failures are not cached, and waiters take over once the lock is released
+        let value = compute().await;
+        if let Ok(value) = &value {
+            if let Err(e) = self.set(key, value, ttl).await {
+                log::error!("unable to write {key} to the cache: {e}");
+            }
+        }
+        if locked {
+            if let Err(e) = self.unlock(key, &token).await {
+                log::error!("unable to unlock {key} in the cache: {e}");
+            }
+        }
+        value
+    }
+
+    /// Check redis, unless it is known to be down and not due for a retry
+    pub async fn health(&self) -> CacheHealth {
+        let mut pipe = redis::pipe();
+        pipe.cmd("PING");
+        if let Err(e) = self.query::<(String,)>(&pipe).await {
+            log::error!("unable to ping redis: {e}");
+        }
+
+        let since = self
+            .outage
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|outage| outage.since);
+        CacheHealth {
+            redis: if since.is_some() { "down" } else { "up" },
+            redis_down_since: since,
+            memory_entries: self.memory.entry_count(),
+        }
+    }
+
+    /// Drop values from memory as other instances write them. Runs until the process exits,
+    /// resubscribing whenever the connection is lost. Memory is cleared on every subscription
+    /// since invalidations sent in the meantime were missed.
+    pub fn spawn_invalidation_listener(&self, client: redis::Client) -> JoinHandle<()> {
+        let cache = self.clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = cache.listen(&client).await {
+                    log::warn!("cache invalidation listener disconnected: {e}");
+                }
+                tokio::time::sleep(REDIS_RETRY_INTERVAL).await;
+            }
+        })
+    }
+
+    async fn listen(&self, client: &redis::Client) -> Result<(), CacheError> {
+        let mut pubsub = client.get_async_connection().await?.into_pubsub();
+        pubsub.subscribe(self.channel()).await?;
+        self.memory.clear();
+
+        let prefix = format!("{}:", self.prefix);
+        let mut messages = pubsub.on_message();
+        while let Some(message) = messages.next().await {
+            let Ok(payload) = message.get_payload::<String>() else {
+                continue;
+            };
+            let Some((instance, key)) = payload.split_once(' ') else {
+                continue;
+            };
+            if instance != &*self.instance {
+                if let Some(key) = key.strip_prefix(&prefix) {
+                    self.memory.remove(key);
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::env;
+    use std::{
+        env,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
 
     use crate::app::{util, util::test_util::TestStruct};
 
     use super::*;
 
+    async fn test_cache() -> Cache {
+        util::test_util::init();
+        let cache_url = env::var("CACHE_URL").expect("invalid or missing cache url");
+        let pool = create_pool(&cache_url).await.expect("error creating pool");
+        Cache::new(
+            pool,
+            &format!("test-{}", Uuid::new_v4()),
+            &LaunchMode::Testing,
+        )
+    }
+
     #[tokio::test]
     pub async fn test_create_pool() {
         util::test_util::init();
@@ -85,35 +492,104 @@ mod tests {
         let _ = create_pool(&cache_url).await.expect("error creating pool");
     }
 
-    #[tokio::test]
-    pub async fn test_get_set_evict() {
-        util::test_util::init();
+    #[test]
+    pub fn test_key() {
+        let pool = Pool::builder().build(RedisConnectionManager::new(
+            redis::Client::open("redis://localhost:6379").unwrap(),
+        ));
+        let cache = Cache::new(pool, "melody", &LaunchMode::Staging);
+        assert_eq!(cache.key("chat:abc"), "melody:staging:v1:chat:abc");
 
-        let cache_url = env::var("CACHE_URL").expect("invalid or missing cache url");
+        assert_eq!(ttl_seconds(Duration::from_secs(60)), 60);
+        assert_eq!(ttl_seconds(Duration::from_millis(1500)), 2);
+        assert_eq!(ttl_seconds(Duration::ZERO), 1);
+    }
 
-        let pool = create_pool(&cache_url).await.expect("error creating pool");
-        let mut conn = pool.get().await.expect("error acquiring connection");
+    #[tokio::test]
+    pub async fn test_get_set_evict() {
+        let cache = test_cache().await;
 
         let t = TestStruct {
             first_name: "Jenny".to_owned(),
             last_name: "Cho".to_owned(),
         };
 
-        set_json(&mut conn, "test", t)
+        cache
+            .set("test", &t, Duration::from_secs(60))
             .await
             .expect("error setting key");
 
-        let _: TestStruct = get_json(&mut conn, "test")
+        let _: TestStruct = cache
+            .get("test")
             .await
             .expect("error retrieving value")
             .unwrap();
 
-        evict(&mut conn, "test").await.expect("error deleting key");
+        cache.delete("test").await.expect("error deleting key");
 
-        let deleted: Option<TestStruct> = get_json(&mut conn, "test")
-            .await
-            .expect("error retrieving value");
+        let deleted: Option<TestStruct> = cache.get("test").await.expect("error retrieving value");
 
         assert!(deleted.is_none());
+
+        cache
+            .mset(&[("a", 1), ("b", 2)], Duration::from_secs(60))
+            .await
+            .expect("error setting keys");
+        let values: Vec<Option<i32>> = cache
+            .mget(&["a", "missing", "b"])
+            .await
+            .expect("error retrieving values");
+        assert_eq!(values, vec![Some(1), None, Some(2)]);
+    }
+
+    #[tokio::test]
+    pub async fn test_memory_only_when_redis_is_down() {
+        let pool = create_pool("redis://127.0.0.1:1")
+            .await
+            .expect("error creating pool");
+        let cache = Cache::new(pool, "melody", &LaunchMode::Testing);
+
+        cache
+            .set("answer", &42, Duration::from_secs(60))
+            .await
+            .expect("writes should not fail while redis is down");
+        assert_eq!(cache.get("answer").await, Ok(Some(42)));
+        assert_eq!(cache.get::<i32>("missing").await, Ok(None));
+        assert!(matches!(cache.conn().await, Err(CacheError::Connection(_))));
+
+        let computed = cache
+            .get_or_compute("computed", Duration::from_secs(60), || async {
+                Ok::<_, CacheError>(7)
+            })
+            .await;
+        assert_eq!(computed, Ok(7));
+
+        let health = cache.health().await;
+        assert_eq!(health.redis, "down");
+        assert!(health.is_degraded());
+        assert_eq!(health.memory_entries, 2);
+    }
+
+    #[tokio::test]
+    pub async fn test_get_or_compute() {
+        let cache = test_cache().await;
+        let computed = Arc::new(AtomicUsize::new(0));
+
+        let lookups = (0..8).map(|_| {
+            let (cache, computed) = (cache.clone(), computed.clone());
+            tokio::spawn(async move {
+                cache
+                    .get_or_compute("answer", Duration::from_secs(60), || async {
+                        computed.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        Ok::<_, CacheError>(42)
+                    })
+                    .await
+            })
+        });
+        for lookup in futures::future::join_all(lookups).await {
+            assert_eq!(lookup.unwrap(), Ok(42));
+        }
+        assert_eq!(computed.load(Ordering::SeqCst), 1);
     }
 }
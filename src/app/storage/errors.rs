@@ -8,10 +8,6 @@ pub enum DbError {
     PoolCreate(String),
     #[error("unable to create client. error: {0}")]
     CreateClient(String),
-    #[error("invalid value for cache (not a supported type). error: {0}")]
-    InvalidCacheValue(String),
-    #[error("unable to parse into value. error: {0}")]
-    Parse(String),
-    #[error("internal server error with database client. error: {0}")]
-    ServerError(String),
+    #[error("unable to execute query. error: {0}")]
+    Query(String),
 }
@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone)]
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "hash_algorithm")]
 #[sqlx(rename_all = "lowercase")]
 pub enum HashAlgorithm {
@@ -8,9 +8,22 @@ pub enum HashAlgorithm {
     Bcrypt,
 }
 
-#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[sqlx(type_name = "auth_provider")]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum AuthProvider {
+    Okta,
+    Auth0,
+    Keycloak,
+    /// Users registered with a password on this service
+    DevelopForGood,
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "asset_backend")]
 #[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum AssetBackend {
     Fs,
     Aws,
@@ -18,9 +31,10 @@ pub enum AssetBackend {
     Azure,
 }
 
-#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[sqlx(type_name = "asset_visibility")]
 #[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
 pub enum AssetVisibility {
     Public,
     Private,
@@ -1,18 +1,55 @@
-use axum::{http::StatusCode, Json, Router};
-use tower_http::{cors::CorsLayer, trace::TraceLayer};
+use axum::{middleware, Router};
+use tower_http::{
+    cors::CorsLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
 
 use crate::{
     app::{
-        api,
-        auth::{auth0::Auth0, authenticator::Authenticator, noop::NoOpAuth},
-        openai::OpenAIClient,
-        storage::{cache, sql},
-        types::AssetBackend,
+        agent,
+        api::{self, errors::ApiError},
+        assets::{
+            fs::FsStore,
+            s3::{S3Options, S3Store},
+            store::AssetStore,
+        },
+        auth::{
+            auth0::Auth0,
+            authenticator::Authenticator,
+            first_party::FirstPartyAuth,
+            noop::NoOpAuth,
+            oidc::{OidcAuthenticator, OidcProvider},
+        },
+        llm::{
+            anthropic::AnthropicClient,
+            local::{LocalClient, LocalOptions, LocalServer},
+            provider::ChatProvider,
+            retry::RetryPolicy,
+            semantic_cache::SemanticCacheConfig,
+        },
+        openai::{
+            azure::{self, AzureOptions},
+            OpenAIClient,
+        },
+        rag,
+        storage::{
+            cache::{self, Cache},
+            sql,
+        },
+        types::{AssetBackend, AssetVisibility},
     },
     launch::LaunchMode,
     state::{AppState, Config, ServiceLayer, StorageLayer},
 };
-use std::{env, sync::Arc};
+use jsonwebtoken::Algorithm;
+use mobc_redis::redis;
+use std::{env, sync::Arc, time::Duration};
+
+const DEFAULT_AUTH0_ALGORITHMS: &str = "RS256";
+const DEFAULT_OIDC_ALGORITHMS: &str = "RS256";
+const DEFAULT_SEMANTIC_CACHE_TTL_SECONDS: u64 = 24 * 60 * 60;
+const DEFAULT_SEMANTIC_CACHE_EMBEDDING_MODEL: &str = "text-embedding-3-small";
 
 /// Create app configuration
 fn build_config() -> Config {
@@ -36,19 +73,197 @@ fn build_config() -> Config {
         .as_str()
     {
         "aws" | "amazon" | "s3" => AssetBackend::Aws,
-        "gcp" | "google" | "gcs" => AssetBackend::Gcp,
-        "azure" => AssetBackend::Azure,
+        other @ ("gcp" | "google" | "gcs" | "azure") => {
+            panic!("unsupported ASSET_BACKEND {other}; expected fs or aws")
+        }
         _ => AssetBackend::Fs,
     };
-    Config::new(&name, app_secret, launch_mode, asset_backend)
+    let asset_visibility = match env::var("ASSET_VISIBILITY")
+        .unwrap_or_default()
+        .to_lowercase()
+        .as_str()
+    {
+        "public" => AssetVisibility::Public,
+        _ => AssetVisibility::Private,
+    };
+    let chat_cache_ttl = env::var("CHAT_CACHE_TTL_SECONDS")
+        .ok()
+        .map(|secs| secs.parse().expect("invalid CHAT_CACHE_TTL_SECONDS value"))
+        .filter(|secs| *secs > 0)
+        .map(Duration::from_secs);
+    let semantic_cache =
+        env::var("SEMANTIC_CACHE_THRESHOLD")
+            .ok()
+            .map(|threshold| SemanticCacheConfig {
+                threshold: threshold
+                    .parse()
+                    .expect("invalid SEMANTIC_CACHE_THRESHOLD value"),
+                ttl: Duration::from_secs(
+                    env::var("SEMANTIC_CACHE_TTL_SECONDS")
+                        .map(|secs| {
+                            secs.parse()
+                                .expect("invalid SEMANTIC_CACHE_TTL_SECONDS value")
+                        })
+                        .unwrap_or(DEFAULT_SEMANTIC_CACHE_TTL_SECONDS),
+                ),
+                embedding_model: env::var("SEMANTIC_CACHE_EMBEDDING_MODEL")
+                    .unwrap_or(DEFAULT_SEMANTIC_CACHE_EMBEDDING_MODEL.into()),
+            });
+    let admin_subjects = env::var("ADMIN_SUBJECTS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|subject| !subject.is_empty())
+        .map(str::to_owned)
+        .collect();
+    Config::new(
+        &name,
+        app_secret,
+        launch_mode,
+        asset_backend,
+        asset_visibility,
+        chat_cache_ttl,
+    )
+    .with_semantic_cache(semantic_cache)
+    .with_admin_subjects(admin_subjects)
 }
 
-async fn build_services() -> ServiceLayer {
-    let openai_api_key = env::var("OPENAI_API_KEY").expect("invalid or missing openai api key");
-    let openai_base_uri = env::var("OPENAI_BASE_URI").expect("invalid openai base uri");
+/// Create the retry policy for upstream ai calls
+fn build_retry_policy() -> RetryPolicy {
+    let defaults = RetryPolicy::default();
+    let max_retries = env::var("AI_MAX_RETRIES")
+        .map(|n| n.parse().expect("invalid AI_MAX_RETRIES value"))
+        .unwrap_or(defaults.max_retries);
+    let timeout = match env::var("AI_REQUEST_TIMEOUT_SECONDS") {
+        Ok(secs) => match secs
+            .parse()
+            .expect("invalid AI_REQUEST_TIMEOUT_SECONDS value")
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        },
+        Err(_) => defaults.timeout,
+    };
+    RetryPolicy::new(max_retries, timeout)
+}
+
+fn build_azure_client() -> OpenAIClient {
+    let api_key =
+        env::var("AZURE_OPENAI_API_KEY").expect("invalid or missing azure openai api key");
+    let endpoint =
+        env::var("AZURE_OPENAI_ENDPOINT").expect("invalid or missing azure openai endpoint");
+    let api_version =
+        env::var("AZURE_OPENAI_API_VERSION").expect("invalid or missing azure openai api version");
+    let deployments = env::var("AZURE_OPENAI_DEPLOYMENTS")
+        .map(|raw| azure::parse_deployments(&raw))
+        .unwrap_or_default();
 
-    let openai_client = OpenAIClient::new(&openai_api_key, &openai_base_uri);
+    OpenAIClient::azure(
+        &api_key,
+        &endpoint,
+        AzureOptions::new(&api_version, deployments),
+    )
+}
 
+/// Create the client used for embeddings. Azure and local servers embed with the same resource
+/// that serves chat; otherwise the openai api is used when a key is configured.
+fn build_embeddings_client(retry: RetryPolicy) -> Option<OpenAIClient> {
+    let client = match env::var("AI_PROVIDER")
+        .unwrap_or("openai".into())
+        .to_lowercase()
+        .as_str()
+    {
+        "azure" => build_azure_client(),
+        "ollama" | "llamacpp" | "llama.cpp" => {
+            let base_uri = env::var("LOCAL_MODEL_URI").expect("missing local model server uri");
+            OpenAIClient::new("", &(base_uri.trim_end_matches('/').to_owned() + "/v1"))
+        }
+        _ => {
+            let api_key = env::var("OPENAI_API_KEY").ok()?;
+            let base_uri = env::var("OPENAI_BASE_URI").expect("invalid openai base uri");
+            OpenAIClient::new(&api_key, &base_uri)
+        }
+    };
+    Some(client.with_retry_policy(retry))
+}
+
+/// Create the store for asset contents
+fn build_asset_store(backend: AssetBackend) -> Box<dyn AssetStore> {
+    match backend {
+        AssetBackend::Fs => {
+            let root = env::var("ASSET_FS_ROOT").unwrap_or("assets".into());
+            Box::new(FsStore::new(root))
+        }
+        AssetBackend::Aws => {
+            let region = env::var("S3_REGION").unwrap_or("us-east-1".into());
+            let options = S3Options {
+                endpoint: env::var("S3_ENDPOINT")
+                    .unwrap_or(format!("https://s3.{region}.amazonaws.com")),
+                bucket: env::var("S3_BUCKET").expect("missing s3 bucket"),
+                region,
+                access_key_id: env::var("S3_ACCESS_KEY_ID").expect("missing s3 access key id"),
+                secret_access_key: env::var("S3_SECRET_ACCESS_KEY")
+                    .expect("missing s3 secret access key"),
+                path_style: env::var("S3_PATH_STYLE").is_ok_and(|path_style| path_style == "true"),
+            };
+            Box::new(S3Store::new(options).expect("invalid s3 configuration"))
+        }
+        // rejected by build_config
+        AssetBackend::Gcp | AssetBackend::Azure => {
+            unreachable!("unsupported asset backend {backend:?}")
+        }
+    }
+}
+
+async fn build_services(config: &Config, storage_layer: &StorageLayer) -> ServiceLayer {
+    let retry = build_retry_policy();
+    let embeddings = build_embeddings_client(retry.clone());
+    let ai: Box<dyn ChatProvider> = match env::var("AI_PROVIDER")
+        .unwrap_or("openai".into())
+        .to_lowercase()
+        .as_str()
+    {
+        "openai" => {
+            let api_key = env::var("OPENAI_API_KEY").expect("invalid or missing openai api key");
+            let base_uri = env::var("OPENAI_BASE_URI").expect("invalid openai base uri");
+
+            Box::new(OpenAIClient::new(&api_key, &base_uri).with_retry_policy(retry))
+        }
+        "azure" => Box::new(build_azure_client().with_retry_policy(retry)),
+        "anthropic" => {
+            let api_key =
+                env::var("ANTHROPIC_API_KEY").expect("invalid or missing anthropic api key");
+            let base_uri = env::var("ANTHROPIC_BASE_URI").expect("invalid anthropic base uri");
+
+            Box::new(AnthropicClient::new(&api_key, &base_uri).with_retry_policy(retry))
+        }
+        server @ ("ollama" | "llamacpp" | "llama.cpp") => {
+            let server = match server {
+                "ollama" => LocalServer::Ollama,
+                _ => LocalServer::LlamaCpp,
+            };
+            let base_uri = env::var("LOCAL_MODEL_URI").expect("missing local model server uri");
+            let default_model = env::var("LOCAL_MODEL").ok();
+            let options = LocalOptions {
+                num_ctx: env::var("OLLAMA_NUM_CTX")
+                    .ok()
+                    .map(|n| n.parse().expect("invalid OLLAMA_NUM_CTX value")),
+                keep_alive: env::var("OLLAMA_KEEP_ALIVE").ok(),
+            };
+
+            Box::new(
+                LocalClient::new(server, &base_uri, options, default_model)
+                    .await
+                    .expect("error initializing local model client")
+                    .with_retry_policy(retry),
+            )
+        }
+        other => panic!(
+            "unsupported AI_PROVIDER {other}; expected openai, azure, anthropic, ollama or llamacpp"
+        ),
+    };
+
+    let mut first_party = None;
     let auth: Box<dyn Authenticator> = match env::var("AUTH_PROVIDER")
         .expect("missing auth provider")
         .as_str()
@@ -62,30 +277,108 @@ async fn build_services() -> ServiceLayer {
                 .map(|aud| aud.into())
                 .collect();
 
-            Box::new(
-                Auth0::new(&tenant_uri, audiences)
-                    .await
-                    .expect("error initializing auth0 provider"),
-            )
+            let algorithms: Vec<Algorithm> = env::var("AUTH0_ALGORITHMS")
+                .unwrap_or(DEFAULT_AUTH0_ALGORITHMS.into())
+                .split_ascii_whitespace()
+                .map(|alg| alg.parse().expect("invalid AUTH0_ALGORITHMS value"))
+                .collect();
+
+            let mut auth0 = Auth0::new(&tenant_uri, audiences)
+                .await
+                .expect("error initializing auth0 provider")
+                .with_algorithms(algorithms);
+            if let Ok(secret) = env::var("AUTH0_CLIENT_SECRET") {
+                auth0 = auth0.with_client_secret(&secret);
+            }
+            Box::new(auth0)
+        }
+        provider @ ("keycloak" | "okta" | "oidc") => {
+            let provider = match provider {
+                "keycloak" => OidcProvider::Keycloak,
+                "okta" => OidcProvider::Okta,
+                _ => OidcProvider::Generic,
+            };
+            let issuer = env::var("OIDC_ISSUER").expect("missing oidc issuer");
+            let audiences: Vec<String> = env::var("OIDC_AUDIENCES")
+                .expect("missing oidc audience(s) value")
+                .split_ascii_whitespace()
+                .map(|aud| aud.into())
+                .collect();
+            let algorithms: Vec<Algorithm> = env::var("OIDC_ALGORITHMS")
+                .unwrap_or(DEFAULT_OIDC_ALGORITHMS.into())
+                .split_ascii_whitespace()
+                .map(|alg| alg.parse().expect("invalid OIDC_ALGORITHMS value"))
+                .collect();
+
+            let mut oidc = OidcAuthenticator::new(provider, &issuer, audiences)
+                .await
+                .expect("error initializing oidc provider")
+                .with_algorithms(algorithms);
+            if let Ok(secs) = env::var("OIDC_LEEWAY_SECONDS") {
+                let secs = secs.parse().expect("invalid OIDC_LEEWAY_SECONDS value");
+                oidc = oidc.with_leeway(Duration::from_secs(secs));
+            }
+            if let Ok(secret) = env::var("OIDC_CLIENT_SECRET") {
+                oidc = oidc.with_client_secret(&secret);
+            }
+            Box::new(oidc)
+        }
+        "developforgood" => {
+            let mut auth =
+                FirstPartyAuth::new(storage_layer.sql.clone(), &config.name, &config.secret);
+            if let Ok(secs) = env::var("AUTH_TOKEN_TTL_SECONDS") {
+                let secs = secs.parse().expect("invalid AUTH_TOKEN_TTL_SECONDS value");
+                auth = auth.with_ttl(Duration::from_secs(secs));
+            }
+            first_party = Some(auth.clone());
+            Box::new(auth)
         }
         "noop" => Box::new(NoOpAuth::new()),
-        _ => unimplemented!(),
+        other => panic!(
+            "unsupported AUTH_PROVIDER {other}; expected auth0, keycloak, okta, oidc, \
+             developforgood or noop"
+        ),
     };
 
-    ServiceLayer::new(openai_client, auth)
+    let tools = agent::tools::registry(storage_layer.sql.clone(), embeddings.clone());
+
+    let assets = build_asset_store(config.asset_backend);
+
+    if config.semantic_cache.is_some() && embeddings.is_none() {
+        log::warn!("the semantic cache is disabled because no embeddings provider is configured");
+    }
+
+    let mut services =
+        ServiceLayer::new(ai, embeddings, auth, tools, assets).with_first_party(first_party);
+    if let Ok(max_jobs) = env::var("INGESTION_MAX_JOBS") {
+        let max_jobs = max_jobs.parse().expect("invalid INGESTION_MAX_JOBS value");
+        services = services.with_max_ingestion_jobs(max_jobs);
+    }
+    services
 }
 
-async fn build_storage_layer() -> StorageLayer {
+async fn build_storage_layer(config: &Config) -> StorageLayer {
     let db_url = env::var("DATABASE_URL").expect("missing database url");
     let cache_url = env::var("CACHE_URL").expect("missing cache url");
 
     let sql = sql::create_pool(&db_url)
         .await
         .expect("error initializing database connection pool");
-    let cache = cache::create_pool(&cache_url)
+    let pool = cache::create_pool(&cache_url)
         .await
         .expect("error initializing cache connection pool");
 
+    let mut cache = Cache::new(pool, &config.name, &config.launch_mode);
+    if let Ok(capacity) = env::var("CACHE_MEMORY_CAPACITY") {
+        cache = cache.with_memory_capacity(
+            capacity
+                .parse()
+                .expect("invalid CACHE_MEMORY_CAPACITY value"),
+        );
+    }
+    let client = redis::Client::open(cache_url).expect("invalid cache url");
+    cache.spawn_invalidation_listener(client);
+
     StorageLayer::new(sql, cache)
 }
 
@@ -93,8 +386,8 @@ async fn build_app_state() -> AppState {
     dotenvy::dotenv().expect("error loading environment variables");
 
     let config = build_config();
-    let storage_layer = build_storage_layer().await;
-    let services = build_services().await;
+    let storage_layer = build_storage_layer(&config).await;
+    let services = build_services(&config, &storage_layer).await;
 
     AppState::new(config, storage_layer, services)
 }
@@ -107,6 +400,11 @@ pub async fn build_app() -> Router {
         .run(&shared_state.storage_layer.sql)
         .await
         .expect("failed to run database migrations");
+    match rag::jobs::fail_interrupted_jobs(&shared_state.storage_layer.sql).await {
+        Ok(0) => {}
+        Ok(failed) => log::warn!("marked {failed} interrupted ingestion jobs as failed"),
+        Err(e) => log::error!("unable to clean up interrupted ingestion jobs: {e}"),
+    }
 
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
@@ -115,12 +413,10 @@ pub async fn build_app() -> Router {
     Router::new()
         .with_state(shared_state)
         .nest("/api", api_routes)
-        .fallback(|| async move {
-            (
-                StatusCode::NOT_FOUND,
-                Json(serde_json::json!({"status": "Not Found"})),
-            )
-        })
+        .fallback(|| async move { ApiError::NotFound("no route matches the request".into()) })
+        .layer(middleware::from_fn(api::errors::attach_request_id))
         .layer(TraceLayer::new_for_http())
         .layer(CorsLayer::permissive())
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
 }
@@ -1,10 +1,19 @@
+use std::{sync::Arc, time::Duration};
+
 use reqwest::Client;
 use sqlx::PgPool;
+use tokio::sync::Semaphore;
 
 use crate::{
     app::{
-        auth::authenticator::Authenticator, openai::OpenAIClient, storage::cache::RedisPool,
-        types::AssetBackend,
+        agent::registry::ToolRegistry,
+        assets::store::AssetStore,
+        auth::{authenticator::Authenticator, first_party::FirstPartyAuth},
+        llm::{provider::ChatProvider, semantic_cache::SemanticCacheConfig},
+        openai::OpenAIClient,
+        rag::jobs::DEFAULT_MAX_INGESTION_JOBS,
+        storage::cache::Cache,
+        types::{AssetBackend, AssetVisibility},
     },
     launch::LaunchMode,
 };
@@ -15,6 +24,15 @@ pub struct Config {
     pub secret: Vec<u8>,
     pub launch_mode: LaunchMode,
     pub asset_backend: AssetBackend,
+    /// Visibility of uploaded assets that don't ask for one
+    pub asset_visibility: AssetVisibility,
+    /// How long deterministic chat completions are cached. Without it only requests that set
+    /// their own ttl are cached.
+    pub chat_cache_ttl: Option<Duration>,
+    /// Reuse completions for similar prompts. Needs an embeddings provider.
+    pub semantic_cache: Option<SemanticCacheConfig>,
+    /// Subjects allowed to use admin routes
+    pub admin_subjects: Vec<String>,
 }
 
 impl Config {
@@ -23,38 +41,93 @@ impl Config {
         secret: Vec<u8>,
         launch_mode: LaunchMode,
         asset_backend: AssetBackend,
+        asset_visibility: AssetVisibility,
+        chat_cache_ttl: Option<Duration>,
     ) -> Self {
         Self {
             name: name.to_owned(),
             secret,
             launch_mode,
             asset_backend,
+            asset_visibility,
+            chat_cache_ttl,
+            semantic_cache: None,
+            admin_subjects: vec![],
         }
     }
+
+    pub fn with_semantic_cache(mut self, semantic_cache: Option<SemanticCacheConfig>) -> Self {
+        self.semantic_cache = semantic_cache;
+        self
+    }
+
+    pub fn with_admin_subjects(mut self, admin_subjects: Vec<String>) -> Self {
+        self.admin_subjects = admin_subjects;
+        self
+    }
+
+    pub fn is_admin(&self, subject: &str) -> bool {
+        self.admin_subjects.iter().any(|admin| admin == subject)
+    }
 }
 
 #[derive(Clone)]
 pub struct StorageLayer {
     pub sql: PgPool,
-    pub cache: RedisPool,
+    pub cache: Cache,
 }
 
 // #[derive(Clone)]
 pub struct ServiceLayer {
-    pub ai: OpenAIClient,
+    pub ai: Box<dyn ChatProvider>,
+    /// Embeddings are only served by openai compatible apis, so they may be unavailable when
+    /// chat is served by another provider
+    pub embeddings: Option<OpenAIClient>,
     pub http: Client,
     pub auth: Box<dyn Authenticator>,
+    pub tools: ToolRegistry,
+    pub assets: Box<dyn AssetStore>,
+    /// Registration and login with a password, when the first party provider is configured
+    pub first_party: Option<FirstPartyAuth>,
+    /// A permit for each upload that may be ingested at once. Uploads are held in memory until
+    /// they're ingested, so none are accepted while every permit is taken.
+    pub ingestion: Arc<Semaphore>,
 }
 
 impl ServiceLayer {
-    pub fn new(ai: OpenAIClient, auth: Box<dyn Authenticator>) -> Self {
+    pub fn new(
+        ai: Box<dyn ChatProvider>,
+        embeddings: Option<OpenAIClient>,
+        auth: Box<dyn Authenticator>,
+        tools: ToolRegistry,
+        assets: Box<dyn AssetStore>,
+    ) -> Self {
         let http = Client::new();
-        Self { ai, http, auth }
+        Self {
+            ai,
+            embeddings,
+            http,
+            auth,
+            tools,
+            assets,
+            first_party: None,
+            ingestion: Arc::new(Semaphore::new(DEFAULT_MAX_INGESTION_JOBS)),
+        }
+    }
+
+    pub fn with_first_party(mut self, first_party: Option<FirstPartyAuth>) -> Self {
+        self.first_party = first_party;
+        self
+    }
+
+    pub fn with_max_ingestion_jobs(mut self, max_jobs: usize) -> Self {
+        self.ingestion = Arc::new(Semaphore::new(max_jobs));
+        self
     }
 }
 
 impl StorageLayer {
-    pub fn new(sql: PgPool, cache: RedisPool) -> Self {
+    pub fn new(sql: PgPool, cache: Cache) -> Self {
         Self { sql, cache }
     }
 }